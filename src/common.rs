@@ -15,7 +15,7 @@ mod shared {
     /// How much information should be omitted when sending `UserRecord` to the client.
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub enum UserMaskLevel {
-        /// Allow everything through, **including the hashed password!**
+        /// Allow everything through except the hashed password, which never leaves the server.
         SelfUse,
         /// Allow everything except the hashed password.
         HidePass,
@@ -31,12 +31,17 @@ mod shared {
         pub uid: UserId,
         pub email: Option<String>,
         pub pubkey: Pubkey,
+        /// Always `None`, and then left out. Kept so older clients still parse the record.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         pub hashed_pass: Option<HashedPassword>,
         pub alias: Option<String>,
         pub friends: Option<Vec<UserId>>,
         pub groups: Option<Vec<GroupId>>,
         pub motd: Option<String>,
         pub online: bool,
+        pub status_text: Option<String>,
+        /// Only shown to the user themselves.
+        pub visibility: Option<UserVisibility>,
     }
 
     impl FromSqlTup<SqlUserMessage> for PublicUserMessage {
@@ -47,6 +52,8 @@ mod shared {
                 to: UserId::from(tup.2),
                 content: ClientMessage::from(tup.3),
                 time_posted: DateTime::from_utc(tup.4, Utc),
+                delivered: tup.6.map(|t| DateTime::from_utc(t, Utc)),
+                read: tup.7.map(|t| DateTime::from_utc(t, Utc)),
             })
         }
     }
@@ -58,15 +65,188 @@ mod shared {
         to: UserId,
         time_posted: DateTime<Utc>,
         content: ClientMessage,
+        /// When it first reached one of the receiver's devices.
+        delivered: Option<DateTime<Utc>>,
+        read: Option<DateTime<Utc>>,
+    }
+
+    impl PublicUserMessage {
+        pub fn umid(&self) -> UserMessageId {
+            self.umid.clone()
+        }
+    }
+
+    impl FromSqlTup<SqlGroupMessage> for PublicGroupMessage {
+        fn from_sql_tup(tup: SqlGroupMessage) -> Option<Self> {
+            Some(Self {
+                gmid: GroupMessageId::from(tup.0),
+                from: UserId::from(tup.1),
+                group: GroupId::from(tup.2),
+                content: ClientMessage::from(tup.3),
+                time_posted: DateTime::from_utc(tup.4, Utc),
+            })
+        }
+    }
+
+    /// Message posted to a group. Sent as-is to every member.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct PublicGroupMessage {
+        gmid: GroupMessageId,
+        from: UserId,
+        group: GroupId,
+        time_posted: DateTime<Utc>,
+        content: ClientMessage,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct GroupRecord {
         pub gid: GroupId,
+        pub name: Option<String>,
         pub motd: Option<String>,
+        pub members: Vec<GroupMember>,
+    }
+
+    impl GroupRecord {
+        pub fn member_ids(&self) -> Vec<UserId> {
+            self.members.iter().map(|m| m.uid).collect()
+        }
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct GroupMember {
+        pub uid: UserId,
+        pub role: GroupRole,
+    }
+
+    /// Role of a group member. Declared from most to least privileged, so `<` means "outranks".
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+    pub enum GroupRole {
+        /// Creator of the group, until handed on with `TransferOwnership`. There is exactly one.
+        Owner,
+        Admin,
+        #[default]
+        Member,
+        /// Member that can read but not post or invite.
+        Muted,
+    }
+
+    /// Things a member may be allowed to do in a group.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum GroupPermission {
+        Post,
+        Invite,
+        /// Change the name or motd.
+        EditInfo,
+        Kick,
+        Ban,
+        SetRole,
+    }
+
+    impl GroupRole {
+        /// Check if this role grants a permission. Acting on another member also needs `outranks`.
+        pub fn allows(self, p: GroupPermission) -> bool {
+            match p {
+                GroupPermission::Post | GroupPermission::Invite => self <= GroupRole::Member,
+                GroupPermission::EditInfo
+                | GroupPermission::Kick
+                | GroupPermission::Ban
+                | GroupPermission::SetRole => self <= GroupRole::Admin,
+            }
+        }
+        pub fn outranks(self, other: GroupRole) -> bool {
+            self < other
+        }
+    }
+
+    impl From<GroupRole> for u8 {
+        fn from(r: GroupRole) -> Self {
+            match r {
+                GroupRole::Owner => 0,
+                GroupRole::Admin => 1,
+                GroupRole::Member => 2,
+                GroupRole::Muted => 3,
+            }
+        }
+    }
+
+    impl TryFrom<u8> for GroupRole {
+        type Error = u8;
+
+        fn try_from(i: u8) -> Result<Self, Self::Error> {
+            match i {
+                0 => Ok(GroupRole::Owner),
+                1 => Ok(GroupRole::Admin),
+                2 => Ok(GroupRole::Member),
+                3 => Ok(GroupRole::Muted),
+                _ => Err(i),
+            }
+        }
+    }
+
+    impl From<GroupRole> for mysql::Value {
+        fn from(r: GroupRole) -> Self {
+            u8::from(r).into()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FriendRequestState {
+        Pending,
+        Accepted,
+        Declined,
+    }
+
+    impl From<FriendRequestState> for u8 {
+        fn from(s: FriendRequestState) -> Self {
+            match s {
+                FriendRequestState::Pending => 0,
+                FriendRequestState::Accepted => 1,
+                FriendRequestState::Declined => 2,
+            }
+        }
+    }
+
+    impl TryFrom<u8> for FriendRequestState {
+        type Error = u8;
+
+        fn try_from(i: u8) -> Result<Self, Self::Error> {
+            match i {
+                0 => Ok(FriendRequestState::Pending),
+                1 => Ok(FriendRequestState::Accepted),
+                2 => Ok(FriendRequestState::Declined),
+                _ => Err(i),
+            }
+        }
+    }
+
+    impl From<FriendRequestState> for mysql::Value {
+        fn from(st: FriendRequestState) -> Self {
+            u8::from(st).into()
+        }
+    }
+
+    /// Friend request from `from` to `to`. There is at most one per direction; sending again replaces it.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct FriendRequest {
+        pub from: UserId,
+        pub to: UserId,
+        pub state: FriendRequestState,
+        /// When it was sent or last answered.
+        pub at: DateTime<Utc>,
+    }
+
+    /// A user's friends, and the pending requests they sent or received.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct FriendList {
+        pub friends: Vec<UserId>,
+        pub requests: Vec<FriendRequest>,
+    }
+
+    /**
+    Status a user picked. Whether they are actually shown as online also depends on having a device connected.
+    Picking `Offline` is the same as `Invisible`.
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
     pub enum UserStatus {
         Online,
         Offline,
@@ -75,7 +255,17 @@ mod shared {
 
     impl Default for UserStatus {
         fn default() -> Self {
-            Self::Offline
+            Self::Online
+        }
+    }
+
+    impl UserStatus {
+        /// What others get to see, given whether the user has a device connected.
+        pub fn shown(self, connected: bool) -> UserStatus {
+            match self {
+                UserStatus::Online if connected => UserStatus::Online,
+                _ => UserStatus::Offline,
+            }
         }
     }
 
@@ -116,15 +306,24 @@ mod shared {
         }
     }
 
-    impl Into<u32> for UserId {
-        fn into(self) -> u32 {
-            self.0
+    impl From<UserId> for u32 {
+        fn from(i: UserId) -> Self {
+            i.0
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Copy)]
     pub struct GroupId(u32);
 
+    impl FromStr for GroupId {
+        type Err = ParseIntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let tmp = u32::from_str(s)?;
+            Ok(Self(tmp))
+        }
+    }
+
     impl Display for GroupId {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "{}", self.0)
@@ -137,6 +336,12 @@ mod shared {
         }
     }
 
+    impl From<GroupId> for u32 {
+        fn from(i: GroupId) -> Self {
+            i.0
+        }
+    }
+
     impl Into<mysql::Value> for GroupId {
         fn into(self) -> mysql::Value {
             self.0.into()
@@ -158,15 +363,30 @@ mod shared {
         }
     }
 
+    impl From<UserMessageId> for u64 {
+        fn from(i: UserMessageId) -> Self {
+            i.0
+        }
+    }
+
     impl Into<mysql::Value> for UserMessageId {
         fn into(self) -> mysql::Value {
             self.0.into()
         }
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Copy)]
     pub struct GroupMessageId(u64);
 
+    impl FromStr for GroupMessageId {
+        type Err = ParseIntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let tmp = u64::from_str(s)?;
+            Ok(Self(tmp))
+        }
+    }
+
     impl Display for GroupMessageId {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "{}", self.0)
@@ -179,12 +399,61 @@ mod shared {
         }
     }
 
+    impl From<GroupMessageId> for u64 {
+        fn from(i: GroupMessageId) -> Self {
+            i.0
+        }
+    }
+
     impl Into<mysql::Value> for GroupMessageId {
         fn into(self) -> mysql::Value {
             self.0.into()
         }
     }
 
+    /// Random, so ids don't give away how many sessions there are. Fits in a JSON number.
+    #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Copy)]
+    pub struct SessionId(u64);
+
+    impl SessionId {
+        pub fn random() -> SessionId {
+            SessionId(rand::thread_rng().gen_range(1..1 << 53))
+        }
+    }
+
+    impl FromStr for SessionId {
+        type Err = ParseIntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let tmp = u64::from_str(s)?;
+            Ok(Self(tmp))
+        }
+    }
+
+    impl Display for SessionId {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl From<u64> for SessionId {
+        fn from(i: u64) -> Self {
+            SessionId(i)
+        }
+    }
+
+    impl From<SessionId> for u64 {
+        fn from(i: SessionId) -> Self {
+            i.0
+        }
+    }
+
+    impl From<SessionId> for mysql::Value {
+        fn from(sid: SessionId) -> Self {
+            sid.0.into()
+        }
+    }
+
     /// Public key for message signing. Server performs no validation!
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Pubkey(String);
@@ -201,8 +470,11 @@ mod shared {
         }
     }
 
-    /// Hashed password. Server performs no validation!
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    /**
+    Stored password: an Argon2 PHC string with its own salt and parameters.
+    Rows from before the server hashed anything hold the credential exactly as the client sent it.
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub struct HashedPassword(String);
 
     impl From<String> for HashedPassword {
@@ -211,6 +483,12 @@ mod shared {
         }
     }
 
+    impl HashedPassword {
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
+
     pub const LT_LEN: usize = 40;
 
     pub fn alphanumeric_len(value: &str, len: usize) -> bool {
@@ -249,15 +527,63 @@ mod shared {
         }
     }
 
+    /// Trades for a new login token once the old one has expired. Only good for one use.
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
+    pub struct RefreshToken {
+        pub refresh_tk: String,
+    }
+
+    impl RefreshToken {
+        pub fn random() -> RefreshToken {
+            RefreshToken {
+                refresh_tk: LoginToken::new().tk,
+            }
+        }
+    }
+
+    /// A logged in device.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Session {
+        pub sid: SessionId,
+        pub uid: UserId,
+        pub created: DateTime<Utc>,
+        pub last_seen: DateTime<Utc>,
+        /// The login token stops working after this, unless used before then.
+        pub expires: DateTime<Utc>,
+        /// The refresh token stops working after this.
+        pub refresh_expires: DateTime<Utc>,
+        /// Whatever the client called itself when logging in.
+        pub device: Option<String>,
+        pub ip: Option<String>,
+    }
+
+    /// Reply to logging in or refreshing. `tk` goes in the `Authorization` header.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SessionTokens {
+        #[serde(flatten)]
+        pub lt: LoginToken,
+        #[serde(flatten)]
+        pub refresh: RefreshToken,
+        pub sid: SessionId,
+        pub expires: DateTime<Utc>,
+        pub refresh_expires: DateTime<Utc>,
+    }
+
     #[derive(Serialize, Deserialize, Debug)]
     pub enum MessageActor {
         Dm(UserId),
         Group(GroupId),
     }
 
-    #[derive(Serialize, Deserialize)]
+    /// Which messages of a conversation to look through for history.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
     pub enum HistoryQuery {
+        /// Everything.
+        #[default]
+        All,
+        /// Messages the asker hasn't read yet. Messages the asker sent are never unseen.
         Unseen,
+        /// Posted at or after `from` and before `to`.
         Interval {
             from: DateTime<Utc>,
             to: DateTime<Utc>,
@@ -265,6 +591,87 @@ mod shared {
         Since(DateTime<Utc>),
     }
 
+    impl HistoryQuery {
+        /// Split into `(unseen only, from, to)` for backends to filter on.
+        pub fn bounds(&self) -> (bool, Option<NaiveDateTime>, Option<NaiveDateTime>) {
+            match self {
+                HistoryQuery::All => (false, None, None),
+                HistoryQuery::Unseen => (true, None, None),
+                HistoryQuery::Interval { from, to } => {
+                    (false, Some(from.naive_utc()), Some(to.naive_utc()))
+                }
+                HistoryQuery::Since(from) => (false, Some(from.naive_utc()), None),
+            }
+        }
+    }
+
+    /// Most messages returned in one page of history.
+    pub const HISTORY_PAGE_MAX: u16 = 100;
+
+    /**
+    Query string of the REST history endpoints.
+    `unseen`, `from` and `to` pick the `HistoryQuery`; `before` is the id of the oldest message already seen.
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct HistoryParams {
+        #[serde(default)]
+        pub unseen: bool,
+        pub from: Option<DateTime<Utc>>,
+        pub to: Option<DateTime<Utc>>,
+        pub before: Option<u64>,
+        pub amt: Option<u16>,
+    }
+
+    impl HistoryParams {
+        pub fn query(&self) -> HistoryQuery {
+            match (self.unseen, self.from, self.to) {
+                (true, _, _) => HistoryQuery::Unseen,
+                (false, Some(from), Some(to)) => HistoryQuery::Interval { from, to },
+                (false, Some(from), None) => HistoryQuery::Since(from),
+                // no lower bound is the same as everything before `to`
+                (false, None, Some(to)) => HistoryQuery::Interval {
+                    from: DateTime::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
+                    to,
+                },
+                (false, None, None) => HistoryQuery::All,
+            }
+        }
+        pub fn amt(&self) -> u16 {
+            self.amt.unwrap_or(HISTORY_PAGE_MAX)
+        }
+    }
+
+    /// Most users returned in one page of search results.
+    pub const SEARCH_PAGE_MAX: u16 = 50;
+
+    /**
+    Query string of `GET /users/search`. At least one of `alias`, a prefix, or `email`, exact, is needed.
+    Results are ordered by uid; `after` is the uid of the last one already seen.
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct UserSearchParams {
+        pub alias: Option<String>,
+        pub email: Option<String>,
+        pub after: Option<u32>,
+        pub amt: Option<u16>,
+    }
+
+    impl UserSearchParams {
+        /// Drop empty terms. `None` if nothing is left to search by.
+        pub fn terms(&self) -> Option<(Option<String>, Option<String>)> {
+            let alias = self.alias.clone().filter(|a| !a.is_empty());
+            let email = self.email.clone().filter(|e| !e.is_empty());
+            if alias.is_none() && email.is_none() {
+                None
+            } else {
+                Some((alias, email))
+            }
+        }
+        pub fn amt(&self) -> u16 {
+            self.amt.unwrap_or(SEARCH_PAGE_MAX)
+        }
+    }
+
     impl<T> From<T> for WsClientboundPayload
     where
         T: ClientboundPayload,
@@ -281,6 +688,46 @@ mod shared {
         NewMessage(PublicUserMessage),
         NewMessages(Vec<PublicUserMessage>),
         MessageSent(UserMessageId),
+        NewGroupMessage(PublicGroupMessage),
+        NewGroupMessages(Vec<PublicGroupMessage>),
+        /// Group was created, joined, left, or asked for.
+        GroupUpdated(GroupRecord),
+        GroupInvite { group: GroupId, from: UserId },
+        Typing { from: UserId },
+        /// Sent when `from` stops, sends nothing for a while, or disconnects without stopping.
+        StoppedTyping { from: UserId },
+        /// A friend's presence changed, or your own from another device.
+        PresenceChanged {
+            uid: UserId,
+            status: UserStatus,
+            text: Option<String>,
+        },
+        /// A message you sent reached one more of the receiver's devices. `devices` counts those so far.
+        Delivered {
+            umid: UserMessageId,
+            at: DateTime<Utc>,
+            devices: u32,
+        },
+        /// A message you sent was read by the receiver.
+        Read { umid: UserMessageId, at: DateTime<Utc> },
+        /// You were kicked or banned from a group.
+        RemovedFromGroup { group: GroupId, by: UserId, banned: bool },
+        /// A friend request to or from you was sent or answered.
+        FriendRequest(FriendRequest),
+        /// You or `friend` ended the friendship.
+        FriendRemoved { friend: UserId },
+        Friends(FriendList),
+        /// Users you have blocked.
+        Blocked(Vec<UserId>),
+        /// A friend changed their profile, or you did from another device.
+        ProfileChanged {
+            uid: UserId,
+            alias: Option<String>,
+            motd: Option<String>,
+            pubkey: Pubkey,
+        },
+        /// Something you sent was refused, and why. Not sent for every refusal.
+        Error(ApiError),
     }
     #[derive(Serialize, Deserialize, Debug)]
     pub struct RegisterRequest {
@@ -293,6 +740,103 @@ mod shared {
     pub struct LoginRequest {
         pub email: String,
         pub password_hash: String,
+        /// Label for the new session, e.g. "phone".
+        #[serde(default)]
+        pub device: Option<String>,
+    }
+
+    /// Longest alias, in characters. Matches `Q_CREATE_TABLE_USERS`.
+    pub const ALIAS_MAX: usize = 40;
+    /// Longest user motd, in characters. Matches `Q_CREATE_TABLE_USERS`.
+    pub const MOTD_MAX: usize = 500;
+    /// Longest pubkey, in characters. Matches `Q_CREATE_TABLE_USERS`.
+    pub const PUBKEY_MAX: usize = 512;
+    /// Longest status text, in characters. Matches `Q_ALTER_USERS_STATUS_TEXT`.
+    pub const STATUS_TEXT_MAX: usize = 100;
+
+    /// Check `v` is at most `max` characters long.
+    fn fits(field: &'static str, v: Option<&String>, max: usize) -> Result<(), ProfileError> {
+        match v {
+            Some(v) if v.chars().count() > max => Err(ProfileError::TooLong { field, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a status text fits its column.
+    pub fn check_status_text(text: Option<&String>) -> Result<(), ProfileError> {
+        fits("status text", text, STATUS_TEXT_MAX)
+    }
+
+    /// Tell a field set to `null` from a missing one: `Some(None)` clears, `None` keeps.
+    fn nullable<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Option::<T>::deserialize(d).map(Some)
+    }
+
+    /// Changes to a user's own profile. Missing fields are left as they are.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct ProfileUpdate {
+        /// `null` removes the alias.
+        #[serde(default, deserialize_with = "nullable")]
+        pub alias: Option<Option<String>>,
+        /// `null` removes the motd.
+        #[serde(default, deserialize_with = "nullable")]
+        pub motd: Option<Option<String>>,
+        #[serde(default)]
+        pub visibility: Option<UserVisibility>,
+        #[serde(default)]
+        pub pubkey: Option<String>,
+    }
+
+    impl ProfileUpdate {
+        /// Check every field fits its column.
+        pub fn check(&self) -> Result<(), ProfileError> {
+            fits("alias", self.alias.as_ref().and_then(Option::as_ref), ALIAS_MAX)?;
+            fits("motd", self.motd.as_ref().and_then(Option::as_ref), MOTD_MAX)?;
+            fits("pubkey", self.pubkey.as_ref(), PUBKEY_MAX)?;
+            if self.pubkey.as_deref() == Some("") {
+                return Err(ProfileError::EmptyPubkey);
+            }
+            Ok(())
+        }
+    }
+
+    /// Body of every error reply from the API.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ApiError {
+        /// What went wrong. Stable, so clients can match on it.
+        pub code: ApiErrorCode,
+        /// What went wrong, for people. May change between versions.
+        pub message: String,
+        /// Wait this long before trying again. Also sent as `Retry-After`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub retry_after_s: Option<i64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ApiErrorCode {
+        /// The request is malformed, e.g. a body or query that doesn't parse.
+        BadRequest,
+        /// Wrong email or password. Which one is never told.
+        InvalidCredentials,
+        /// The login or refresh token is missing, malformed, expired or revoked.
+        InvalidToken,
+        /// Nothing is there, or it isn't yours to see.
+        NotFound,
+        MethodNotAllowed,
+        /// The email is already registered.
+        UserAlreadyExists,
+        LengthRequired,
+        PayloadTooLarge,
+        UnsupportedMediaType,
+        TooManyAttempts,
+        /// Can't be done as things are, e.g. the owner leaving a group that still has members.
+        Conflict,
+        /// Something failed on the server's side.
+        Internal,
     }
     pub trait ClientboundPayload
     where
@@ -313,9 +857,123 @@ mod shared {
         }
     }
 
+    impl ClientboundPayload for PublicGroupMessage {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::NewGroupMessage(self)
+        }
+    }
+
+    impl ClientboundPayload for Vec<PublicGroupMessage> {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::NewGroupMessages(self)
+        }
+    }
+
+    impl ClientboundPayload for GroupRecord {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::GroupUpdated(self)
+        }
+    }
+
+    impl ClientboundPayload for FriendRequest {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::FriendRequest(self)
+        }
+    }
+
+    impl ClientboundPayload for FriendList {
+        fn make_payload(self) -> WsClientboundPayload {
+            WsClientboundPayload::Friends(self)
+        }
+    }
+
     #[derive(Serialize, Deserialize, Debug)]
     pub enum WsServerboundPayload {
         NewUserMessage { to: UserId, content: ClientMessage },
+        NewGroupMessage { to: GroupId, content: ClientMessage },
+        /// Pick a status and optional status text. Friends are sent `PresenceChanged`.
+        /// A text over `STATUS_TEXT_MAX` characters is answered with `Error`.
+        SetStatus {
+            status: UserStatus,
+            text: Option<String>,
+        },
+        /// Start or keep typing to a user. Must be repeated every few seconds or it lapses.
+        Typing { to: UserId },
+        StoppedTyping { to: UserId },
+        /// Confirm this device got a message sent to you. Each device should send it once per message.
+        /// The sender is sent `Delivered`.
+        Delivered { umid: UserMessageId },
+        /// Flag a message sent to you as read. The sender is sent `Read`.
+        MarkRead { umid: UserMessageId },
+        /// Flag a message of a group you are a member of as read, so catching up stops sending it.
+        MarkGroupRead { group: GroupId, gmid: GroupMessageId },
+        CreateGroup {
+            #[serde(default)]
+            name: Option<String>,
+            motd: Option<String>,
+        },
+        /// Ask to be friends. If `to` already asked you, this accepts their request instead.
+        RequestFriend { to: UserId },
+        AcceptFriend { from: UserId },
+        /// Only your own devices are told, not the sender.
+        DeclineFriend { from: UserId },
+        RemoveFriend { friend: UserId },
+        /// Answered with `Friends`.
+        GetFriends,
+        /// Stop `user` from messaging you, seeing your presence or seeing more than a private profile.
+        /// Also ends any friendship. Answered with `Blocked`.
+        Block { user: UserId },
+        /// Answered with `Blocked`.
+        Unblock { user: UserId },
+        /// Answered with `Blocked`.
+        GetBlocked,
+        /// Only members that aren't muted can invite.
+        InviteToGroup { group: GroupId, invitee: UserId },
+        /// Needs a pending invite, and not being banned.
+        JoinGroup { group: GroupId },
+        /// The owner can only leave once everyone else has, or after `TransferOwnership`.
+        LeaveGroup { group: GroupId },
+        GetGroup { group: GroupId },
+        /**
+        Get a page of the conversation with a user, newest first.
+        For the next page, pass the id of the last message received as `before`.
+        Answered with `NewMessages`.
+        */
+        GetUserHistory {
+            with: UserId,
+            #[serde(default)]
+            query: HistoryQuery,
+            before: Option<UserMessageId>,
+            amt: u16,
+        },
+        /// Like `GetUserHistory`, for a group the asker is a member of. Answered with `NewGroupMessages`.
+        GetGroupHistory {
+            group: GroupId,
+            #[serde(default)]
+            query: HistoryQuery,
+            before: Option<GroupMessageId>,
+            amt: u16,
+        },
+        /// Admins and up.
+        SetGroupName { group: GroupId, name: Option<String> },
+        /// Admins and up.
+        SetGroupMotd { group: GroupId, motd: Option<String> },
+        /// Admins and up, on members they outrank.
+        Kick { group: GroupId, member: UserId },
+        /// Admins and up, on members they outrank. Also drops any pending invite.
+        Ban { group: GroupId, member: UserId },
+        /// Admins and up.
+        Unban { group: GroupId, member: UserId },
+        /// Admins and up, on members they outrank, to a role below their own.
+        SetRole {
+            group: GroupId,
+            member: UserId,
+            role: GroupRole,
+        },
+        /// Owner only. Makes `member` the owner, and the old owner an admin.
+        TransferOwnership { group: GroupId, member: UserId },
+        /// Friends and your other devices are sent `ProfileChanged`.
+        UpdateProfile(ProfileUpdate),
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,23 +1,55 @@
-use std::unimplemented;
-
 use crate::imports::*;
 use crate::symbols::*;
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub db_addr: String,
     pub api_addr: String,
-    pub ws_addr: String
+    /// Dedicated websocket listener. May be left out if `ws_route` is set.
+    #[serde(default)]
+    pub ws_addr: Option<String>,
+    /// Also accept websockets at `/ws` on the API server.
+    #[serde(default)]
+    pub ws_route: bool,
+    #[serde(default)]
+    pub db_pool: DbPoolConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub login_throttle: ThrottleConfig,
+    /// Serve both listeners over TLS. Plain TCP if missing.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CoreConfig {
-    pub db_addr: String
+/// Database connection pool settings. Every field is optional in the launch config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DbPoolConfig {
+    /// Connections opened at startup and kept open.
+    pub min: usize,
+    /// Most connections open at once, which also bounds concurrent queries.
+    pub max: usize,
+    /// How long an operation waits for a free connection, in milliseconds.
+    pub checkout_timeout_ms: u32,
+    /// How long opening a new connection may take, in milliseconds.
+    pub connect_timeout_ms: u64,
+    /// How many times to retry getting a connection while the database is unreachable.
+    pub reconnect_attempts: u32,
 }
 
-impl From<&Config> for CoreConfig {
-    fn from(c: &Config) -> Self {
-        CoreConfig {db_addr: c.db_addr.clone()}
+impl Default for DbPoolConfig {
+    fn default() -> Self {
+        DbPoolConfig {
+            min: 1,
+            max: 10,
+            checkout_timeout_ms: 5000,
+            connect_timeout_ms: 5000,
+            reconnect_attempts: 3,
+        }
     }
 }
 
@@ -41,7 +73,7 @@ impl Core {
             None
         }
     }
-    pub fn run(cc: CoreConfig, chans: CoreChannels) {
+    pub fn run(store: Storage, chans: CoreChannels) {
         let mut r_stop = chans.r_stop.clone();
         std::thread::spawn(move || {
             let mut rt = tokio::runtime::Builder::new()
@@ -50,7 +82,7 @@ impl Core {
                 .build()
                 .unwrap();
             rt.spawn(async move {
-                Core::internal(cc, chans).await;
+                Core::internal(store, chans).await;
             });
             rt.block_on(async move {
                 r_stop.recv().await.unwrap();
@@ -64,42 +96,40 @@ impl Core {
     - process inbound ws messages
     - process inbound core requests
 
+    Storage work is handed off to spawned tasks, so a slow query never holds up the loop.
+    Ws messages go through `WS_LANES` lanes, which keeps each user's messages in order.
+
     Has a shutdown receiver.
     */
-    pub async fn internal(cc: CoreConfig, chans: CoreChannels) {
+    pub async fn internal(store: Storage, chans: CoreChannels) {
         let mut run = true;
         let mut r_f_ws = chans.r_ws;
-        let mut s_t_ws = chans.s_ws;
+        let s_t_ws = chans.s_ws;
         let mut r_stop = chans.r_stop;
         let mut r_corereq = chans.r_corereq;
-        let mut store = Storage::new(
-            &cc.db_addr
-        )
-        .unwrap();
         let mut first_stopped = false;
+        let presence = Presence::default();
+        // a user's messages must be applied in the order they were sent, so each user is pinned to a lane
+        let mut lanes: Vec<Sender<WsToCore>> = (0..WS_LANES)
+            .map(|_| {
+                let (s_lane, r_lane) = tokio::sync::mpsc::channel(1000);
+                tokio::spawn(Core::ws_lane(
+                    r_lane,
+                    store.clone(),
+                    s_t_ws.clone(),
+                    presence.clone(),
+                ));
+                s_lane
+            })
+            .collect();
         info!("Core: started");
         while run {
             debug!("CORE LOOP");
             tokio::select! {
                 Some(m_ws) = r_f_ws.recv() => {
-                    match m_ws {
-                        WsToCore::Tx(r_tx) => {
-                            let (uid, r_tx) = r_tx.extract();
-                            match r_tx {
-                                WsServerboundPayload::NewUserMessage {to, content: c} => {
-                                    if let Some(p_msg) = store.new_message_u(uid.clone(), to.clone(), c.clone()) {
-                                        let send = Some(p_msg) // necessary to use map
-                                            .map(WsClientboundPayload::from)
-                                            .map(WsClientboundTx::from)
-                                            .map(move |tx| CoreToWs::from_tx_u(to, tx))
-                                            .unwrap();
-                                        s_t_ws.send(send).await;
-                                    }
-                                },
-                                _ => unimplemented!()
-                            }
-                        },
-                        _ => unimplemented!()
+                    let lane = u32::from(m_ws.sender()) as usize % WS_LANES;
+                    if lanes[lane].send(m_ws).await.is_err() {
+                        error!("core: ws lane {} closed", lane);
                     }
                 }
                 Some(_) = r_stop.recv() => {
@@ -114,24 +144,786 @@ impl Core {
                 }
                 Some((creq, s)) = r_corereq.recv() => {
                     debug!("core: received corereq");
-                    s.send(Core::handle_corereqs(creq, &mut store).await);
+                    let store = store.clone();
+                    let s_t_ws = s_t_ws.clone();
+                    let presence = presence.clone();
+                    tokio::spawn(async move {
+                        s.send(Core::handle_corereqs(creq, store, s_t_ws, presence).await)
+                    });
+                }
+            }
+        }
+    }
+    /**
+    Run storage work on the blocking thread pool.
+    The number of queries actually running at once is bounded by the backend's connection pool.
+    */
+    async fn blocking<T, F>(store: Storage, f: F) -> Option<T>
+    where
+        F: FnOnce(&Storage) -> Option<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .ok()
+            .flatten()
+    }
+    /**
+    Handle ws messages one at a time, in order.
+    Ephemeral signals never reach storage. Their state lives in the lane, since a sender always maps to the same one.
+    */
+    async fn ws_lane(
+        r_lane: Receiver<WsToCore>,
+        store: Storage,
+        s_t_ws: Sender<CoreToWs>,
+        presence: Presence,
+    ) {
+        let mut r_lane = r_lane;
+        // (from, to) -> when the indicator lapses unless refreshed
+        // tokio's clock rather than std's, so tests can pause it
+        let mut typing: HashMap<(UserId, UserId), tokio::time::Instant> = HashMap::new();
+        let mut expiry = tokio::time::interval(TYPING_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                m_ws = r_lane.recv() => match m_ws {
+                    Some(WsToCore::Tx(tx)) if tx.is_ephemeral() => {
+                        let (uid, tx) = tx.extract();
+                        let pushes = Core::relay_signal(uid, tx, store.clone(), &mut typing).await;
+                        Core::send_pushes(s_t_ws.clone(), pushes).await;
+                    }
+                    Some(m_ws) => {
+                        Core::handle_ws(m_ws, store.clone(), s_t_ws.clone(), presence.clone()).await
+                    }
+                    None => break,
+                },
+                _ = expiry.tick() => {
+                    let now = tokio::time::Instant::now();
+                    let lapsed = typing
+                        .iter()
+                        .filter(|(_, until)| **until <= now)
+                        .map(|(k, _)| *k)
+                        .collect::<Vec<(UserId, UserId)>>();
+                    let pushes = lapsed
+                        .into_iter()
+                        .map(|(from, to)| {
+                            typing.remove(&(from, to));
+                            Core::push_u(to, WsClientboundPayload::StoppedTyping { from })
+                        })
+                        .collect();
+                    Core::send_pushes(s_t_ws.clone(), pushes).await;
+                }
+            }
+        }
+    }
+    /**
+    Relay a signal unless the receiver has blocked the sender, the same check DMs get.
+    Only starting to type is checked; whatever follows only concerns a peer that was already told.
+    */
+    async fn relay_signal(
+        uid: UserId,
+        tx: WsServerboundPayload,
+        store: Storage,
+        typing: &mut HashMap<(UserId, UserId), tokio::time::Instant>,
+    ) -> Vec<CoreToWs> {
+        if let WsServerboundPayload::Typing { to } = tx {
+            if !typing.contains_key(&(uid, to)) {
+                let blocked = Core::blocking(store, move |store| store.has_blocked(to, uid)).await;
+                if uid == to || blocked != Some(false) {
+                    debug!("core: dropped typing from uid {} to {}", &uid, &to);
+                    return vec![];
+                }
+            }
+        }
+        Core::handle_signal(uid, tx, typing)
+    }
+    /// Relay typing indicators. A peer is only told when the state actually changes.
+    fn handle_signal(
+        uid: UserId,
+        tx: WsServerboundPayload,
+        typing: &mut HashMap<(UserId, UserId), tokio::time::Instant>,
+    ) -> Vec<CoreToWs> {
+        match tx {
+            WsServerboundPayload::Typing { to } => {
+                let until = tokio::time::Instant::now() + TYPING_EXPIRY;
+                match typing.insert((uid, to), until) {
+                    Some(_) => vec![],
+                    None => vec![Core::push_u(to, WsClientboundPayload::Typing { from: uid })],
+                }
+            }
+            WsServerboundPayload::StoppedTyping { to } => match typing.remove(&(uid, to)) {
+                Some(_) => vec![Core::push_u(
+                    to,
+                    WsClientboundPayload::StoppedTyping { from: uid },
+                )],
+                None => vec![],
+            },
+            _ => vec![],
+        }
+    }
+    /**
+    Logic for handling messages from ws.
+    */
+    async fn handle_ws(
+        m_ws: WsToCore,
+        store: Storage,
+        s_t_ws: Sender<CoreToWs>,
+        presence: Presence,
+    ) {
+        let pushes = match m_ws {
+            WsToCore::Tx(r_tx) => {
+                let (uid, r_tx) = r_tx.extract();
+                Core::blocking(store, move |store| {
+                    let mut pushes = Vec::new();
+                    if Core::handle_tx(uid, r_tx, store, &presence, &mut pushes).is_none() {
+                        debug!("core: tx from uid {} rejected", &uid);
+                    }
+                    Some(pushes)
+                })
+                .await
+            }
+            WsToCore::Delivered { uid, sid, umid } => {
+                Core::blocking(store, move |store| {
+                    let mut pushes = Vec::new();
+                    Core::mark_delivered(uid, sid, umid, store, &mut pushes)?;
+                    Some(pushes)
+                })
+                .await
+            }
+            WsToCore::Connected { uid, cid } => {
+                let first = presence.connect(uid);
+                Core::blocking(store, move |store| {
+                    let mut pushes = Vec::new();
+                    if first {
+                        Core::push_presence(uid, store, &presence, &mut pushes)?;
+                    }
+                    Core::catch_up(uid, cid, store, &mut pushes)?;
+                    Some(pushes)
+                })
+                .await
+            }
+            WsToCore::Disconnected { uid, cid } => {
+                debug!("core: {} of uid {} gone", &cid, &uid);
+                if presence.disconnect(uid) {
+                    Core::blocking(store, move |store| {
+                        let mut pushes = Vec::new();
+                        Core::push_presence(uid, store, &presence, &mut pushes)?;
+                        Some(pushes)
+                    })
+                    .await
+                } else {
+                    None
+                }
+            }
+        };
+        Core::send_pushes(s_t_ws, pushes.unwrap_or_default()).await;
+    }
+    /**
+    Apply a transmission from a client.
+    Anything that has to reach clients as a result is added to `pushes`.
+    */
+    fn handle_tx(
+        uid: UserId,
+        r_tx: WsServerboundPayload,
+        store: &Storage,
+        presence: &Presence,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<()> {
+        match r_tx {
+            WsServerboundPayload::NewUserMessage { to, content: c } => {
+                let p_msg = store.new_message_u(uid, to, c)?;
+                pushes.push(Core::push_u(to, p_msg));
+            }
+            WsServerboundPayload::Typing { .. } | WsServerboundPayload::StoppedTyping { .. } => {
+                // relayed by the lane without storage
+                return None;
+            }
+            WsServerboundPayload::SetStatus { status, text } => {
+                if let Err(e) = check_status_text(text.as_ref()) {
+                    pushes.push(Core::push_error(uid, ApiErrorCode::BadRequest, e.to_string()));
+                    return None;
+                }
+                store.set_status(uid, status, text.clone())?;
+                Core::push_presence(uid, store, presence, pushes)?;
+                // the user's own devices see what was picked, not what friends see
+                pushes.push(Core::push_u(
+                    uid,
+                    WsClientboundPayload::PresenceChanged { uid, status, text },
+                ));
+            }
+            WsServerboundPayload::UpdateProfile(update) => {
+                Core::update_profile(uid, &update, store, pushes)?;
+            }
+            WsServerboundPayload::RequestFriend { to } => {
+                Core::request_friend(uid, to, store, presence, pushes)??;
+            }
+            WsServerboundPayload::AcceptFriend { from } => {
+                Core::answer_friend_request(uid, from, true, store, presence, pushes)??;
+            }
+            WsServerboundPayload::DeclineFriend { from } => {
+                Core::answer_friend_request(uid, from, false, store, presence, pushes)??;
+            }
+            WsServerboundPayload::RemoveFriend { friend } => {
+                Core::remove_friend(uid, friend, store, pushes)??;
+            }
+            WsServerboundPayload::GetFriends => {
+                let fl = Core::friend_list(uid, store)?;
+                pushes.push(Core::push_u(uid, fl));
+            }
+            WsServerboundPayload::Block { user } => {
+                Core::set_blocked(uid, user, true, store, pushes)??;
+            }
+            WsServerboundPayload::Unblock { user } => {
+                Core::set_blocked(uid, user, false, store, pushes)??;
+            }
+            WsServerboundPayload::GetBlocked => {
+                let blocked = store.get_blocked(uid)?;
+                pushes.push(Core::push_u(uid, WsClientboundPayload::Blocked(blocked)));
+            }
+            // ws turns these into `WsToCore::Delivered`, so they only get here without a session
+            WsServerboundPayload::Delivered { .. } => return None,
+            WsServerboundPayload::MarkRead { umid } => {
+                let at = Utc::now();
+                let sender = store.flag_u_read(umid.clone(), uid, at)?;
+                pushes.push(Core::push_u(sender, WsClientboundPayload::Read { umid, at }));
+            }
+            WsServerboundPayload::MarkGroupRead { group, gmid } => {
+                Core::mark_group_read(uid, group, gmid, store)??;
+            }
+            WsServerboundPayload::NewGroupMessage { to, content: c } => {
+                let g_msg = Core::post_to_group(uid, to, c, store, pushes)?;
+                debug!("core: group message {:?} posted", &g_msg);
+            }
+            WsServerboundPayload::CreateGroup { name, motd } => {
+                let gr = store.create_group(uid, name, motd)?;
+                pushes.push(Core::push_u(uid, gr));
+            }
+            WsServerboundPayload::InviteToGroup { group, invitee } => {
+                Core::require(uid, group, GroupPermission::Invite, store)?;
+                if store.has_blocked(invitee, uid)? {
+                    return None;
+                }
+                if store.is_group_member(invitee, group)? || store.is_banned(invitee, group)? {
+                    return None;
+                }
+                store.invite_to_group(uid, invitee, group)?;
+                pushes.push(Core::push_u(
+                    invitee,
+                    WsClientboundPayload::GroupInvite { group, from: uid },
+                ));
+            }
+            WsServerboundPayload::JoinGroup { group } => {
+                store.join_group(uid, group)?;
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::LeaveGroup { group } => {
+                let role = store.get_member_role(uid, group)??;
+                // don't leave a group behind without an owner
+                if role == GroupRole::Owner && store.get_group_members(group)?.len() > 1 {
+                    let message = "hand the group over with TransferOwnership first".to_owned();
+                    pushes.push(Core::push_error(uid, ApiErrorCode::Conflict, message));
+                    return None;
                 }
+                store.leave_group(uid, group)?;
+                Core::push_group_update(group, vec![uid], store, pushes)?;
+            }
+            WsServerboundPayload::GetUserHistory {
+                with,
+                query,
+                before,
+                amt,
+            } => {
+                let page = Core::user_history(uid, with, &query, before, amt, store)?;
+                pushes.push(Core::push_u(uid, page));
+            }
+            WsServerboundPayload::GetGroupHistory {
+                group,
+                query,
+                before,
+                amt,
+            } => {
+                let page = Core::group_history(uid, group, &query, before, amt, store)??;
+                pushes.push(Core::push_u(uid, page));
+            }
+            WsServerboundPayload::GetGroup { group } => {
+                store.get_member_role(uid, group)??;
+                let gr = store.get_group_data(group)?;
+                pushes.push(Core::push_u(uid, gr));
+            }
+            WsServerboundPayload::SetGroupName { group, name } => {
+                Core::require(uid, group, GroupPermission::EditInfo, store)?;
+                store.set_group_name(group, name)?;
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::SetGroupMotd { group, motd } => {
+                Core::require(uid, group, GroupPermission::EditInfo, store)?;
+                store.set_group_motd(group, motd)?;
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::Kick { group, member } => {
+                Core::require_over(uid, member, group, GroupPermission::Kick, store)?;
+                store.leave_group(member, group)?;
+                pushes.push(Core::push_u(
+                    member,
+                    WsClientboundPayload::RemovedFromGroup {
+                        group,
+                        by: uid,
+                        banned: false,
+                    },
+                ));
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::Ban { group, member } => {
+                let role = Core::require(uid, group, GroupPermission::Ban, store)?;
+                // non-members can be banned pre-emptively, members only by someone who outranks them
+                if let Some(member_role) = store.get_member_role(member, group)? {
+                    if !role.outranks(member_role) {
+                        return None;
+                    }
+                }
+                store.ban_from_group(uid, member, group)?;
+                pushes.push(Core::push_u(
+                    member,
+                    WsClientboundPayload::RemovedFromGroup {
+                        group,
+                        by: uid,
+                        banned: true,
+                    },
+                ));
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::Unban { group, member } => {
+                Core::require(uid, group, GroupPermission::Ban, store)?;
+                store.unban_from_group(member, group)?;
+            }
+            WsServerboundPayload::SetRole {
+                group,
+                member,
+                role: new_role,
+            } => {
+                let role = Core::require_over(uid, member, group, GroupPermission::SetRole, store)?;
+                // can't hand out a role as high as your own, so ownership only moves by transfer
+                if !role.outranks(new_role) {
+                    return None;
+                }
+                store.set_member_role(member, group, new_role)?;
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+            WsServerboundPayload::TransferOwnership { group, member } => {
+                store.transfer_ownership(uid, member, group)?;
+                Core::push_group_update(group, vec![], store, pushes)?;
+            }
+        }
+        Some(())
+    }
+    /**
+    Send a newly connected device everything its user hasn't read yet:
+    one `NewMessages` batch of DMs, then one `NewGroupMessages` batch across all groups.
+    Both are sent even when empty, so the client knows it is caught up.
+    */
+    fn catch_up(
+        uid: UserId,
+        cid: ConnectionId,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<()> {
+        let dms = store.get_user_unread(uid)?;
+        let mut g_msgs = Vec::new();
+        for g in store.get_user_groups(uid)? {
+            g_msgs.extend(store.get_user_group_unread(uid, g)?);
+        }
+        debug!(
+            "core: catching up {} with {} dms, {} group messages",
+            &cid,
+            dms.len(),
+            g_msgs.len()
+        );
+        pushes.push(CoreToWs::SendConnection {
+            cid: cid.clone(),
+            tx: WsClientboundTx::from(WsClientboundPayload::from(dms)),
+        });
+        pushes.push(CoreToWs::SendConnection {
+            cid,
+            tx: WsClientboundTx::from(WsClientboundPayload::from(g_msgs)),
+        });
+        Some(())
+    }
+    /// How a user is currently shown to their friends.
+    fn presence_of(uid: UserId, store: &Storage, presence: &Presence) -> Option<WsClientboundPayload> {
+        let (status, text) = store.get_status(uid)?;
+        let status = status.shown(presence.is_connected(uid));
+        let text = text.filter(|_| status == UserStatus::Online);
+        Some(WsClientboundPayload::PresenceChanged { uid, status, text })
+    }
+    /**
+    Push how a user is currently shown to their friends.
+    Blocked users never get it, since blocking ends the friendship and friend requests can't cross a block.
+    */
+    fn push_presence(
+        uid: UserId,
+        store: &Storage,
+        presence: &Presence,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<()> {
+        let friends = store.get_friends(uid)?;
+        if !friends.is_empty() {
+            pushes.push(Core::push_us(friends, Core::presence_of(uid, store, presence)?));
+        }
+        Some(())
+    }
+    /**
+    Change a user's profile, pushing the new one to their friends and own devices.
+    Returns the profile as the user sees it.
+    */
+    fn update_profile(
+        uid: UserId,
+        update: &ProfileUpdate,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<PublicUserRecord> {
+        update.check().ok()?;
+        store.set_profile(uid, update)?;
+        let pur = store.get_user_data(uid, Some(uid))??;
+        let mut dest = store.get_friends(uid)?;
+        dest.push(uid);
+        pushes.push(Core::push_us(
+            dest,
+            WsClientboundPayload::ProfileChanged {
+                uid,
+                alias: pur.alias.clone(),
+                motd: pur.motd.clone(),
+                pubkey: pur.pubkey.clone(),
+            },
+        ));
+        Some(pur)
+    }
+    /**
+    Ask `to` to be friends. If they already asked `uid`, theirs is accepted instead.
+    The inner `None` means the request was refused, e.g. because one blocked the other.
+    */
+    fn request_friend(
+        uid: UserId,
+        to: UserId,
+        store: &Storage,
+        presence: &Presence,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<Option<FriendRequest>> {
+        if uid == to
+            || store.are_friends(uid, Some(to))?
+            || store.has_blocked(uid, to)?
+            || store.has_blocked(to, uid)?
+        {
+            return Some(None);
+        }
+        let pending = |req: &FriendRequest| req.state == FriendRequestState::Pending;
+        if store.get_friend_request(to, uid)?.filter(pending).is_some() {
+            return Core::answer_friend_request(uid, to, true, store, presence, pushes);
+        }
+        if store.get_friend_request(uid, to)?.filter(pending).is_some() {
+            return Some(None);
+        }
+        let req = store.set_friend_request(uid, to, FriendRequestState::Pending, Utc::now())?;
+        pushes.push(Core::push_us(vec![uid, to], req.clone()));
+        Some(Some(req))
+    }
+    /**
+    Accept or decline the pending request `from` sent to `uid`. Only `uid` is told about a decline.
+    The inner `None` means there was no such request.
+    */
+    fn answer_friend_request(
+        uid: UserId,
+        from: UserId,
+        accept: bool,
+        store: &Storage,
+        presence: &Presence,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<Option<FriendRequest>> {
+        let at = Utc::now();
+        let pending = store
+            .get_friend_request(from, uid)?
+            .filter(|req| req.state == FriendRequestState::Pending);
+        if pending.is_none() {
+            return Some(None);
+        }
+        if accept {
+            let req = store.accept_friend_request(from, uid, at)?;
+            pushes.push(Core::push_us(vec![from, uid], req.clone()));
+            // friends from now on, so each learns whether the other is around
+            pushes.push(Core::push_u(uid, Core::presence_of(from, store, presence)?));
+            pushes.push(Core::push_u(from, Core::presence_of(uid, store, presence)?));
+            Some(Some(req))
+        } else {
+            let req = store.set_friend_request(from, uid, FriendRequestState::Declined, at)?;
+            pushes.push(Core::push_u(uid, req.clone()));
+            Some(Some(req))
+        }
+    }
+    /// End a friendship. Both sides are told. The inner `None` means they weren't friends.
+    fn remove_friend(
+        uid: UserId,
+        friend: UserId,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<Option<()>> {
+        if !store.are_friends(uid, Some(friend))? {
+            return Some(None);
+        }
+        store.remove_friend(uid, friend)?;
+        pushes.push(Core::push_u(uid, WsClientboundPayload::FriendRemoved { friend }));
+        pushes.push(Core::push_u(
+            friend,
+            WsClientboundPayload::FriendRemoved { friend: uid },
+        ));
+        Some(Some(()))
+    }
+    /**
+    Block or unblock `other` for `uid`, then push the block list to `uid`.
+    The inner `None` means there was nothing to do it to: `uid` themselves, or someone who isn't blocked.
+    */
+    fn set_blocked(
+        uid: UserId,
+        other: UserId,
+        blocked: bool,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<Option<Vec<UserId>>> {
+        if uid == other {
+            return Some(None);
+        }
+        if blocked {
+            let were_friends = store.are_friends(uid, Some(other))?;
+            store.block_user(uid, other)?;
+            if were_friends {
+                pushes.push(Core::push_u(uid, WsClientboundPayload::FriendRemoved { friend: other }));
+                pushes.push(Core::push_u(
+                    other,
+                    WsClientboundPayload::FriendRemoved { friend: uid },
+                ));
+            }
+        } else {
+            if !store.has_blocked(uid, other)? {
+                return Some(None);
+            }
+            store.unblock_user(uid, other)?;
+        }
+        let list = store.get_blocked(uid)?;
+        pushes.push(Core::push_u(uid, WsClientboundPayload::Blocked(list.clone())));
+        Some(Some(list))
+    }
+    fn friend_list(uid: UserId, store: &Storage) -> Option<FriendList> {
+        Some(FriendList {
+            friends: store.get_friends(uid)?,
+            requests: store.get_friend_requests(uid)?,
+        })
+    }
+    /// Post to a group as a member, fanning the message out to every member.
+    fn post_to_group(
+        uid: UserId,
+        g: GroupId,
+        c: ClientMessage,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<PublicGroupMessage> {
+        Core::require(uid, g, GroupPermission::Post, store)?;
+        let members = store.get_group_members(g)?;
+        let g_msg = store.new_message_g(uid, g, c)?;
+        pushes.push(Core::push_us(members, g_msg.clone()));
+        Some(g_msg)
+    }
+    /// Record that session `sid` of receiver `uid` got a user message, and tell the sender.
+    fn mark_delivered(
+        uid: UserId,
+        sid: SessionId,
+        umid: UserMessageId,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<()> {
+        let at = Utc::now();
+        let (sender, devices) = store.flag_u_delivered(umid.clone(), uid, sid, at)?;
+        pushes.push(Core::push_u(
+            sender,
+            WsClientboundPayload::Delivered { umid, at, devices },
+        ));
+        Some(())
+    }
+    /// Flag a group message as read by a member.
+    /// Flag `gmid` as read by member `uid`. The inner `None` means `uid` isn't a member.
+    fn mark_group_read(
+        uid: UserId,
+        g: GroupId,
+        gmid: GroupMessageId,
+        store: &Storage,
+    ) -> Option<Option<()>> {
+        if store.get_member_role(uid, g)?.is_none() {
+            return Some(None);
+        }
+        store.flag_g_read(g, gmid, uid).map(Some)
+    }
+    /// Get a page of DM history, capped at `HISTORY_PAGE_MAX` messages.
+    fn user_history(
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+        store: &Storage,
+    ) -> Option<Vec<PublicUserMessage>> {
+        store.get_user_history(s, r, q, before, amt.min(HISTORY_PAGE_MAX))
+    }
+    /**
+    Get a page of group history for a member, capped at `HISTORY_PAGE_MAX` messages.
+    The inner `None` means `s` isn't a member.
+    */
+    fn group_history(
+        s: UserId,
+        g: GroupId,
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+        store: &Storage,
+    ) -> Option<Option<Vec<PublicGroupMessage>>> {
+        if store.get_member_role(s, g)?.is_none() {
+            return Some(None);
+        }
+        store
+            .get_group_history(s, g, q, before, amt.min(HISTORY_PAGE_MAX))
+            .map(Some)
+    }
+    /// Get `uid`'s role in `g` if they are a member and the role grants `p`.
+    fn require(uid: UserId, g: GroupId, p: GroupPermission, store: &Storage) -> Option<GroupRole> {
+        store
+            .get_member_role(uid, g)?
+            .filter(|role| role.allows(p))
+    }
+    /// Like `require`, for acting on `target`, who must be a member that `uid` outranks.
+    fn require_over(
+        uid: UserId,
+        target: UserId,
+        g: GroupId,
+        p: GroupPermission,
+        store: &Storage,
+    ) -> Option<GroupRole> {
+        let role = Core::require(uid, g, p, store)?;
+        let target_role = store.get_member_role(target, g)??;
+        Some(role).filter(|role| role.outranks(target_role))
+    }
+    /// Push the current state of a group to its members, and to `extra` users that just left.
+    fn push_group_update(
+        g: GroupId,
+        extra: Vec<UserId>,
+        store: &Storage,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<()> {
+        let gr = store.get_group_data(g)?;
+        let mut dest = gr.member_ids();
+        dest.extend(extra);
+        pushes.push(Core::push_us(dest, gr));
+        Some(())
+    }
+    /// Build a push of a payload to all of a user's devices.
+    fn push_u<P: Into<WsClientboundPayload>>(dest: UserId, pl: P) -> CoreToWs {
+        CoreToWs::from_tx_u(dest, WsClientboundTx::from(pl.into()))
+    }
+    /// Build a push telling all of a user's devices why their last transmission was refused.
+    fn push_error(dest: UserId, code: ApiErrorCode, message: String) -> CoreToWs {
+        let err = ApiError {
+            code,
+            message,
+            retry_after_s: None,
+        };
+        Core::push_u(dest, WsClientboundPayload::Error(err))
+    }
+    /// Build a push of a payload to all devices of several users.
+    fn push_us<P: Into<WsClientboundPayload>>(dest: Vec<UserId>, pl: P) -> CoreToWs {
+        CoreToWs::from_tx_us(dest, WsClientboundTx::from(pl.into()))
+    }
+    async fn send_pushes(s_t_ws: Sender<CoreToWs>, pushes: Vec<CoreToWs>) {
+        let mut s_t_ws = s_t_ws;
+        for p in pushes {
+            if s_t_ws.send(p).await.is_err() {
+                warn!("core: ws channel closed, dropping push");
             }
         }
     }
     /**
     Logic for handling core requests.
     */
-    async fn handle_corereqs(creq: CoreRequest, store: &mut Storage) -> Option<CoreReply> {
+    async fn handle_corereqs(
+        creq: CoreRequest,
+        store: Storage,
+        s_t_ws: Sender<CoreToWs>,
+        presence: Presence,
+    ) -> Option<CoreReply> {
         debug!("handling corereq {:?}", &creq);
+        let (reply, pushes) = Core::blocking(store, move |store| {
+            let mut pushes = Vec::new();
+            let reply = Core::handle_corereq(creq, store, &presence, &mut pushes);
+            Some((reply, pushes))
+        })
+        .await?;
+        Core::send_pushes(s_t_ws, pushes).await;
+        reply
+    }
+    /// Answer with `NotFound` when there was nothing to find, and `reply` otherwise. A failure stays `None`.
+    fn found<T>(res: Option<Option<T>>, reply: impl FnOnce(T) -> CoreReply) -> Option<CoreReply> {
+        res.map(|found| found.map_or(CoreReply::NotFound, reply))
+    }
+    fn handle_corereq(
+        creq: CoreRequest,
+        store: &Storage,
+        presence: &Presence,
+        pushes: &mut Vec<CoreToWs>,
+    ) -> Option<CoreReply> {
         match creq {
-            CoreRequest::Login(req) => store.try_login(req).map(CoreReply::Login).ok(),
-            CoreRequest::Register(req) => store.try_register(req).map(CoreReply::Register).ok(),
-            CoreRequest::GetUserData { lookup, asker } => store
-                .get_user_data(lookup, asker)
-                .map(CoreReply::GetUserData),
-            CoreRequest::GetGroupData(gid) => {
-                store.get_group_data(gid).map(CoreReply::GetGroupData)
+            CoreRequest::Login(req) => match store.try_login(req) {
+                Ok(uid) => Some(CoreReply::Login(uid)),
+                // storage trouble isn't the user's fault, so it stays a failed request
+                Err(LoginError::InvalidEmail) | Err(LoginError::InvalidPassword) => {
+                    Some(CoreReply::LoginRejected)
+                }
+                Err(_) => None,
+            },
+            CoreRequest::Register(req) => match store.try_register(req) {
+                Ok(uid) => Some(CoreReply::Register(uid)),
+                Err(RegisterError::UserAlreadyExists) => Some(CoreReply::UserAlreadyExists),
+                Err(_) => None,
+            },
+            CoreRequest::GetUserData { lookup, asker } => {
+                let mut pur = match store.get_user_data(lookup, asker)? {
+                    Some(pur) => pur,
+                    None => return Some(CoreReply::NotFound),
+                };
+                let blocked = match asker {
+                    Some(asker) => store.has_blocked(lookup, asker)?,
+                    None => false,
+                };
+                pur.online = pur.online && presence.is_connected(lookup) && !blocked;
+                if blocked {
+                    pur.status_text = None;
+                }
+                Some(CoreReply::GetUserData(pur))
+            }
+            CoreRequest::SearchUsers {
+                alias,
+                email,
+                asker,
+                after,
+                amt,
+            } => {
+                let amt = amt.min(SEARCH_PAGE_MAX);
+                let mut found =
+                    store.search_users(alias.as_deref(), email.as_deref(), asker, after, amt)?;
+                // blocked users aren't found at all, so only connections are left to check
+                for pur in &mut found {
+                    pur.online = pur.online && presence.is_connected(pur.uid);
+                }
+                Some(CoreReply::Users(found))
+            }
+            CoreRequest::GetGroupData { lookup, asker } => {
+                // only members can see a group
+                if store.get_member_role(asker, lookup)?.is_none() {
+                    return Some(CoreReply::NotFound);
+                }
+                store.get_group_data(lookup).map(CoreReply::GetGroupData)
             }
             CoreRequest::GetUserUserUnread { s, r } => store
                 .get_user_user_unread(s, r)
@@ -147,14 +939,100 @@ impl Core {
                 store.new_message_u(u, d, c).map(CoreReply::NewUserMessage)
             }
             CoreRequest::NewGroupMessage { u, g, c } => {
-                store.new_message_g(u, g, c).map(CoreReply::NewGroupMessage)
+                Core::post_to_group(u, g, c, store, pushes).map(CoreReply::NewGroupMessage)
+            }
+            CoreRequest::MarkGroupRead { u, g, gmid } => {
+                Core::found(Core::mark_group_read(u, g, gmid, store), |_| CoreReply::GroupRead(gmid))
+            }
+            CoreRequest::UpdateProfile { u, update } => {
+                Core::update_profile(u, &update, store, pushes).map(CoreReply::GetUserData)
             }
-            CoreRequest::GetUserLast { s, r, amt } => unimplemented!(),
-            CoreRequest::GetGroupLast { s, g, amt } => unimplemented!(),
+            CoreRequest::RequestFriend { u, to } => Core::found(
+                Core::request_friend(u, to, store, presence, pushes),
+                CoreReply::FriendRequest,
+            ),
+            CoreRequest::AnswerFriendRequest { u, from, accept } => Core::found(
+                Core::answer_friend_request(u, from, accept, store, presence, pushes),
+                CoreReply::FriendRequest,
+            ),
+            CoreRequest::RemoveFriend { u, friend } => {
+                if Core::remove_friend(u, friend, store, pushes)?.is_none() {
+                    return Some(CoreReply::NotFound);
+                }
+                Core::friend_list(u, store).map(CoreReply::Friends)
+            }
+            CoreRequest::GetFriends { u } => Core::friend_list(u, store).map(CoreReply::Friends),
+            CoreRequest::SetBlocked { u, other, blocked } => Core::found(
+                Core::set_blocked(u, other, blocked, store, pushes),
+                CoreReply::Blocked,
+            ),
+            CoreRequest::GetBlocked { u } => store.get_blocked(u).map(CoreReply::Blocked),
+            CoreRequest::GetUserLast {
+                s,
+                r,
+                query,
+                before,
+                amt,
+            } => Core::user_history(s, r, &query, before, amt, store).map(CoreReply::UserHistory),
+            CoreRequest::GetGroupLast {
+                s,
+                g,
+                query,
+                before,
+                amt,
+            } => Core::found(
+                Core::group_history(s, g, &query, before, amt, store),
+                CoreReply::GroupHistory,
+            ),
+        }
+    }
+}
+
+/**
+Number of connected devices per user, shared by every lane and core request.
+A user's connects and disconnects all go through their lane, so they are counted in order.
+*/
+#[derive(Clone, Default)]
+pub struct Presence {
+    conns: Arc<ShardedLock<HashMap<UserId, usize>>>,
+}
+
+impl Presence {
+    /// Count a new device. Returns whether it is the user's first.
+    pub fn connect(&self, uid: UserId) -> bool {
+        let mut conns = self.conns.write().unwrap();
+        let n = conns.entry(uid).or_insert(0);
+        *n += 1;
+        *n == 1
+    }
+    /// Count a device going away. Returns whether it was the user's last.
+    pub fn disconnect(&self, uid: UserId) -> bool {
+        let mut conns = self.conns.write().unwrap();
+        match conns.get_mut(&uid) {
+            Some(n) if *n > 1 => {
+                *n -= 1;
+                false
+            }
+            Some(_) => {
+                conns.remove(&uid);
+                true
+            }
+            None => false,
         }
     }
+    pub fn is_connected(&self, uid: UserId) -> bool {
+        self.conns.read().unwrap().contains_key(&uid)
+    }
 }
 
+/// How long a typing indicator lasts without being refreshed by another `Typing`.
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(6);
+/// How often lapsed typing indicators are looked for.
+pub const TYPING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of lanes ws messages are spread over. Bounds how many are handled at once.
+pub const WS_LANES: usize = 16;
+
 #[derive(Debug)]
 pub enum CoreRequest {
     Login(LoginRequest),
@@ -163,7 +1041,18 @@ pub enum CoreRequest {
         lookup: UserId,
         asker: Option<UserId>,
     },
-    GetGroupData(GroupId),
+    /// A page of users `asker` may discover, capped at `SEARCH_PAGE_MAX`.
+    SearchUsers {
+        alias: Option<String>,
+        email: Option<String>,
+        asker: Option<UserId>,
+        after: Option<UserId>,
+        amt: u16,
+    },
+    GetGroupData {
+        lookup: GroupId,
+        asker: UserId,
+    },
     GetUserUserUnread {
         s: UserId,
         r: UserId,
@@ -172,14 +1061,20 @@ pub enum CoreRequest {
         s: UserId,
         g: GroupId,
     },
+    /// History of the conversation between `s` and `r`, asked by `s`.
     GetUserLast {
         s: UserId,
         r: UserId,
+        query: HistoryQuery,
+        before: Option<UserMessageId>,
         amt: u16,
     },
+    /// History of a group, asked by member `s`.
     GetGroupLast {
         s: UserId,
         g: GroupId,
+        query: HistoryQuery,
+        before: Option<GroupMessageId>,
         amt: u16,
     },
     NewUserMessage {
@@ -192,16 +1087,69 @@ pub enum CoreRequest {
         g: GroupId,
         c: ClientMessage,
     },
+    /// Member `u` has read `gmid` of `g`.
+    MarkGroupRead {
+        u: UserId,
+        g: GroupId,
+        gmid: GroupMessageId,
+    },
+    /// Answered with `u`'s own profile as it is now.
+    UpdateProfile {
+        u: UserId,
+        update: ProfileUpdate,
+    },
+    /// `u` asks `to` to be friends.
+    RequestFriend {
+        u: UserId,
+        to: UserId,
+    },
+    /// `u` accepts or declines the request `from` sent them.
+    AnswerFriendRequest {
+        u: UserId,
+        from: UserId,
+        accept: bool,
+    },
+    /// Answered with what is left of `u`'s friend list.
+    RemoveFriend {
+        u: UserId,
+        friend: UserId,
+    },
+    GetFriends {
+        u: UserId,
+    },
+    /// `u` blocks or unblocks `other`. Answered with `u`'s block list.
+    SetBlocked {
+        u: UserId,
+        other: UserId,
+        blocked: bool,
+    },
+    GetBlocked {
+        u: UserId,
+    },
 }
 
 #[derive(Debug)]
 pub enum CoreReply {
     Login(UserId),
+    /// Wrong email or password. Which one isn't passed on.
+    LoginRejected,
     Register(UserId),
+    /// The email asked to register with is taken.
+    UserAlreadyExists,
     GetUserData(PublicUserRecord),
+    Users(Vec<PublicUserRecord>),
     GetGroupData(GroupRecord),
     ClientboundTx(WsClientboundTx),
     ClientboundTxs(Vec<WsClientboundTx>),
     NewUserMessage(PublicUserMessage),
-    NewGroupMessage(GroupMessageId),
+    NewGroupMessage(PublicGroupMessage),
+    /// The group message now flagged as read.
+    GroupRead(GroupMessageId),
+    UserHistory(Vec<PublicUserMessage>),
+    GroupHistory(Vec<PublicGroupMessage>),
+    FriendRequest(FriendRequest),
+    Friends(FriendList),
+    Blocked(Vec<UserId>),
+    /// Nothing found, or nothing the asker may see or act on. `None` is kept for failures.
+    NotFound,
 }
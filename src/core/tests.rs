@@ -0,0 +1,771 @@
+/*!
+Core logic run straight against the embedded backends. Only the pushes a client would receive are looked at.
+*/
+use crate::fixtures::*;
+use crate::imports::*;
+use crate::symbols::*;
+
+/// Apply a transmission, returning what it pushed. `None` if it was rejected.
+fn tx(s: &Storage, uid: UserId, pl: WsServerboundPayload) -> Option<Vec<CoreToWs>> {
+    let mut pushes = Vec::new();
+    Core::handle_tx(uid, pl, s, &Presence::default(), &mut pushes)?;
+    Some(pushes)
+}
+
+/// The payload of a push, as the client gets it.
+fn payload(p: &CoreToWs) -> serde_json::Value {
+    let tx = match p {
+        CoreToWs::SendDirect { tx, .. }
+        | CoreToWs::SendMultiple { tx, .. }
+        | CoreToWs::SendConnection { tx, .. } => tx.clone(),
+    };
+    match tx.extract() {
+        Some(tungstenite::Message::Text(s)) => serde_json::from_str(&s).unwrap(),
+        m => panic!("not text: {:?}", m),
+    }
+}
+
+/// Make a group owned by `owner` with `members` joined, in that order.
+fn group(s: &Storage, owner: UserId, members: &[UserId]) -> GroupId {
+    let g = s.create_group(owner, None, None).unwrap().gid;
+    for m in members {
+        s.invite_to_group(owner, *m, g).unwrap();
+        s.join_group(*m, g).unwrap();
+    }
+    g
+}
+
+fn post(s: &Storage, uid: UserId, g: GroupId, content: &str) -> GroupMessageId {
+    let m = s
+        .new_message_g(uid, g, ClientMessage::from(content.to_owned()))
+        .unwrap();
+    GroupMessageId::from(serde_json::to_value(&m).unwrap()["gmid"].as_u64().unwrap())
+}
+
+/// Group messages a new device of `uid` would be caught up with.
+fn caught_up_groups(s: &Storage, uid: UserId) -> Vec<GroupMessageId> {
+    let mut pushes = Vec::new();
+    Core::catch_up(uid, ConnectionId(0), s, &mut pushes).unwrap();
+    assert_eq!(pushes.len(), 2);
+    payload(&pushes[1])["NewGroupMessages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| GroupMessageId::from(m["gmid"].as_u64().unwrap()))
+        .collect()
+}
+
+#[test]
+fn catch_up_skips_read_group_messages() {
+    for (name, s) in stores() {
+        let (a, b, c) = (register(&s, "a"), register(&s, "b"), register(&s, "c"));
+        let g = group(&s, a, &[b]);
+        let other = group(&s, c, &[]);
+        let m1 = post(&s, a, g, "one");
+        let m2 = post(&s, a, g, "two");
+        let elsewhere = post(&s, c, other, "elsewhere");
+        assert_eq!(caught_up_groups(&s, b), vec![m1, m2], "{}", name);
+        // what was sent is sent again until it is read
+        assert_eq!(caught_up_groups(&s, b), vec![m1, m2], "{}", name);
+
+        let read = |uid, group, gmid| tx(&s, uid, WsServerboundPayload::MarkGroupRead { group, gmid });
+        read(b, g, m1).unwrap();
+        assert_eq!(caught_up_groups(&s, b), vec![m2], "{}", name);
+        // marking twice is fine
+        read(b, g, m1).unwrap();
+        read(b, g, m2).unwrap();
+        assert!(caught_up_groups(&s, b).is_empty(), "{}", name);
+        // read by one member is still unread for the rest
+        let m3 = post(&s, b, g, "three");
+        assert_eq!(caught_up_groups(&s, a), vec![m3], "{}", name);
+
+        // only members, and only messages of the group named
+        assert!(read(c, g, m3).is_none(), "{}", name);
+        assert!(read(a, g, elsewhere).is_none(), "{}", name);
+        assert_eq!(caught_up_groups(&s, a), vec![m3], "{}", name);
+        assert_eq!(caught_up_groups(&s, c), vec![], "{}", name);
+        let unseen = s
+            .get_group_history(a, g, &HistoryQuery::Unseen, None, HISTORY_PAGE_MAX)
+            .unwrap();
+        assert_eq!(unseen.len(), 1, "{}", name);
+    }
+}
+
+/// Relay a typing signal through `typing`, returning how many pushes it made.
+async fn signal(
+    s: &Storage,
+    typing: &mut HashMap<(UserId, UserId), tokio::time::Instant>,
+    uid: UserId,
+    pl: WsServerboundPayload,
+) -> usize {
+    Core::relay_signal(uid, pl, s.clone(), typing).await.len()
+}
+
+#[tokio::test]
+async fn typing_is_dropped_once_blocked() {
+    for (name, s) in stores() {
+        let (a, b) = (register(&s, "a"), register(&s, "b"));
+        let t = &mut HashMap::new();
+        assert_eq!(signal(&s, t, a, WsServerboundPayload::Typing { to: b }).await, 1, "{}", name);
+        assert_eq!(signal(&s, t, a, WsServerboundPayload::Typing { to: b }).await, 0, "{}", name);
+        let stopped = WsServerboundPayload::StoppedTyping { to: b };
+        assert_eq!(signal(&s, t, a, stopped).await, 1, "{}", name);
+
+        s.block_user(b, a).unwrap();
+        assert_eq!(signal(&s, t, a, WsServerboundPayload::Typing { to: b }).await, 0, "{}", name);
+        let stopped = WsServerboundPayload::StoppedTyping { to: b };
+        assert_eq!(signal(&s, t, a, stopped).await, 0, "{}", name);
+        // blocking only goes one way
+        assert_eq!(signal(&s, t, b, WsServerboundPayload::Typing { to: a }).await, 1, "{}", name);
+        assert_eq!(signal(&s, t, a, WsServerboundPayload::Typing { to: a }).await, 0, "{}", name);
+
+        s.unblock_user(b, a).unwrap();
+        assert_eq!(signal(&s, t, a, WsServerboundPayload::Typing { to: b }).await, 1, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn typing_lapses_once_without_a_refresh() {
+    tokio::time::pause();
+    for (name, s) in stores() {
+        let (a, b) = (register(&s, "a"), register(&s, "b"));
+        let (mut s_lane, r_lane) = mpsc::channel(8);
+        let (s_t_ws, mut r_t_ws) = mpsc::channel(8);
+        tokio::spawn(Core::ws_lane(r_lane, s.clone(), s_t_ws, Presence::default()));
+        let typing = serde_json::to_string(&WsServerboundPayload::Typing { to: b }).unwrap();
+        let typing = WsServerboundTx::new(a, tungstenite::Message::Text(typing)).unwrap();
+        s_lane.send(WsToCore::from(typing)).await.unwrap();
+        let p = r_t_ws.recv().await.unwrap();
+        assert_eq!(payload(&p)["Typing"]["from"], serde_json::json!(a), "{}", name);
+
+        tokio::time::advance(TYPING_EXPIRY + TYPING_CHECK_INTERVAL).await;
+        let p = r_t_ws.recv().await.unwrap();
+        assert_eq!(payload(&p)["StoppedTyping"]["from"], serde_json::json!(a), "{}", name);
+        // a lapsed indicator is gone, so later checks find nothing more to stop
+        tokio::time::advance(TYPING_EXPIRY + TYPING_CHECK_INTERVAL).await;
+        drop(s_lane);
+        assert!(r_t_ws.recv().await.is_none(), "{}", name);
+    }
+}
+
+/// Confirm delivery of `umid` from session `sid` of `uid`, returning what it pushed.
+fn deliver(s: &Storage, uid: UserId, sid: u64, umid: UserMessageId) -> Option<Vec<CoreToWs>> {
+    let mut pushes = Vec::new();
+    Core::mark_delivered(uid, SessionId::from(sid), umid, s, &mut pushes)?;
+    Some(pushes)
+}
+
+#[test]
+fn delivery_is_confirmed_per_device() {
+    for (name, s) in stores() {
+        let (a, b, c) = (register(&s, "a"), register(&s, "b"), register(&s, "c"));
+        let umid = s.new_message_u(a, b, ClientMessage::from("hi".to_owned())).unwrap().umid();
+        let delivered = || {
+            s.get_user_history(a, b, &HistoryQuery::All, None, HISTORY_PAGE_MAX).unwrap()[0]
+                .clone()
+        };
+        assert!(serde_json::to_value(delivered()).unwrap()["delivered"].is_null(), "{}", name);
+
+        let pushes = deliver(&s, b, 1, umid.clone()).unwrap();
+        assert_eq!(pushes.len(), 1, "{}", name);
+        assert_eq!(payload(&pushes[0])["Delivered"]["devices"], 1, "{}", name);
+        let first = serde_json::to_value(delivered()).unwrap()["delivered"].clone();
+        assert!(!first.is_null(), "{}", name);
+
+        // the same device only counts once
+        assert!(deliver(&s, b, 1, umid.clone()).is_none(), "{}", name);
+        let pushes = deliver(&s, b, 2, umid.clone()).unwrap();
+        assert_eq!(payload(&pushes[0])["Delivered"]["devices"], 2, "{}", name);
+        // the first delivery is what the sender sees in history
+        assert_eq!(serde_json::to_value(delivered()).unwrap()["delivered"], first, "{}", name);
+
+        // only the receiver's devices, and never without knowing the device
+        assert!(deliver(&s, c, 3, umid.clone()).is_none(), "{}", name);
+        assert!(deliver(&s, a, 3, umid.clone()).is_none(), "{}", name);
+        assert!(tx(&s, b, WsServerboundPayload::Delivered { umid: umid.clone() }).is_none(), "{}", name);
+        let missing = UserMessageId::from(u64::from(umid) + 100);
+        assert!(deliver(&s, b, 3, missing).is_none(), "{}", name);
+    }
+}
+
+#[test]
+fn status_text_is_checked() {
+    for (name, s) in stores() {
+        let a = register(&s, "a");
+        let mut pushes = Vec::new();
+        let status = |text: String| WsServerboundPayload::SetStatus {
+            status: UserStatus::Online,
+            text: Some(text),
+        };
+        let too_long = status("é".repeat(STATUS_TEXT_MAX + 1));
+        let rejected = Core::handle_tx(a, too_long, &s, &Presence::default(), &mut pushes);
+        assert!(rejected.is_none(), "{}", name);
+        // the sender is still told why
+        assert_eq!(kinds(&pushes), ["Error"], "{}", name);
+        assert_eq!(
+            payload(&pushes[0])["Error"]["code"],
+            "BadRequest",
+            "{}",
+            name
+        );
+        assert_eq!(s.get_status(a).unwrap().1, None, "{}", name);
+
+        let text = "é".repeat(STATUS_TEXT_MAX);
+        tx(&s, a, status(text.clone())).unwrap();
+        assert_eq!(s.get_status(a).unwrap().1, Some(text), "{}", name);
+    }
+}
+
+#[test]
+fn roles_rank_and_allow() {
+    use GroupRole::*;
+    let roles = [Owner, Admin, Member, Muted];
+    for (i, r) in roles.iter().enumerate() {
+        for (j, other) in roles.iter().enumerate() {
+            assert_eq!(r.outranks(*other), i < j, "{:?} over {:?}", r, other);
+        }
+    }
+    let allowed = |p| -> Vec<GroupRole> { roles.iter().copied().filter(|r| r.allows(p)).collect() };
+    assert_eq!(allowed(GroupPermission::Post), [Owner, Admin, Member]);
+    assert_eq!(allowed(GroupPermission::Invite), [Owner, Admin, Member]);
+    for p in [
+        GroupPermission::EditInfo,
+        GroupPermission::Kick,
+        GroupPermission::Ban,
+        GroupPermission::SetRole,
+    ] {
+        assert_eq!(allowed(p), [Owner, Admin], "{:?}", p);
+    }
+}
+
+#[test]
+fn group_permissions_are_enforced() {
+    for (name, s) in stores() {
+        let (o, ad, m, mu, x) = (
+            register(&s, "o"),
+            register(&s, "ad"),
+            register(&s, "m"),
+            register(&s, "mu"),
+            register(&s, "x"),
+        );
+        let g = group(&s, o, &[ad, m, mu]);
+        s.set_member_role(ad, g, GroupRole::Admin).unwrap();
+        s.set_member_role(mu, g, GroupRole::Muted).unwrap();
+
+        let require = |uid, p| Core::require(uid, g, p, &s);
+        assert_eq!(
+            require(m, GroupPermission::Post),
+            Some(GroupRole::Member),
+            "{}",
+            name
+        );
+        assert_eq!(require(mu, GroupPermission::Post), None, "{}", name);
+        assert_eq!(require(m, GroupPermission::Kick), None, "{}", name);
+        assert_eq!(
+            require(ad, GroupPermission::Kick),
+            Some(GroupRole::Admin),
+            "{}",
+            name
+        );
+        assert_eq!(
+            require(x, GroupPermission::Post),
+            None,
+            "{}: outsider",
+            name
+        );
+
+        let over = |uid, target| Core::require_over(uid, target, g, GroupPermission::Kick, &s);
+        assert_eq!(over(o, ad), Some(GroupRole::Owner), "{}", name);
+        assert_eq!(over(ad, m), Some(GroupRole::Admin), "{}", name);
+        assert_eq!(over(ad, o), None, "{}: admin over owner", name);
+        assert_eq!(over(ad, ad), None, "{}: same rank", name);
+        assert_eq!(over(m, mu), None, "{}: member can't kick", name);
+        assert_eq!(over(ad, x), None, "{}: not a member", name);
+
+        // members can't kick or ban admins, and admins can't hand out their own rank
+        let kick = |uid, member| tx(&s, uid, WsServerboundPayload::Kick { group: g, member });
+        let set_role = |uid, member, role| {
+            tx(
+                &s,
+                uid,
+                WsServerboundPayload::SetRole {
+                    group: g,
+                    member,
+                    role,
+                },
+            )
+        };
+        assert!(kick(m, ad).is_none(), "{}", name);
+        assert!(
+            tx(
+                &s,
+                m,
+                WsServerboundPayload::Ban {
+                    group: g,
+                    member: ad
+                }
+            )
+            .is_none(),
+            "{}",
+            name
+        );
+        assert!(
+            tx(
+                &s,
+                ad,
+                WsServerboundPayload::Ban {
+                    group: g,
+                    member: o
+                }
+            )
+            .is_none(),
+            "{}",
+            name
+        );
+        assert!(set_role(ad, m, GroupRole::Admin).is_none(), "{}", name);
+        assert!(set_role(o, m, GroupRole::Owner).is_none(), "{}", name);
+        assert_eq!(
+            s.get_member_role(ad, g).unwrap(),
+            Some(GroupRole::Admin),
+            "{}",
+            name
+        );
+        assert_eq!(
+            s.get_member_role(m, g).unwrap(),
+            Some(GroupRole::Member),
+            "{}",
+            name
+        );
+        set_role(ad, m, GroupRole::Muted).unwrap();
+        assert!(
+            tx(
+                &s,
+                ad,
+                WsServerboundPayload::Ban {
+                    group: g,
+                    member: x
+                }
+            )
+            .is_some(),
+            "{}",
+            name
+        );
+        kick(ad, mu).unwrap();
+        assert!(!s.is_group_member(mu, g).unwrap(), "{}", name);
+
+        // the owner has to hand the group over or go last, and is told so
+        let leave = |uid| tx(&s, uid, WsServerboundPayload::LeaveGroup { group: g });
+        let mut pushes = Vec::new();
+        let pl = WsServerboundPayload::LeaveGroup { group: g };
+        assert!(Core::handle_tx(o, pl, &s, &Presence::default(), &mut pushes).is_none());
+        assert_eq!(payload(&pushes[0])["Error"]["code"], "Conflict", "{}", name);
+        assert!(s.is_group_member(o, g).unwrap(), "{}", name);
+        let transfer = |uid, member| {
+            tx(
+                &s,
+                uid,
+                WsServerboundPayload::TransferOwnership { group: g, member },
+            )
+        };
+        assert!(transfer(ad, ad).is_none(), "{}: not the owner", name);
+        assert!(transfer(o, o).is_none(), "{}: to self", name);
+        assert!(transfer(o, mu).is_none(), "{}: not a member", name);
+        transfer(o, ad).unwrap();
+        let role = |uid| s.get_member_role(uid, g).unwrap();
+        assert_eq!(role(ad), Some(GroupRole::Owner), "{}", name);
+        assert_eq!(role(o), Some(GroupRole::Admin), "{}", name);
+        assert!(transfer(o, m).is_none(), "{}: not the owner anymore", name);
+        leave(o).unwrap();
+        assert!(leave(ad).is_none(), "{}", name);
+        leave(m).unwrap();
+        assert!(leave(m).is_none(), "{}: left already", name);
+        leave(ad).unwrap();
+        assert!(s.get_group_members(g).unwrap().is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn history_is_capped_and_members_only() {
+    for (name, s) in stores() {
+        let (a, b, c) = (register(&s, "a"), register(&s, "b"), register(&s, "c"));
+        let g = group(&s, a, &[b]);
+        for i in 0..=HISTORY_PAGE_MAX {
+            s.new_message_u(a, b, ClientMessage::from(i.to_string()))
+                .unwrap();
+            post(&s, b, g, &i.to_string());
+        }
+        let q = HistoryQuery::All;
+        let dms = Core::user_history(a, b, &q, None, u16::MAX, &s).unwrap();
+        assert_eq!(dms.len(), HISTORY_PAGE_MAX as usize, "{}", name);
+        // the one left over is the oldest
+        let rest = Core::user_history(a, b, &q, Some(dms[dms.len() - 1].umid()), u16::MAX, &s);
+        assert_eq!(rest.unwrap().len(), 1, "{}", name);
+        assert_eq!(
+            Core::user_history(a, b, &q, None, 5, &s).unwrap().len(),
+            5,
+            "{}",
+            name
+        );
+
+        let page = Core::group_history(b, g, &q, None, u16::MAX, &s).unwrap().unwrap();
+        assert_eq!(page.len(), HISTORY_PAGE_MAX as usize, "{}", name);
+        assert!(
+            matches!(Core::group_history(c, g, &q, None, 5, &s), Some(None)),
+            "{}: outsider",
+            name
+        );
+        let history = WsServerboundPayload::GetGroupHistory {
+            group: g,
+            query: HistoryQuery::All,
+            before: None,
+            amt: 5,
+        };
+        assert!(tx(&s, c, history).is_none(), "{}: outsider", name);
+    }
+}
+
+/// Names of the payloads pushed, in order.
+fn kinds(pushes: &[CoreToWs]) -> Vec<String> {
+    pushes
+        .iter()
+        .map(|p| match payload(p) {
+            serde_json::Value::Object(o) => o.keys().next().unwrap().clone(),
+            serde_json::Value::String(s) => s,
+            v => panic!("unexpected payload {}", v),
+        })
+        .collect()
+}
+
+#[test]
+fn friend_requests() {
+    for (name, s) in stores() {
+        let (a, b, c) = (register(&s, "a"), register(&s, "b"), register(&s, "c"));
+        let request = |from, to| tx(&s, from, WsServerboundPayload::RequestFriend { to });
+
+        assert!(request(a, a).is_none(), "{}: self", name);
+        assert_eq!(
+            kinds(&request(a, b).unwrap()),
+            ["FriendRequest"],
+            "{}",
+            name
+        );
+        assert!(request(a, b).is_none(), "{}: already pending", name);
+        // asking back accepts, and each learns whether the other is around
+        let pushes = request(b, a).unwrap();
+        assert_eq!(
+            kinds(&pushes),
+            ["FriendRequest", "PresenceChanged", "PresenceChanged"],
+            "{}",
+            name
+        );
+        assert!(s.are_friends(a, Some(b)).unwrap(), "{}", name);
+        assert!(request(a, b).is_none(), "{}: already friends", name);
+
+        // a decline is only told to the one declining, and can't be repeated
+        request(c, a).unwrap();
+        let decline = |uid, from| tx(&s, uid, WsServerboundPayload::DeclineFriend { from });
+        let pushes = decline(a, c).unwrap();
+        assert_eq!(pushes.len(), 1, "{}", name);
+        assert!(decline(a, c).is_none(), "{}", name);
+        assert!(
+            tx(&s, a, WsServerboundPayload::AcceptFriend { from: c }).is_none(),
+            "{}",
+            name
+        );
+
+    }
+}
+
+#[test]
+fn blocks() {
+    for (name, s) in stores() {
+        let (a, b) = (register(&s, "a"), register(&s, "b"));
+        let request = |from, to| tx(&s, from, WsServerboundPayload::RequestFriend { to });
+        request(a, b).unwrap();
+        request(b, a).unwrap();
+
+        // blocking a friend tells both sides the friendship is over
+        let block = |uid, user| tx(&s, uid, WsServerboundPayload::Block { user });
+        let pushes = block(a, b).unwrap();
+        assert_eq!(
+            kinds(&pushes),
+            ["FriendRemoved", "FriendRemoved", "Blocked"],
+            "{}",
+            name
+        );
+        assert!(block(a, a).is_none(), "{}: self", name);
+        assert!(request(b, a).is_none(), "{}: blocked", name);
+        assert!(request(a, b).is_none(), "{}: blocker", name);
+        let dm = |from, to| {
+            let content = ClientMessage::from("hi".to_owned());
+            tx(
+                &s,
+                from,
+                WsServerboundPayload::NewUserMessage { to, content },
+            )
+        };
+        assert!(dm(b, a).is_none(), "{}", name);
+        assert!(dm(a, b).is_some(), "{}", name);
+
+        let pushes = tx(&s, a, WsServerboundPayload::Unblock { user: b }).unwrap();
+        assert_eq!(
+            payload(&pushes[0])["Blocked"],
+            serde_json::json!([]),
+            "{}",
+            name
+        );
+        assert!(
+            tx(&s, a, WsServerboundPayload::Unblock { user: b }).is_none(),
+            "{}",
+            name
+        );
+        // unblocking doesn't bring the friendship back
+        assert!(!s.are_friends(a, Some(b)).unwrap(), "{}", name);
+        request(b, a).unwrap();
+    }
+}
+
+/// Tell core a device of `uid` came or went, returning the statuses pushed to others for `uid`.
+async fn device(s: &Storage, presence: &Presence, uid: UserId, cid: u64, up: bool) -> Vec<String> {
+    let cid = ConnectionId(cid);
+    let m = match up {
+        true => WsToCore::Connected { uid, cid },
+        false => WsToCore::Disconnected { uid, cid },
+    };
+    let (s_t_ws, mut r_t_ws) = mpsc::channel(8);
+    Core::handle_ws(m, s.clone(), s_t_ws, presence.clone()).await;
+    let mut shown = Vec::new();
+    while let Some(p) = r_t_ws.recv().await {
+        if let CoreToWs::SendMultiple { dest, .. } = &p {
+            assert!(!dest.contains(&uid), "pushed to self");
+        }
+        let pl = payload(&p);
+        if pl["PresenceChanged"]["uid"] == serde_json::json!(uid) {
+            shown.push(pl["PresenceChanged"]["status"].as_str().unwrap().to_owned());
+        }
+    }
+    shown
+}
+
+#[tokio::test]
+async fn presence_follows_the_last_device() {
+    for (name, s) in stores() {
+        let (a, b) = (register(&s, "a"), register(&s, "b"));
+        tx(&s, a, WsServerboundPayload::RequestFriend { to: b }).unwrap();
+        tx(&s, b, WsServerboundPayload::RequestFriend { to: a }).unwrap();
+        let presence = Presence::default();
+        // how a is shown to anyone asking now
+        let status = || {
+            let pl = serde_json::to_value(Core::presence_of(a, &s, &presence).unwrap()).unwrap();
+            pl["PresenceChanged"]["status"].clone()
+        };
+
+        assert_eq!(device(&s, &presence, a, 1, true).await, ["Online"], "{}", name);
+        assert_eq!(device(&s, &presence, a, 2, true).await, [] as [&str; 0], "{}", name);
+        // one device of two going away changes nothing
+        assert_eq!(device(&s, &presence, a, 1, false).await, [] as [&str; 0], "{}", name);
+        assert_eq!(status(), "Online", "{}", name);
+        assert_eq!(device(&s, &presence, a, 2, false).await, ["Offline"], "{}", name);
+        assert_eq!(status(), "Offline", "{}", name);
+
+        // invisible users are shown offline even while connected
+        s.set_status(a, UserStatus::Invisible, None).unwrap();
+        assert_eq!(device(&s, &presence, a, 3, true).await, ["Offline"], "{}", name);
+        assert_eq!(status(), "Offline", "{}", name);
+    }
+}
+
+#[test]
+fn profile_update_is_checked() {
+    let long = |max: usize| Some("é".repeat(max + 1));
+    let too_long = [
+        (
+            "alias",
+            ProfileUpdate {
+                alias: Some(long(ALIAS_MAX)),
+                ..ProfileUpdate::default()
+            },
+        ),
+        (
+            "motd",
+            ProfileUpdate {
+                motd: Some(long(MOTD_MAX)),
+                ..ProfileUpdate::default()
+            },
+        ),
+        (
+            "pubkey",
+            ProfileUpdate {
+                pubkey: long(PUBKEY_MAX),
+                ..ProfileUpdate::default()
+            },
+        ),
+    ];
+    for (field, update) in &too_long {
+        let err = update.check().unwrap_err();
+        assert!(
+            matches!(err, ProfileError::TooLong { field: f, .. } if f == *field),
+            "{:?}",
+            err
+        );
+    }
+    let empty_pubkey = ProfileUpdate {
+        pubkey: Some(String::new()),
+        ..ProfileUpdate::default()
+    };
+    assert!(matches!(
+        empty_pubkey.check(),
+        Err(ProfileError::EmptyPubkey)
+    ));
+    // limits count characters, not bytes
+    let at_limit = ProfileUpdate {
+        alias: Some(Some("é".repeat(ALIAS_MAX))),
+        motd: Some(Some("é".repeat(MOTD_MAX))),
+        pubkey: Some("é".repeat(PUBKEY_MAX)),
+        ..ProfileUpdate::default()
+    };
+    at_limit.check().unwrap();
+
+    for (name, s) in stores() {
+        let a = register(&s, "a");
+        for (field, update) in &too_long {
+            assert!(
+                tx(&s, a, WsServerboundPayload::UpdateProfile(update.clone())).is_none(),
+                "{}: {}",
+                name,
+                field
+            );
+        }
+        let pur = s.get_user_data(a, Some(a)).unwrap().unwrap();
+        assert_eq!((pur.alias, pur.motd), (None, None), "{}", name);
+        assert_eq!(
+            serde_json::to_value(&pur.pubkey).unwrap(),
+            "key",
+            "{}",
+            name
+        );
+        let pushes = tx(&s, a, WsServerboundPayload::UpdateProfile(at_limit.clone())).unwrap();
+        assert_eq!(pushes.len(), 1, "{}", name);
+        assert_eq!(
+            s.get_user_data(a, None).unwrap().unwrap().alias,
+            Some("é".repeat(ALIAS_MAX)),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn visibility_changes_what_others_see() {
+    for (name, s) in stores() {
+        let (a, friend, stranger) = (
+            register(&s, "a"),
+            register(&s, "friend"),
+            register(&s, "stranger"),
+        );
+        s.add_friend(a, friend).unwrap();
+        let set = |visibility| {
+            let update = ProfileUpdate {
+                visibility: Some(visibility),
+                ..ProfileUpdate::default()
+            };
+            tx(&s, a, WsServerboundPayload::UpdateProfile(update)).unwrap();
+        };
+        // whether `asker` sees a's friends, and finds a by email
+        let sees = |asker: Option<UserId>| {
+            let pur = s.get_user_data(a, asker).unwrap().unwrap();
+            assert!(pur.hashed_pass.is_none(), "{}", name);
+            let found = s
+                .search_users(None, Some("a@test"), asker, None, 10)
+                .unwrap();
+            (pur.friends.is_some(), !found.is_empty())
+        };
+        let askers = [None, Some(stranger), Some(friend), Some(a)];
+
+        set(UserVisibility::Public);
+        let seen: Vec<_> = askers.iter().map(|asker| sees(*asker)).collect();
+        assert_eq!(
+            seen,
+            [(true, true), (true, true), (true, true), (true, true)],
+            "{}: public",
+            name
+        );
+
+        set(UserVisibility::FriendsOnly);
+        let seen: Vec<_> = askers.iter().map(|asker| sees(*asker)).collect();
+        assert_eq!(
+            seen,
+            [(false, false), (false, false), (true, true), (true, false)],
+            "{}: friends",
+            name
+        );
+
+        set(UserVisibility::Private);
+        let seen: Vec<_> = askers.iter().map(|asker| sees(*asker)).collect();
+        assert_eq!(
+            seen,
+            [(false, false), (false, false), (true, false), (true, false)],
+            "{}: private",
+            name
+        );
+
+        // only the user sees which it is
+        let own = s.get_user_data(a, Some(a)).unwrap().unwrap().visibility;
+        assert!(matches!(own, Some(UserVisibility::Private)), "{}", name);
+        assert!(
+            s.get_user_data(a, Some(friend))
+                .unwrap()
+                .unwrap()
+                .visibility
+                .is_none(),
+            "{}",
+            name
+        );
+    }
+}
+
+/// Answer a core request the way web asks for it.
+fn ask(s: &Storage, req: CoreRequest) -> Option<CoreReply> {
+    Core::handle_corereq(req, s, &Presence::default(), &mut Vec::new())
+}
+
+#[test]
+fn missing_is_not_failed() {
+    for (name, s) in stores() {
+        let a = register(&s, "a");
+        let b = register(&s, "b");
+        let g = group(&s, a, &[]);
+        let nobody = UserId::from(u32::MAX);
+        let not_found = |reply: Option<CoreReply>| matches!(reply, Some(CoreReply::NotFound));
+
+        let lookup = |lookup| CoreRequest::GetUserData {
+            lookup,
+            asker: Some(a),
+        };
+        assert!(not_found(ask(&s, lookup(nobody))), "{}: no such user", name);
+        assert!(
+            matches!(ask(&s, lookup(b)), Some(CoreReply::GetUserData(_))),
+            "{}",
+            name
+        );
+        let group_data = CoreRequest::GetGroupData {
+            lookup: g,
+            asker: b,
+        };
+        assert!(not_found(ask(&s, group_data)), "{}: outsider", name);
+        let unfriend = CoreRequest::RemoveFriend { u: a, friend: b };
+        assert!(not_found(ask(&s, unfriend)), "{}: not friends", name);
+        let answer = CoreRequest::AnswerFriendRequest {
+            u: a,
+            from: b,
+            accept: true,
+        };
+        assert!(not_found(ask(&s, answer)), "{}: no request", name);
+        let unblock = CoreRequest::SetBlocked {
+            u: a,
+            other: b,
+            blocked: false,
+        };
+        assert!(not_found(ask(&s, unblock)), "{}: not blocked", name);
+    }
+}
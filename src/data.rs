@@ -13,6 +13,7 @@ pub type SqlUserRecord = (
     Option<String>, // motd
     String,         // status
     String,         // visibility
+    Option<String>, // status_text
 );
 
 /// Tuple type for `UserMessage`.
@@ -21,8 +22,41 @@ pub type SqlUserMessage = (
     u32,           // sender_id
     u32,           // receiver_id
     String,        // msg_content
+    NaiveDateTime,         // time_posted stored as UTC
+    bool,                  // r
+    Option<NaiveDateTime>, // delivered
+    Option<NaiveDateTime>, // read_at
+);
+
+/// Tuple type for `GroupMessage`.
+pub type SqlGroupMessage = (
+    u64,           // gmid
+    u32,           // sender_id
+    u32,           // gid
+    String,        // msg_content
     NaiveDateTime, // time_posted stored as UTC
-    bool,          // r
+);
+
+/// Tuple type for `FriendRequest`.
+pub type SqlFriendRequest = (
+    u32,           // sender_id
+    u32,           // receiver_id
+    u8,            // state
+    NaiveDateTime, // at stored as UTC
+);
+
+/// Tuple type for `SessionRecord`.
+pub type SqlSession = (
+    u64,            // sid
+    u32,            // uid
+    String,         // token_hash
+    String,         // refresh_hash
+    NaiveDateTime,  // created
+    NaiveDateTime,  // last_seen
+    NaiveDateTime,  // expires
+    NaiveDateTime,  // refresh_expires
+    Option<String>, // device
+    Option<String>, // ip
 );
 
 /// **Internal use:** User record. Intentionally made not serializable, so it doesn't accidentally get sent to the client.
@@ -38,6 +72,7 @@ pub struct UserRecord {
     pub motd: Option<String>,
     pub status: UserStatus,
     pub visibility: UserVisibility,
+    pub status_text: Option<String>,
 }
 
 pub trait FromSqlTup<T>
@@ -60,6 +95,7 @@ impl FromSqlTup<SqlUserRecord> for UserRecord {
         let motd = tup.7;
         let status = serde_json::from_str(&tup.8).ok()?;
         let visibility = serde_json::from_str(&tup.9).ok()?;
+        let status_text = tup.10;
         Some(Self {
             uid,
             email,
@@ -71,6 +107,45 @@ impl FromSqlTup<SqlUserRecord> for UserRecord {
             motd,
             status,
             visibility,
+            status_text,
+        })
+    }
+}
+
+impl FromSqlTup<SqlFriendRequest> for FriendRequest {
+    fn from_sql_tup(tup: SqlFriendRequest) -> Option<Self> {
+        Some(Self {
+            from: UserId::from(tup.0),
+            to: UserId::from(tup.1),
+            state: FriendRequestState::try_from(tup.2).ok()?,
+            at: DateTime::<Utc>::from_utc(tup.3, Utc),
+        })
+    }
+}
+
+/// **Internal use:** Session with the hashes of its tokens, as stored.
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub session: Session,
+    pub token_hash: String,
+    pub refresh_hash: String,
+}
+
+impl FromSqlTup<SqlSession> for SessionRecord {
+    fn from_sql_tup(tup: SqlSession) -> Option<Self> {
+        Some(Self {
+            session: Session {
+                sid: SessionId::from(tup.0),
+                uid: UserId::from(tup.1),
+                created: DateTime::<Utc>::from_utc(tup.4, Utc),
+                last_seen: DateTime::<Utc>::from_utc(tup.5, Utc),
+                expires: DateTime::<Utc>::from_utc(tup.6, Utc),
+                refresh_expires: DateTime::<Utc>::from_utc(tup.7, Utc),
+                device: tup.8,
+                ip: tup.9,
+            },
+            token_hash: tup.2,
+            refresh_hash: tup.3,
         })
     }
 }
@@ -85,10 +160,8 @@ impl UserRecord {
                 _ => Some(self.email),
             },
             pubkey: self.pubkey,
-            hashed_pass: match mask {
-                UserMaskLevel::SelfUse => Some(self.hashed_pass),
-                _ => None,
-            },
+            // not even to the user themselves: it would allow cracking the password offline
+            hashed_pass: None,
             alias: self.alias,
             friends: match mask {
                 UserMaskLevel::HidePassEmailMembership => None,
@@ -99,15 +172,19 @@ impl UserRecord {
                 _ => Some(self.groups),
             },
             motd: self.motd,
+            // only says whether the status allows being shown online; `Core` knows about connections
             online: match mask {
-                UserMaskLevel::SelfUse => match self.status {
-                    UserStatus::Offline => false,
-                    _ => true,
-                },
-                _ => match self.status {
-                    UserStatus::Online => true,
-                    _ => false,
-                },
+                UserMaskLevel::SelfUse => true,
+                _ => self.status == UserStatus::Online,
+            },
+            status_text: match mask {
+                UserMaskLevel::SelfUse => self.status_text,
+                _ if self.status == UserStatus::Online => self.status_text,
+                _ => None,
+            },
+            visibility: match mask {
+                UserMaskLevel::SelfUse => Some(self.visibility),
+                _ => None,
             },
         }
     }
@@ -167,6 +244,23 @@ impl WsServerboundTx {
             _ => None,
         }
     }
+    pub fn sender(&self) -> UserId {
+        self.sender
+    }
+    /// Whether this is a signal that is only relayed, never stored.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(
+            self.inner,
+            WsServerboundPayload::Typing { .. } | WsServerboundPayload::StoppedTyping { .. }
+        )
+    }
+    /// The message acknowledged, if this is a delivery confirmation.
+    pub fn delivered(&self) -> Option<UserMessageId> {
+        match &self.inner {
+            WsServerboundPayload::Delivered { umid } => Some(umid.clone()),
+            _ => None,
+        }
+    }
     pub fn extract(self) -> (UserId, WsServerboundPayload) {
         (self.sender, self.inner)
     }
@@ -1,15 +1,62 @@
-pub const Q_CREATE_YAP: &'static str = "CREATE DATABASE IF NOT EXISTS yap;";
+/**
+SQL text fixed at compile time. Only `sql!` makes one, from a string literal, so a query can never
+be assembled from input; values always go in as bound parameters.
+Storage only reaches the drivers through `SqliteQuery` and `MysqlQuery`, which take nothing else.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sql(&'static str);
+
+impl Sql {
+    /// Use `sql!` instead.
+    #[doc(hidden)]
+    pub const fn from_literal(q: &'static str) -> Sql {
+        Sql(q)
+    }
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+/// Make a `Sql` from a string literal. Anything built at runtime, like `format!`, won't compile.
+#[macro_export]
+macro_rules! sql {
+    ($q:literal) => {
+        $crate::db::Sql::from_literal($q)
+    };
+}
+
+pub const Q_CREATE_YAP: Sql = sql!("CREATE DATABASE IF NOT EXISTS yap;");
+
+/// Database selected by every pooled mysql connection. Must match `Q_CREATE_YAP`.
+pub const YAP_DB_NAME: &str = "yap";
+
+/// A versioned schema change. Statements in `up` are run in order.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub up: &'static [Sql],
+}
 
-pub const Q_USE_YAP: &'static str = "USE yap;";
+pub const Q_CREATE_SCHEMA_VERSION: Sql = sql!("
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INT UNSIGNED NOT NULL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    applied DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);");
+
+pub const Q_GET_SCHEMA_VERSION: Sql = sql!("SELECT MAX(version) FROM schema_version;");
+
+pub const Q_SET_SCHEMA_VERSION: Sql =
+    sql!("INSERT INTO schema_version (version, name) VALUES (:version, :name);");
 
 /* pubkey SHOULD BE rsa-2048 hex(512) but not enforced
-hashed_pass SHOULD BE sha256 hex(64) but not enforced
+hashed_pass is an argon2 PHC string, see `Q_ALTER_USERS_HASHED_PASS_WIDTH`
 friends json
 groups json
 status json
 visibility json
 */
-pub const Q_CREATE_TABLE_USERS: &'static str = "
+pub const Q_CREATE_TABLE_USERS: Sql = sql!("
 CREATE TABLE IF NOT EXISTS u (
     uid INT UNSIGNED AUTO_INCREMENT UNIQUE NOT NULL PRIMARY KEY,
     email VARCHAR(50) UNIQUE NOT NULL,
@@ -22,10 +69,10 @@ CREATE TABLE IF NOT EXISTS u (
     status BLOB NOT NULL,
     visibility BLOB NOT NULL,
     INDEX emails (email)
-);";
+);");
 
 // msg_content json
-pub const Q_CREATE_TABLE_USER_MESSAGES: &'static str = "
+pub const Q_CREATE_TABLE_USER_MESSAGES: Sql = sql!("
 CREATE TABLE IF NOT EXISTS u_message (
     umid BIGINT UNSIGNED AUTO_INCREMENT UNIQUE NOT NULL PRIMARY KEY,
     sender_id INT UNSIGNED NOT NULL,
@@ -35,23 +82,23 @@ CREATE TABLE IF NOT EXISTS u_message (
     r BOOLEAN NOT NULL,
     FOREIGN KEY (sender_id) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
     FOREIGN KEY (receiver_id) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
-);";
+);");
 
-pub const Q_CREATE_TABLE_GROUPS: &'static str = "
+pub const Q_CREATE_TABLE_GROUPS: Sql = sql!("
 CREATE TABLE IF NOT EXISTS g (
     gid INT UNSIGNED AUTO_INCREMENT UNIQUE NOT NULL PRIMARY KEY,
     motd VARCHAR(500)
-);";
+);");
 
-pub const Q_CREATE_TABLE_GROUP_USERS: &'static str = "
+pub const Q_CREATE_TABLE_GROUP_USERS: Sql = sql!("
 CREATE TABLE IF NOT EXISTS g_member (
     uid INT UNSIGNED NOT NULL,
     gid INT UNSIGNED NOT NULL,
     FOREIGN KEY (uid) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
     FOREIGN KEY (gid) REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE
-);";
+);");
 
-pub const Q_CREATE_TABLE_GROUP_MESSAGES: &'static str = "
+pub const Q_CREATE_TABLE_GROUP_MESSAGES: Sql = sql!("
 CREATE TABLE IF NOT EXISTS g_message (
     gmid BIGINT UNSIGNED AUTO_INCREMENT UNIQUE NOT NULL PRIMARY KEY,
     sender_id INT UNSIGNED NOT NULL,
@@ -60,20 +107,512 @@ CREATE TABLE IF NOT EXISTS g_message (
     FOREIGN KEY (gid) REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
     msg_content BLOB NOT NULL,
     time_posted DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
-);";
+);");
 
-pub const Q_CREATE_TABLE_USER_READ_GROUP: &'static str = "
+pub const Q_CREATE_TABLE_USER_READ_GROUP: Sql = sql!("
 CREATE TABLE IF NOT EXISTS g_message_read (
     gmid BIGINT UNSIGNED UNIQUE NOT NULL,
     reader_id INT UNSIGNED NOT NULL,
     FOREIGN KEY (gmid) REFERENCES g_message(gmid) ON DELETE CASCADE ON UPDATE CASCADE,
     FOREIGN KEY (reader_id) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
-);";
+);");
 
-pub const Q_CREATE_FRIENDS: &'static str = "
+pub const Q_CREATE_FRIENDS: Sql = sql!("
 CREATE TABLE IF NOT EXISTS u_friend (
     l INT UNSIGNED NOT NULL,
     r INT UNSIGNED NOT NULL,
     FOREIGN KEY (l) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
     FOREIGN KEY (r) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
-);";
\ No newline at end of file
+);");
+
+pub const Q_CREATE_TABLE_GROUP_INVITES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_invite (
+    gid INT UNSIGNED NOT NULL,
+    uid INT UNSIGNED NOT NULL,
+    inviter INT UNSIGNED NOT NULL,
+    UNIQUE KEY gid_uid (gid, uid),
+    FOREIGN KEY (gid) REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (uid) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (inviter) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_ALTER_GROUP_USERS_UNIQUE: Sql =
+    sql!("ALTER TABLE g_member ADD UNIQUE KEY uid_gid (uid, gid);");
+
+// gmid alone was unique, which only let one member ever read a message
+pub const Q_ALTER_USER_READ_GROUP_UNIQUE: Sql =
+    sql!("ALTER TABLE g_message_read ADD UNIQUE KEY gmid_reader (gmid, reader_id), DROP INDEX gmid;");
+
+pub const Q_ALTER_USER_MESSAGES_TIME_DEFAULT: Sql =
+    sql!("ALTER TABLE u_message MODIFY time_posted DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP;");
+
+pub const Q_ALTER_GROUPS_NAME: Sql = sql!("ALTER TABLE g ADD COLUMN name VARCHAR(100);");
+
+// role is a `GroupRole` as u8, 2 being `Member`
+pub const Q_ALTER_GROUP_USERS_ROLE: Sql =
+    sql!("ALTER TABLE g_member ADD COLUMN role TINYINT UNSIGNED NOT NULL DEFAULT 2;");
+
+// groups made before roles existed get their earliest registered member as owner
+pub const Q_PROMOTE_GROUP_OWNERS: Sql = sql!("
+UPDATE g_member m JOIN (SELECT gid, MIN(uid) AS uid FROM g_member GROUP BY gid) o
+ON m.gid = o.gid AND m.uid = o.uid
+SET m.role = 0;");
+
+pub const Q_CREATE_TABLE_GROUP_BANS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_ban (
+    gid INT UNSIGNED NOT NULL,
+    uid INT UNSIGNED NOT NULL,
+    banned_by INT UNSIGNED NOT NULL,
+    UNIQUE KEY gid_uid (gid, uid),
+    FOREIGN KEY (gid) REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (uid) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (banned_by) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_ALTER_USER_MESSAGES_RECEIPTS: Sql =
+    sql!("ALTER TABLE u_message ADD COLUMN delivered DATETIME NULL, ADD COLUMN read_at DATETIME NULL;");
+
+pub const Q_ALTER_USERS_STATUS_TEXT: Sql =
+    sql!("ALTER TABLE u ADD COLUMN status_text VARCHAR(100);");
+
+// status was never written before presence existed, so nobody actually chose to be offline
+pub const Q_RESET_USERS_STATUS: Sql =
+    sql!("UPDATE u SET status = '\"Online\"' WHERE status = '\"Offline\"';");
+
+pub const Q_CREATE_TABLE_FRIEND_REQUESTS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_friend_request (
+    sender_id INT UNSIGNED NOT NULL,
+    receiver_id INT UNSIGNED NOT NULL,
+    state TINYINT UNSIGNED NOT NULL DEFAULT 0,
+    at DATETIME NOT NULL,
+    UNIQUE KEY sender_receiver (sender_id, receiver_id),
+    FOREIGN KEY (sender_id) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (receiver_id) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_CREATE_TABLE_BLOCKS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_block (
+    uid INT UNSIGNED NOT NULL,
+    blocked INT UNSIGNED NOT NULL,
+    UNIQUE KEY uid_blocked (uid, blocked),
+    FOREIGN KEY (uid) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    FOREIGN KEY (blocked) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+// tokens are only stored as sha-256 hex digests
+pub const Q_CREATE_TABLE_SESSIONS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_session (
+    sid BIGINT UNSIGNED PRIMARY KEY,
+    uid INT UNSIGNED NOT NULL,
+    token_hash CHAR(64) NOT NULL,
+    refresh_hash CHAR(64) NOT NULL,
+    created DATETIME NOT NULL,
+    last_seen DATETIME NOT NULL,
+    expires DATETIME NOT NULL,
+    refresh_expires DATETIME NOT NULL,
+    device VARCHAR(255),
+    ip VARCHAR(64),
+    UNIQUE KEY token_hash (token_hash),
+    UNIQUE KEY refresh_hash (refresh_hash),
+    KEY uid (uid),
+    FOREIGN KEY (uid) REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+// sid isn't a foreign key, so receipts outlive the session that confirmed them
+pub const Q_CREATE_TABLE_USER_MESSAGE_DELIVERIES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_message_delivery (
+    umid BIGINT UNSIGNED NOT NULL,
+    sid BIGINT UNSIGNED NOT NULL,
+    at DATETIME NOT NULL,
+    UNIQUE KEY umid_sid (umid, sid),
+    FOREIGN KEY (umid) REFERENCES u_message(umid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+/* Queries below are valid in both mysql and sqlite.
+Optional filters are bound as NULL when unused.
+*/
+pub const Q_GET_USER_HISTORY: Sql = sql!("
+SELECT * FROM u_message WHERE
+((sender_id = :s AND receiver_id = :r) OR (sender_id = :r AND receiver_id = :s)) AND
+(:before IS NULL OR umid < :before) AND
+(:from IS NULL OR time_posted >= :from) AND
+(:to IS NULL OR time_posted < :to) AND
+(NOT :unseen OR (receiver_id = :s AND r = 0))
+ORDER BY umid DESC LIMIT :amt;");
+
+pub const Q_GET_GROUP_HISTORY: Sql = sql!("
+SELECT * FROM g_message m WHERE
+m.gid = :gid AND
+(:before IS NULL OR m.gmid < :before) AND
+(:from IS NULL OR m.time_posted >= :from) AND
+(:to IS NULL OR m.time_posted < :to) AND
+(NOT :unseen OR (m.sender_id <> :uid AND
+    NOT EXISTS (SELECT 1 FROM g_message_read r WHERE r.gmid = m.gmid AND r.reader_id = :uid)))
+ORDER BY m.gmid DESC LIMIT :amt;");
+
+/* Matches on alias ignore ASCII case in sqlite and in mysql's default collations.
+Visibilities are bound json encoded, as stored.
+*/
+/**
+Rows as `SqlUserRecord`, so hits can be masked without looking each one up again.
+Memberships come from `g_member` and `u_friend` in place of the legacy columns, as JSON arrays.
+*/
+pub const Q_SEARCH_USERS: Sql = sql!("
+SELECT uid, email, pubkey, hashed_pass, alias,
+CONCAT('[', COALESCE((SELECT GROUP_CONCAT(f.r) FROM u_friend f WHERE f.l = u.uid), ''), ']'),
+CONCAT('[', COALESCE((SELECT GROUP_CONCAT(m.gid) FROM g_member m WHERE m.uid = u.uid), ''), ']'),
+motd, status, visibility, status_text
+FROM u WHERE
+(:alias IS NULL OR alias LIKE :alias ESCAPE '!') AND
+(:email IS NULL OR email = :email) AND
+(:after IS NULL OR uid > :after) AND
+(visibility = :public OR
+    (visibility = :friends_only AND uid IN (SELECT r FROM u_friend WHERE l = :requester))) AND
+NOT EXISTS (SELECT 1 FROM u_block b WHERE b.uid = u.uid AND b.blocked = :requester)
+ORDER BY uid LIMIT :amt;");
+
+pub const Q_CREATE_INDEX_USER_ALIAS: Sql = sql!("CREATE INDEX aliases ON u (alias);");
+
+// argon2 PHC strings are around 97 characters, which strict mode rejects and others truncate
+pub const Q_ALTER_USERS_HASHED_PASS_WIDTH: Sql =
+    sql!("ALTER TABLE u MODIFY hashed_pass VARCHAR(255) NOT NULL;");
+
+/**
+Ordered mysql migrations. **Append only**; never edit a migration that has shipped.
+
+Version 1 is the schema from before migrations existed. Its statements use `IF NOT EXISTS`,
+so databases created by older builds are adopted without changes.
+*/
+pub const MYSQL_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        up: &[
+            Q_CREATE_TABLE_USERS,
+            Q_CREATE_TABLE_GROUPS,
+            Q_CREATE_TABLE_USER_MESSAGES,
+            Q_CREATE_TABLE_GROUP_MESSAGES,
+            Q_CREATE_TABLE_USER_READ_GROUP,
+            Q_CREATE_FRIENDS,
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "group members, message time default",
+        up: &[
+            Q_CREATE_TABLE_GROUP_USERS,
+            Q_ALTER_USER_MESSAGES_TIME_DEFAULT,
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "group invites, unique membership and read flags",
+        up: &[
+            Q_CREATE_TABLE_GROUP_INVITES,
+            Q_ALTER_GROUP_USERS_UNIQUE,
+            Q_ALTER_USER_READ_GROUP_UNIQUE,
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "group names, roles and bans",
+        up: &[
+            Q_ALTER_GROUPS_NAME,
+            Q_ALTER_GROUP_USERS_ROLE,
+            Q_PROMOTE_GROUP_OWNERS,
+            Q_CREATE_TABLE_GROUP_BANS,
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "message receipts",
+        up: &[Q_ALTER_USER_MESSAGES_RECEIPTS],
+    },
+    Migration {
+        version: 6,
+        name: "status text",
+        up: &[Q_ALTER_USERS_STATUS_TEXT, Q_RESET_USERS_STATUS],
+    },
+    Migration {
+        version: 7,
+        name: "friend requests",
+        up: &[Q_CREATE_TABLE_FRIEND_REQUESTS],
+    },
+    Migration {
+        version: 8,
+        name: "blocks",
+        up: &[Q_CREATE_TABLE_BLOCKS],
+    },
+    Migration {
+        version: 9,
+        name: "password hash width",
+        up: &[Q_ALTER_USERS_HASHED_PASS_WIDTH],
+    },
+    Migration {
+        version: 10,
+        name: "sessions",
+        up: &[Q_CREATE_TABLE_SESSIONS],
+    },
+    Migration {
+        version: 11,
+        name: "alias index",
+        up: &[Q_CREATE_INDEX_USER_ALIAS],
+    },
+    Migration {
+        version: 12,
+        name: "per device delivery",
+        up: &[Q_CREATE_TABLE_USER_MESSAGE_DELIVERIES],
+    },
+];
+
+/* SQLite dialect of the schema above.
+Column types follow SQLite's type affinity, so sizes are not enforced.
+*/
+pub const Q_SQLITE_PRAGMAS: Sql = sql!("PRAGMA foreign_keys = ON;");
+
+pub const Q_SQLITE_CREATE_SCHEMA_VERSION: Sql = sql!("
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    applied DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_USERS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u (
+    uid INTEGER PRIMARY KEY AUTOINCREMENT,
+    email VARCHAR(50) UNIQUE NOT NULL,
+    pubkey VARCHAR(512) NOT NULL,
+    hashed_pass VARCHAR(64) NOT NULL,
+    alias VARCHAR(40),
+    friends BLOB NOT NULL,
+    groups BLOB NOT NULL,
+    motd VARCHAR(500),
+    status BLOB NOT NULL,
+    visibility BLOB NOT NULL
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_USER_MESSAGES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_message (
+    umid INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    receiver_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    msg_content BLOB NOT NULL,
+    time_posted DATETIME NOT NULL,
+    r BOOLEAN NOT NULL
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_GROUPS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g (
+    gid INTEGER PRIMARY KEY AUTOINCREMENT,
+    motd VARCHAR(500)
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_GROUP_USERS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_member (
+    uid INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    gid INTEGER NOT NULL REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_GROUP_MESSAGES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_message (
+    gmid INTEGER PRIMARY KEY AUTOINCREMENT,
+    sender_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    gid INTEGER NOT NULL REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
+    msg_content BLOB NOT NULL,
+    time_posted DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_USER_READ_GROUP: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_message_read (
+    gmid INTEGER UNIQUE NOT NULL REFERENCES g_message(gmid) ON DELETE CASCADE ON UPDATE CASCADE,
+    reader_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_SQLITE_CREATE_FRIENDS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_friend (
+    l INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    r INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_GROUP_INVITES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_invite (
+    gid INTEGER NOT NULL REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
+    uid INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    inviter INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    UNIQUE (gid, uid)
+);");
+
+pub const Q_SQLITE_GROUP_USERS_UNIQUE: Sql =
+    sql!("CREATE UNIQUE INDEX IF NOT EXISTS g_member_uid_gid ON g_member (uid, gid);");
+
+// sqlite can't drop a constraint, so the table is rebuilt
+pub const Q_SQLITE_REBUILD_USER_READ_GROUP: Sql = sql!("
+CREATE TABLE g_message_read_new (
+    gmid INTEGER NOT NULL REFERENCES g_message(gmid) ON DELETE CASCADE ON UPDATE CASCADE,
+    reader_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    UNIQUE (gmid, reader_id)
+);
+INSERT INTO g_message_read_new (gmid, reader_id) SELECT gmid, reader_id FROM g_message_read;
+DROP TABLE g_message_read;
+ALTER TABLE g_message_read_new RENAME TO g_message_read;");
+
+pub const Q_SQLITE_ALTER_GROUPS_NAME: Sql = sql!("ALTER TABLE g ADD COLUMN name VARCHAR(100);");
+
+pub const Q_SQLITE_ALTER_GROUP_USERS_ROLE: Sql =
+    sql!("ALTER TABLE g_member ADD COLUMN role INTEGER NOT NULL DEFAULT 2;");
+
+pub const Q_SQLITE_PROMOTE_GROUP_OWNERS: Sql = sql!("
+UPDATE g_member SET role = 0
+WHERE uid = (SELECT MIN(o.uid) FROM g_member o WHERE o.gid = g_member.gid);");
+
+pub const Q_SQLITE_CREATE_TABLE_GROUP_BANS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS g_ban (
+    gid INTEGER NOT NULL REFERENCES g(gid) ON DELETE CASCADE ON UPDATE CASCADE,
+    uid INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    banned_by INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    UNIQUE (gid, uid)
+);");
+
+// sqlite only adds one column per statement
+pub const Q_SQLITE_ALTER_USER_MESSAGES_RECEIPTS: Sql = sql!("
+ALTER TABLE u_message ADD COLUMN delivered DATETIME;
+ALTER TABLE u_message ADD COLUMN read_at DATETIME;");
+
+pub const Q_SQLITE_CREATE_TABLE_FRIEND_REQUESTS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_friend_request (
+    sender_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    receiver_id INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    state INTEGER NOT NULL DEFAULT 0,
+    at DATETIME NOT NULL,
+    UNIQUE (sender_id, receiver_id)
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_BLOCKS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_block (
+    uid INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    blocked INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    UNIQUE (uid, blocked)
+);");
+
+pub const Q_SQLITE_CREATE_TABLE_SESSIONS: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_session (
+    sid INTEGER PRIMARY KEY,
+    uid INTEGER NOT NULL REFERENCES u(uid) ON DELETE CASCADE ON UPDATE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    refresh_hash TEXT NOT NULL UNIQUE,
+    created DATETIME NOT NULL,
+    last_seen DATETIME NOT NULL,
+    expires DATETIME NOT NULL,
+    refresh_expires DATETIME NOT NULL,
+    device TEXT,
+    ip TEXT
+);
+CREATE INDEX IF NOT EXISTS u_session_uid ON u_session (uid);");
+
+/// As `Q_SEARCH_USERS`. sqlite has no `CONCAT`.
+pub const Q_SQLITE_SEARCH_USERS: Sql = sql!("
+SELECT uid, email, pubkey, hashed_pass, alias,
+'[' || COALESCE((SELECT group_concat(f.r) FROM u_friend f WHERE f.l = u.uid), '') || ']',
+'[' || COALESCE((SELECT group_concat(m.gid) FROM g_member m WHERE m.uid = u.uid), '') || ']',
+motd, status, visibility, status_text
+FROM u WHERE
+(:alias IS NULL OR alias LIKE :alias ESCAPE '!') AND
+(:email IS NULL OR email = :email) AND
+(:after IS NULL OR uid > :after) AND
+(visibility = :public OR
+    (visibility = :friends_only AND uid IN (SELECT r FROM u_friend WHERE l = :requester))) AND
+NOT EXISTS (SELECT 1 FROM u_block b WHERE b.uid = u.uid AND b.blocked = :requester)
+ORDER BY uid LIMIT :amt;");
+
+pub const Q_SQLITE_CREATE_INDEX_USER_ALIAS: Sql =
+    sql!("CREATE INDEX IF NOT EXISTS u_alias ON u (alias);");
+
+pub const Q_SQLITE_CREATE_TABLE_USER_MESSAGE_DELIVERIES: Sql = sql!("
+CREATE TABLE IF NOT EXISTS u_message_delivery (
+    umid INTEGER NOT NULL REFERENCES u_message(umid) ON DELETE CASCADE ON UPDATE CASCADE,
+    sid INTEGER NOT NULL,
+    at DATETIME NOT NULL,
+    UNIQUE (umid, sid)
+);");
+
+/// Ordered sqlite migrations. Versions line up with `MYSQL_MIGRATIONS`. **Append only.**
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        up: &[
+            Q_SQLITE_CREATE_TABLE_USERS,
+            Q_SQLITE_CREATE_TABLE_GROUPS,
+            Q_SQLITE_CREATE_TABLE_USER_MESSAGES,
+            Q_SQLITE_CREATE_TABLE_GROUP_MESSAGES,
+            Q_SQLITE_CREATE_TABLE_USER_READ_GROUP,
+            Q_SQLITE_CREATE_FRIENDS,
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "group members, message time default",
+        // sqlite can't change a column default in place; messages always carry an explicit time.
+        up: &[Q_SQLITE_CREATE_TABLE_GROUP_USERS],
+    },
+    Migration {
+        version: 3,
+        name: "group invites, unique membership and read flags",
+        up: &[
+            Q_SQLITE_CREATE_TABLE_GROUP_INVITES,
+            Q_SQLITE_GROUP_USERS_UNIQUE,
+            Q_SQLITE_REBUILD_USER_READ_GROUP,
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "group names, roles and bans",
+        up: &[
+            Q_SQLITE_ALTER_GROUPS_NAME,
+            Q_SQLITE_ALTER_GROUP_USERS_ROLE,
+            Q_SQLITE_PROMOTE_GROUP_OWNERS,
+            Q_SQLITE_CREATE_TABLE_GROUP_BANS,
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "message receipts",
+        up: &[Q_SQLITE_ALTER_USER_MESSAGES_RECEIPTS],
+    },
+    Migration {
+        version: 6,
+        name: "status text",
+        up: &[Q_ALTER_USERS_STATUS_TEXT, Q_RESET_USERS_STATUS],
+    },
+    Migration {
+        version: 7,
+        name: "friend requests",
+        up: &[Q_SQLITE_CREATE_TABLE_FRIEND_REQUESTS],
+    },
+    Migration {
+        version: 8,
+        name: "blocks",
+        up: &[Q_SQLITE_CREATE_TABLE_BLOCKS],
+    },
+    Migration {
+        version: 9,
+        name: "password hash width",
+        // sqlite doesn't enforce `VARCHAR` sizes, so hashes already fit; only the version moves.
+        up: &[],
+    },
+    Migration {
+        version: 10,
+        name: "sessions",
+        up: &[Q_SQLITE_CREATE_TABLE_SESSIONS],
+    },
+    Migration {
+        version: 11,
+        name: "alias index",
+        up: &[Q_SQLITE_CREATE_INDEX_USER_ALIAS],
+    },
+    Migration {
+        version: 12,
+        name: "per device delivery",
+        up: &[Q_SQLITE_CREATE_TABLE_USER_MESSAGE_DELIVERIES],
+    },
+];
@@ -1,18 +1,26 @@
 use crate::imports::*;
 use crate::symbols::*;
 
+/// Wrong email or password. Which one is never told.
 #[derive(Debug)]
-pub struct WebInvalidPassword;
+pub struct WebInvalidCredentials;
 
-impl Reject for WebInvalidPassword {}
+impl Reject for WebInvalidCredentials {}
 
+/// Too many failed logins from this address or for this email.
 #[derive(Debug)]
-pub struct WebInvalidUser;
+pub struct WebLoginThrottled {
+    pub retry_after_s: i64,
+}
 
-impl Reject for WebInvalidUser {}
+impl Reject for WebLoginThrottled {}
 
 #[derive(Debug)]
-pub struct WebRegisterError {}
+pub enum WebRegisterError {
+    UserAlreadyExists,
+    /// Core or storage failed.
+    Failed,
+}
 
 impl Reject for WebRegisterError {}
 
@@ -21,32 +29,164 @@ pub struct WebChannelsError;
 
 impl Reject for WebChannelsError {}
 
+/// Core found nothing, or nothing the asker may see.
 #[derive(Debug)]
 pub struct WebCoreLookupFailed;
 
 impl Reject for WebCoreLookupFailed {}
 
+/// Core couldn't answer, e.g. because storage is down.
+#[derive(Debug)]
+pub struct WebCoreFailed;
+
+impl Reject for WebCoreFailed {}
+
 #[derive(Debug)]
 pub struct WebInvalidLoginToken;
 
 impl Reject for WebInvalidLoginToken {}
 
+#[derive(Debug)]
+pub struct WebSessionError;
+
+impl Reject for WebSessionError {}
+
+/// A user search with neither an alias nor an email.
+#[derive(Debug)]
+pub struct WebEmptySearch;
+
+impl Reject for WebEmptySearch {}
+
+#[derive(Debug)]
+pub struct WebInvalidProfile(pub ProfileError);
+
+impl Reject for WebInvalidProfile {}
+
 #[derive(Debug)]
 pub enum NetInternalError {
     ListenerBind(Box<dyn Error>),
 }
 
+/// Errors raised by a storage backend.
+#[derive(Debug)]
+pub enum StorageError {
+    /// `db_addr` names a backend that isn't supported.
+    UnsupportedBackend(String),
+    /// The database was migrated by a newer build than this one.
+    SchemaTooNew { current: u32, supported: u32 },
+    Mysql(mysql::Error),
+    Sqlite(rusqlite::Error),
+    /// No pooled connection was free within `checkout_timeout_ms`.
+    CheckoutTimeout,
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedBackend(addr) => write!(f, "unsupported storage backend: {}", addr),
+            Self::SchemaTooNew { current, supported } => write!(
+                f,
+                "database schema version {} is newer than supported version {}",
+                current, supported
+            ),
+            Self::Mysql(e) => write!(f, "mysql: {}", e),
+            Self::Sqlite(e) => write!(f, "sqlite: {}", e),
+            Self::CheckoutTimeout => write!(f, "timed out waiting for a free connection"),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+impl From<mysql::Error> for StorageError {
+    fn from(e: mysql::Error) -> Self {
+        Self::Mysql(e)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+/// Errors raised while loading a TLS certificate and key.
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, std::io::Error),
+    /// The file holds no PEM certificate.
+    NoCertificate(PathBuf),
+    /// The file holds no PKCS#8 or RSA private key in PEM.
+    NoKey(PathBuf),
+    /// The key doesn't fit the certificate, or either is unusable.
+    Rustls(tokio_rustls::rustls::TLSError),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Self::NoCertificate(path) => write!(f, "{}: no certificate found", path.display()),
+            Self::NoKey(path) => write!(f, "{}: no private key found", path.display()),
+            Self::Rustls(e) => write!(f, "tls: {}", e),
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+impl From<tokio_rustls::rustls::TLSError> for TlsError {
+    fn from(e: tokio_rustls::rustls::TLSError) -> Self {
+        Self::Rustls(e)
+    }
+}
+
+/// Why a `ProfileUpdate` was refused.
+#[derive(Debug)]
+pub enum ProfileError {
+    /// Longer than its column allows, in characters.
+    TooLong { field: &'static str, max: usize },
+    EmptyPubkey,
+}
+
+impl Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLong { field, max } => write!(f, "{} is longer than {} characters", field, max),
+            Self::EmptyPubkey => write!(f, "pubkey can't be empty"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RegisterError {
     UserAlreadyExists,
-    DbError(mysql::Error),
+    DbError(StorageError),
     Unknown,
 }
 
+impl<E> From<E> for RegisterError
+where
+    E: Into<StorageError>,
+{
+    fn from(e: E) -> Self {
+        Self::DbError(e.into())
+    }
+}
+
 #[derive(Debug)]
 pub enum LoginError {
     InvalidEmail,
     InvalidPassword,
-    DbError(mysql::Error),
+    DbError(StorageError),
     Unknown
+}
+
+impl<E> From<E> for LoginError
+where
+    E: Into<StorageError>,
+{
+    fn from(e: E) -> Self {
+        Self::DbError(e.into())
+    }
 }
\ No newline at end of file
@@ -7,9 +7,14 @@ mod core;
 mod data;
 mod db;
 mod errors;
+#[cfg(test)]
+mod fixtures;
 mod msg;
 mod net;
+mod session;
 mod storage;
+mod throttle;
+mod tls;
 mod web;
 mod ws;
 
@@ -25,13 +30,19 @@ pub mod symbols {
     pub use crate::errors::*;
     pub use crate::msg::*;
     pub use crate::net::*;
+    pub use crate::session::*;
     pub use crate::storage::*;
+    pub use crate::throttle::*;
+    pub use crate::tls::*;
     pub use crate::web::*;
     pub use crate::ws::*;
+    pub use crate::sql;
 }
 
 /// Imports this crate uses.
 pub mod imports {
+    pub use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+    pub use argon2::Argon2;
     pub use async_trait::async_trait;
     pub use chrono::{NaiveDateTime, DateTime, Utc};
     pub use crossbeam::sync::ShardedLock;
@@ -41,6 +52,7 @@ pub mod imports {
     pub use rand::{rngs::ThreadRng, Rng};
     pub use serde::{Deserialize, Serialize, de::DeserializeOwned};
     pub use std::collections::VecDeque;
+    pub use std::convert::TryFrom;
     pub use std::error::Error;
     pub use std::fmt::Display;
     pub use std::hash::Hash;
@@ -92,18 +104,22 @@ fn main() -> Result<(), Box<dyn Error>> {
     let fh = File::open(&lc.json_path)?;
     let mut buf = BufReader::new(fh);
 
-    let c = serde_json::from_reader(buf)?;
+    let c: Config = serde_json::from_reader(buf)?;
+    if c.ws_addr.is_none() && !c.ws_route {
+        return Err("no websocket endpoint, set ws_addr or ws_route".into());
+    }
+    // open storage up front so an unusable database stops startup
+    let store = Storage::new(&c.db_addr, &c.db_pool)?;
+    let sessions = Sessions::load(store.clone(), c.session.clone()).ok_or("failed to load sessions")?;
+    let tls = c.tls.clone().map(Tls::load).transpose()?;
     let (s_stop, r_stop) = tokio::sync::watch::channel(chrono::Utc::now());
     let (web_chans, ws_chans, core_chans) = Net::make_chans(r_stop.clone());
     let net_config = NetConfig::from(&c);
-    match Net::build(net_config, web_chans, ws_chans, r_stop.clone()) {
+    match Net::build(net_config, web_chans, ws_chans, sessions, tls, r_stop.clone()) {
         Ok(mut net) => {
             let running = Arc::new(AtomicBool::new(true));
             let (ctrlc_s, ctrlc_r) = crossbeam::channel::bounded(1);
-            Core::run(
-                CoreConfig::from(&c),
-                core_chans,
-            );
+            Core::run(store, core_chans);
 
             ctrlc::set_handler(move || {
                 ctrlc_s.send(());
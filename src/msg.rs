@@ -38,8 +38,16 @@ mod msg {
 
     #[derive(Debug)]
     pub enum WebToWs {
-        AddToken(UserId, LoginToken),
+        /// Disconnect every device of a user.
         ClearTokens(UserId),
+        /// Disconnect the devices of a user that connected with this session.
+        EndSession(UserId, SessionId),
+        /// A websocket upgraded on the API server, already authenticated. Ws hands its own upgrades over this way too.
+        Connect {
+            uid: UserId,
+            sid: SessionId,
+            conn: WsConn,
+        },
     }
 
     #[derive(Debug)]
@@ -52,6 +60,11 @@ mod msg {
             dest: Vec<UserId>,
             tx: WsClientboundTx,
         },
+        /// Send to one device only.
+        SendConnection {
+            cid: ConnectionId,
+            tx: WsClientboundTx,
+        },
     }
 
     impl CoreToWs {
@@ -64,7 +77,28 @@ mod msg {
     }
     #[derive(Debug)]
     pub enum WsToCore {
-        Tx(WsServerboundTx)
+        Tx(WsServerboundTx),
+        /// A device finished the handshake.
+        Connected { uid: UserId, cid: ConnectionId },
+        /// A device went away.
+        Disconnected { uid: UserId, cid: ConnectionId },
+        /// The device logged in as session `sid` of `uid` confirmed it got a message.
+        Delivered {
+            uid: UserId,
+            sid: SessionId,
+            umid: UserMessageId,
+        },
+    }
+
+    impl WsToCore {
+        pub fn sender(&self) -> UserId {
+            match self {
+                Self::Tx(tx) => tx.sender(),
+                Self::Connected { uid, .. } => *uid,
+                Self::Disconnected { uid, .. } => *uid,
+                Self::Delivered { uid, .. } => *uid,
+            }
+        }
     }
 
     impl From<WsServerboundTx> for WsToCore {
@@ -79,8 +113,6 @@ mod msg {
         ForwardToCore(ConnectionId, tungstenite::Message),
         /// ws will remove the mapping to uid.
         Disconnected(ConnectionId),
-        /// (unused) ws will let core know the umid has been sent successfully.
-        ConfirmTransmittedUserMsg(ConnectionId, UserMessageId)
     }
 
     #[derive(Debug, Clone)]
@@ -3,6 +3,9 @@ use tokio::join;
 use crate::imports::*;
 use crate::symbols::*;
 
+#[cfg(test)]
+mod tests;
+
 pub struct Net {
     s_pause: Sender<chrono::DateTime<chrono::Utc>>,
 }
@@ -44,12 +47,16 @@ impl Net {
         nc: NetConfig,
         web_chans: WebChannels,
         ws_chans: WsChannels,
+        sessions: Sessions,
+        tls: Option<Tls>,
         r_stop: tokio::sync::watch::Receiver<DateTime<Utc>>,
     ) -> Result<Net, Box<dyn Error>> {
         info!("building net...");
         let mut r_stop = r_stop;
         let (s_pause, r_pause) = tokio::sync::mpsc::channel(10);
         let nc_closure = nc.clone();
+        let ws_sessions = sessions.clone();
+        let ws_tls = tls.clone();
         std::thread::spawn(move || {
             let mut rt = tokio::runtime::Builder::new()
                 .threaded_scheduler()
@@ -57,8 +64,11 @@ impl Net {
                 .build()
                 .unwrap();
             rt.block_on(async move {
+                if let Some(tls) = tls.clone() {
+                    tokio::task::spawn(tls.reload_on_hangup());
+                }
                 tokio::task::spawn(async move {
-                    match Ws::internal(nc.clone(), ws_chans).await {
+                    match Ws::internal(nc.clone(), ws_chans, ws_sessions, ws_tls).await {
                         Ok(_) => {},
                         Err(e) => {
                             error!("ws unexpectedly terminated: {:?}", e);
@@ -68,7 +78,7 @@ impl Net {
                 let nc = nc_closure.clone();
                 let r_stop_closure = r_stop.clone();
                 tokio::task::spawn(async move {
-                    Web::internal(nc, web_chans, r_stop_closure).await;
+                    Web::internal(nc, web_chans, sessions, tls, r_stop_closure).await;
                 });
                 info!("Net: started");
                 r_stop.recv().await.unwrap();
@@ -84,8 +94,10 @@ impl Net {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NetConfig {
     pub api_addr: String,
-    pub ws_addr: String,
+    pub ws_addr: Option<String>,
+    pub ws_route: bool,
     pub enable_register: bool,
+    pub login_throttle: ThrottleConfig,
 }
 
 impl From<&Config> for NetConfig {
@@ -93,7 +105,9 @@ impl From<&Config> for NetConfig {
         NetConfig {
             api_addr: c.api_addr.clone(),
             ws_addr: c.ws_addr.clone(),
-            enable_register: true
+            ws_route: c.ws_route,
+            enable_register: true,
+            login_throttle: c.login_throttle.clone(),
         }
     }
 }
\ No newline at end of file
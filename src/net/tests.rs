@@ -0,0 +1,223 @@
+/*!
+The whole server on `memory://`, driven over real sockets the way a client would.
+*/
+use crate::imports::*;
+use crate::symbols::*;
+use std::net::SocketAddr;
+use tungstenite::Message;
+
+/// A running Web + Ws + Core. Stops when dropped.
+struct Server {
+    api: SocketAddr,
+    ws: SocketAddr,
+    s_stop: tokio::sync::watch::Sender<DateTime<Utc>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.s_stop.broadcast(Utc::now());
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn serve(ws_route: bool) -> Server {
+    let store = Storage::new("memory://", &DbPoolConfig::default()).unwrap();
+    let sessions = Sessions::load(store.clone(), SessionConfig::default()).unwrap();
+    let (s_stop, r_stop) = tokio::sync::watch::channel(Utc::now());
+    let (web_chans, ws_chans, core_chans) = Net::make_chans(r_stop.clone());
+    let (api, ws) = (free_addr(), free_addr());
+    let nc = NetConfig {
+        api_addr: api.to_string(),
+        ws_addr: Some(ws.to_string()),
+        ws_route,
+        enable_register: true,
+        login_throttle: ThrottleConfig::default(),
+    };
+    Net::build(nc, web_chans, ws_chans, sessions, None, r_stop).unwrap();
+    Core::run(store, core_chans);
+    Server { api, ws, s_stop }
+}
+
+/// Net listens from its own thread, so retry until it is up.
+async fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(conn) = TcpStream::connect(addr).await {
+            return conn;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    panic!("nothing listening on {}", addr)
+}
+
+/// POST `body` as JSON, returning the status and the JSON answer (`Null` if there is none).
+async fn post(addr: SocketAddr, path: &str, body: serde_json::Value) -> (u16, serde_json::Value) {
+    let body = body.to_string();
+    let mut conn = connect(addr).await;
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        addr,
+        body.len(),
+        body
+    );
+    conn.write_all(req.as_bytes()).await.unwrap();
+    let mut res = String::new();
+    conn.read_to_string(&mut res).await.unwrap();
+    let status = res[9..12].parse().unwrap();
+    let json = res.split("\r\n\r\n").nth(1).unwrap_or("");
+    (
+        status,
+        serde_json::from_str(json).unwrap_or(serde_json::Value::Null),
+    )
+}
+
+/// Register and log in, returning the new user and their login token.
+async fn signup(api: SocketAddr, email: &str) -> (UserId, String) {
+    let (status, uid) = post(
+        api,
+        "/register",
+        serde_json::json!({ "email": email, "password_hash": "pass", "pubkey": "key" }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", uid);
+    let (status, tokens) = post(
+        api,
+        "/login",
+        serde_json::json!({ "email": email, "password_hash": "pass" }),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", tokens);
+    (
+        serde_json::from_value(uid).unwrap(),
+        tokens["tk"].as_str().unwrap().to_owned(),
+    )
+}
+
+async fn open_ws(
+    addr: SocketAddr,
+    path: &str,
+    tk: &str,
+) -> Result<WebSocketStream<TcpStream>, tungstenite::Error> {
+    let req = http::Request::builder()
+        .uri(format!("ws://{}{}", addr, path))
+        .header(http::header::AUTHORIZATION, tk)
+        .body(())
+        .unwrap();
+    tokio_tungstenite::client_async(req, connect(addr).await)
+        .await
+        .map(|(ws, _)| ws)
+}
+
+async fn send(ws: &mut WebSocketStream<TcpStream>, pl: WsServerboundPayload) {
+    ws.send(Message::Text(serde_json::to_string(&pl).unwrap()))
+        .await
+        .unwrap();
+}
+
+/// The next push of the `kind` variant, skipping any others.
+async fn next_of(ws: &mut WebSocketStream<TcpStream>, kind: &str) -> serde_json::Value {
+    loop {
+        let m = tokio::time::timeout(Duration::from_secs(10), ws.next())
+            .await
+            .unwrap_or_else(|_| panic!("no {} pushed", kind))
+            .unwrap()
+            .unwrap();
+        if let Message::Text(s) = m {
+            let pl: serde_json::Value = serde_json::from_str(&s).unwrap();
+            if let Some(v) = pl.get(kind) {
+                return v.clone();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn register_login_and_message() {
+    let server = serve(false);
+    let (a, a_tk) = signup(server.api, "a@test").await;
+    let (b, b_tk) = signup(server.api, "b@test").await;
+    let mut a_ws = open_ws(server.ws, "/", &a_tk).await.unwrap();
+    let mut b_ws = open_ws(server.ws, "/", &b_tk).await.unwrap();
+    // core catches each device up once it is connected, so both can be reached after this
+    assert_eq!(
+        next_of(&mut a_ws, "NewMessages").await,
+        serde_json::json!([])
+    );
+    assert_eq!(
+        next_of(&mut b_ws, "NewMessages").await,
+        serde_json::json!([])
+    );
+
+    send(
+        &mut a_ws,
+        WsServerboundPayload::NewUserMessage {
+            to: b,
+            content: ClientMessage::from("hi".to_owned()),
+        },
+    )
+    .await;
+    let m = next_of(&mut b_ws, "NewMessage").await;
+    assert_eq!(m["from"], serde_json::json!(a));
+    assert_eq!(m["content"], "hi");
+
+    send(
+        &mut b_ws,
+        WsServerboundPayload::Delivered {
+            umid: serde_json::from_value(m["umid"].clone()).unwrap(),
+        },
+    )
+    .await;
+    let d = next_of(&mut a_ws, "Delivered").await;
+    assert_eq!(d["umid"], m["umid"]);
+}
+
+#[tokio::test]
+async fn stalled_upgrade_does_not_hold_up_others() {
+    let server = serve(false);
+    let (_, tk) = signup(server.api, "a@test").await;
+    // connected, but never starts the handshake
+    let _stalled = connect(server.ws).await;
+    let connected = async {
+        let mut ws = open_ws(server.ws, "/", &tk).await.unwrap();
+        next_of(&mut ws, "NewMessages").await
+    };
+    // well before the stalled upgrade times out
+    let caught_up = tokio::time::timeout(Duration::from_secs(3), connected).await;
+    assert_eq!(caught_up.unwrap(), serde_json::json!([]));
+}
+
+/// The status a refused upgrade was answered with.
+fn refused(res: Result<WebSocketStream<TcpStream>, tungstenite::Error>) -> StatusCode {
+    match res {
+        Err(tungstenite::Error::Http(status)) => status,
+        Err(e) => panic!("not refused: {}", e),
+        Ok(_) => panic!("upgraded"),
+    }
+}
+
+#[tokio::test]
+async fn ws_route_upgrades_on_the_api_port() {
+    let server = serve(true);
+    let (_, tk) = signup(server.api, "a@test").await;
+    let mut ws = open_ws(server.api, "/ws", &tk).await.unwrap();
+    assert_eq!(next_of(&mut ws, "NewMessages").await, serde_json::json!([]));
+    let bad = open_ws(server.api, "/ws", "nonsense").await;
+    assert_eq!(refused(bad), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn ws_route_is_off_unless_enabled() {
+    let server = serve(false);
+    let (_, tk) = signup(server.api, "a@test").await;
+    let off = open_ws(server.api, "/ws", &tk).await;
+    assert_eq!(refused(off), StatusCode::NOT_FOUND);
+    // the dedicated listener checks tokens the same way
+    let bad = open_ws(server.ws, "/", "nonsense").await;
+    assert_eq!(refused(bad), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,271 @@
+use sha2::{Digest, Sha256};
+
+use crate::imports::*;
+use crate::symbols::*;
+
+#[cfg(test)]
+mod tests;
+
+/// How long sessions last. Every field is optional in the launch config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// A login token stops working after going this many seconds without use.
+    pub idle_timeout_s: i64,
+    /// A refresh token stops working this many seconds after it was handed out.
+    pub refresh_ttl_s: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout_s: 60 * 60 * 24,
+            refresh_ttl_s: 60 * 60 * 24 * 30,
+        }
+    }
+}
+
+/// How often using a session is written to storage. Expiry slides on every use regardless.
+const TOUCH_INTERVAL_S: i64 = 60;
+
+/**
+Login sessions, shared by `Web` and `Ws`. Cheap to clone; clones share the same sessions.
+
+Every live session is kept in memory so tokens can be checked without a query, e.g. during
+the ws handshake. Changes are written through to storage, which only ever sees token hashes.
+*/
+#[derive(Clone)]
+pub struct Sessions {
+    store: Storage,
+    conf: SessionConfig,
+    t: Arc<ShardedLock<SessionTable>>,
+}
+
+#[derive(Default)]
+struct SessionTable {
+    by_sid: HashMap<SessionId, CachedSession>,
+    /// Keyed by login token hash.
+    by_token: HashMap<String, SessionId>,
+    /// Keyed by refresh token hash.
+    by_refresh: HashMap<String, SessionId>,
+}
+
+struct CachedSession {
+    rec: SessionRecord,
+    /// `last_seen` as storage has it.
+    persisted: DateTime<Utc>,
+}
+
+impl SessionTable {
+    fn insert(&mut self, rec: SessionRecord) {
+        let sid = rec.session.sid;
+        self.by_token.insert(rec.token_hash.clone(), sid);
+        self.by_refresh.insert(rec.refresh_hash.clone(), sid);
+        let persisted = rec.session.last_seen;
+        self.by_sid.insert(sid, CachedSession { rec, persisted });
+    }
+    fn remove(&mut self, sid: SessionId) -> Option<SessionRecord> {
+        let cached = self.by_sid.remove(&sid)?;
+        self.by_token.remove(&cached.rec.token_hash);
+        self.by_refresh.remove(&cached.rec.refresh_hash);
+        Some(cached.rec)
+    }
+}
+
+/// Tokens are random enough that a fast hash is fine.
+fn digest(tk: &str) -> String {
+    format!("{:x}", Sha256::digest(tk.as_bytes()))
+}
+
+impl Sessions {
+    /// Load every session that can still be used. Fails if storage can't be read.
+    pub fn load(store: Storage, conf: SessionConfig) -> Option<Sessions> {
+        let mut t = SessionTable::default();
+        for rec in store.load_sessions(Utc::now())? {
+            t.insert(rec);
+        }
+        info!("sessions: loaded {}", t.by_sid.len());
+        Some(Sessions {
+            store,
+            conf,
+            t: Arc::new(ShardedLock::new(t)),
+        })
+    }
+    /// Fresh tokens for `session`, with its expiry times moved forward from `now`.
+    fn issue(&self, session: Session, now: DateTime<Utc>) -> (SessionRecord, SessionTokens) {
+        let lt = LoginToken::new();
+        let refresh = RefreshToken::random();
+        let session = Session {
+            last_seen: now,
+            expires: now + chrono::Duration::seconds(self.conf.idle_timeout_s),
+            refresh_expires: now + chrono::Duration::seconds(self.conf.refresh_ttl_s),
+            ..session
+        };
+        let tokens = SessionTokens {
+            sid: session.sid,
+            expires: session.expires,
+            refresh_expires: session.refresh_expires,
+            lt: lt.clone(),
+            refresh: refresh.clone(),
+        };
+        let rec = SessionRecord {
+            session,
+            token_hash: digest(&lt.tk),
+            refresh_hash: digest(&refresh.refresh_tk),
+        };
+        (rec, tokens)
+    }
+    /// Start a session for a user that just logged in.
+    pub async fn create(
+        &self,
+        uid: UserId,
+        device: Option<String>,
+        ip: Option<String>,
+    ) -> Option<SessionTokens> {
+        let now = Utc::now();
+        let (rec, tokens) = self.issue(
+            Session {
+                sid: SessionId::random(),
+                uid,
+                created: now,
+                last_seen: now,
+                expires: now,
+                refresh_expires: now,
+                device,
+                ip,
+            },
+            now,
+        );
+        let store = self.store.clone();
+        let stored = rec.clone();
+        tokio::task::spawn_blocking(move || store.create_session(&stored))
+            .await
+            .ok()??;
+        self.t.write().ok()?.insert(rec);
+        Some(tokens)
+    }
+    /// Find who a login token belongs to, and push its expiry back.
+    pub fn authenticate(&self, lt: &LoginToken) -> Option<UserId> {
+        self.check(lt).map(|s| s.uid)
+    }
+    /**
+    Find the session of a login token, and push its expiry back.
+    Doesn't wait for storage, so it can be used where awaiting isn't possible.
+    */
+    pub fn check(&self, lt: &LoginToken) -> Option<Session> {
+        let now = Utc::now();
+        let mut t = self.t.write().ok()?;
+        let sid = *t.by_token.get(&digest(&lt.tk))?;
+        let cached = t.by_sid.get_mut(&sid)?;
+        if cached.rec.session.refresh_expires <= now {
+            t.remove(sid);
+            return None;
+        }
+        if cached.rec.session.expires <= now {
+            return None;
+        }
+        let session = &mut cached.rec.session;
+        session.last_seen = now;
+        session.expires = now + chrono::Duration::seconds(self.conf.idle_timeout_s);
+        let res = session.clone();
+        if (now - cached.persisted).num_seconds() >= TOUCH_INTERVAL_S {
+            cached.persisted = now;
+            let expires = session.expires;
+            let store = self.store.clone();
+            tokio::task::spawn_blocking(move || {
+                if store.touch_session(sid, now, expires).is_none() {
+                    warn!("sessions: failed to persist use of {}", sid);
+                }
+            });
+        }
+        Some(res)
+    }
+    /// Trade a refresh token for new tokens. The old login and refresh tokens stop working.
+    pub async fn refresh(&self, refresh: &RefreshToken) -> Option<SessionTokens> {
+        let now = Utc::now();
+        let (rec, tokens) = {
+            let mut t = self.t.write().ok()?;
+            let sid = *t.by_refresh.get(&digest(&refresh.refresh_tk))?;
+            // taken out right away, so the same refresh token can't be used twice
+            let old = t.remove(sid)?;
+            if old.session.refresh_expires <= now {
+                return None;
+            }
+            let (rec, tokens) = self.issue(old.session, now);
+            t.insert(rec.clone());
+            (rec, tokens)
+        };
+        let store = self.store.clone();
+        let stored = rec.clone();
+        let persisted = tokio::task::spawn_blocking(move || store.rotate_session(&stored))
+            .await
+            .ok()
+            .flatten();
+        if persisted.is_none() {
+            // storage may still have the old tokens; make the client log in again rather than diverge
+            let sid = rec.session.sid;
+            self.t.write().ok()?.remove(sid);
+            let store = self.store.clone();
+            let deleted = tokio::task::spawn_blocking(move || store.delete_session(sid))
+                .await
+                .ok()
+                .flatten();
+            if deleted.is_none() {
+                warn!("sessions: failed to drop {} after a failed refresh", sid);
+            }
+            return None;
+        }
+        Some(tokens)
+    }
+    /// Sessions of a user that can still be refreshed, oldest first.
+    pub fn list(&self, uid: UserId) -> Option<Vec<Session>> {
+        let now = Utc::now();
+        let t = self.t.read().ok()?;
+        let mut res: Vec<Session> = t
+            .by_sid
+            .values()
+            .map(|c| &c.rec.session)
+            .filter(|s| s.uid == uid && s.refresh_expires > now)
+            .cloned()
+            .collect();
+        res.sort_by_key(|s| s.created);
+        Some(res)
+    }
+    /// End one of `uid`'s sessions. The inner `None` means there is no such session of theirs.
+    pub async fn revoke(&self, uid: UserId, sid: SessionId) -> Option<Option<Session>> {
+        let rec = {
+            let mut t = self.t.write().ok()?;
+            match t.by_sid.get(&sid) {
+                Some(c) if c.rec.session.uid == uid => {}
+                _ => return Some(None),
+            }
+            t.remove(sid)?
+        };
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.delete_session(sid))
+            .await
+            .ok()??;
+        Some(Some(rec.session))
+    }
+    /// End every session of a user.
+    pub async fn revoke_all(&self, uid: UserId) -> Option<Vec<Session>> {
+        let ended = {
+            let mut t = self.t.write().ok()?;
+            let sids: Vec<SessionId> = t
+                .by_sid
+                .values()
+                .filter(|c| c.rec.session.uid == uid)
+                .map(|c| c.rec.session.sid)
+                .collect();
+            sids.into_iter()
+                .filter_map(|sid| t.remove(sid))
+                .map(|rec| rec.session)
+                .collect()
+        };
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.delete_user_sessions(uid))
+            .await
+            .ok()??;
+        Some(ended)
+    }
+}
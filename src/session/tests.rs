@@ -0,0 +1,155 @@
+/*!
+`Sessions` over the embedded backends. Negative lifetimes stand in for waiting for tokens to expire.
+*/
+use super::digest;
+use crate::fixtures::*;
+use crate::imports::*;
+use crate::symbols::*;
+
+fn sessions(s: &Storage, idle_timeout_s: i64, refresh_ttl_s: i64) -> Sessions {
+    let conf = SessionConfig {
+        idle_timeout_s,
+        refresh_ttl_s,
+    };
+    Sessions::load(s.clone(), conf).unwrap()
+}
+
+/// Sids storage still has, expired or not.
+fn stored(s: &Storage) -> Vec<SessionId> {
+    let long_ago = Utc::now() - chrono::Duration::days(365);
+    s.load_sessions(long_ago)
+        .unwrap()
+        .into_iter()
+        .map(|rec| rec.session.sid)
+        .collect()
+}
+
+#[tokio::test]
+async fn login_token_expires() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let live = sessions(&s, 60, 60).create(uid, None, None).await.unwrap();
+        let sessions = sessions(&s, -1, 60);
+        // loaded with the expiry it was given
+        assert_eq!(sessions.authenticate(&live.lt), Some(uid), "{}", name);
+        let idle = sessions.create(uid, None, None).await.unwrap();
+        assert_eq!(sessions.authenticate(&idle.lt), None, "{}", name);
+        // still listed and refreshable, it only went idle
+        assert_eq!(sessions.list(uid).unwrap().len(), 2, "{}", name);
+        let refreshed = sessions.refresh(&idle.refresh).await.unwrap();
+        assert_eq!(refreshed.sid, idle.sid, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn refresh_rotates_tokens() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let sessions = sessions(&s, 60, 60);
+        let old = sessions.create(uid, Some("phone".to_owned()), None).await.unwrap();
+        let new = sessions.refresh(&old.refresh).await.unwrap();
+        assert_eq!(new.sid, old.sid, "{}", name);
+        assert_ne!(new.lt.tk, old.lt.tk, "{}", name);
+        assert_ne!(new.refresh.refresh_tk, old.refresh.refresh_tk, "{}", name);
+
+        // the old tokens are spent
+        assert_eq!(sessions.authenticate(&old.lt), None, "{}", name);
+        assert!(sessions.refresh(&old.refresh).await.is_none(), "{}", name);
+        assert_eq!(sessions.authenticate(&new.lt), Some(uid), "{}", name);
+
+        // storage only has the new hashes
+        let recs = s.load_sessions(Utc::now()).unwrap();
+        assert_eq!(recs.len(), 1, "{}", name);
+        assert_eq!(recs[0].token_hash, digest(&new.lt.tk), "{}", name);
+        assert_eq!(recs[0].refresh_hash, digest(&new.refresh.refresh_tk), "{}", name);
+        assert_eq!(recs[0].session.device.as_deref(), Some("phone"), "{}", name);
+
+        let again = sessions.refresh(&new.refresh).await.unwrap();
+        assert_eq!(sessions.authenticate(&again.lt), Some(uid), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn refresh_token_expires() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let sessions = sessions(&s, 60, -1);
+        let tokens = sessions.create(uid, None, None).await.unwrap();
+        assert!(sessions.refresh(&tokens.refresh).await.is_none(), "{}", name);
+        assert_eq!(sessions.authenticate(&tokens.lt), None, "{}", name);
+        assert!(sessions.list(uid).unwrap().is_empty(), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn revoke_ends_sessions() {
+    for (name, s) in stores() {
+        let (a, b) = (register(&s, "a"), register(&s, "b"));
+        let sessions = sessions(&s, 60, 60);
+        let a1 = sessions.create(a, None, None).await.unwrap();
+        let a2 = sessions.create(a, None, None).await.unwrap();
+        let b1 = sessions.create(b, None, None).await.unwrap();
+
+        // only your own
+        assert!(matches!(sessions.revoke(b, a1.sid).await, Some(None)), "{}", name);
+        assert_eq!(sessions.authenticate(&a1.lt), Some(a), "{}", name);
+
+        assert_eq!(sessions.revoke(a, a1.sid).await.unwrap().unwrap().sid, a1.sid, "{}", name);
+        assert_eq!(sessions.authenticate(&a1.lt), None, "{}", name);
+        assert!(sessions.refresh(&a1.refresh).await.is_none(), "{}", name);
+        assert!(matches!(sessions.revoke(a, a1.sid).await, Some(None)), "{}", name);
+        let listed: Vec<SessionId> = sessions.list(a).unwrap().iter().map(|s| s.sid).collect();
+        assert_eq!(listed, vec![a2.sid], "{}", name);
+        assert!(!stored(&s).contains(&a1.sid), "{}", name);
+
+        let ended = sessions.revoke_all(a).await.unwrap();
+        assert_eq!(ended.len(), 1, "{}", name);
+        assert_eq!(sessions.authenticate(&a2.lt), None, "{}", name);
+        assert_eq!(stored(&s), vec![b1.sid], "{}", name);
+        assert_eq!(sessions.authenticate(&b1.lt), Some(b), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn load_drops_expired_sessions() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let live = sessions(&s, 60, 60).create(uid, None, None).await.unwrap();
+        let dead = sessions(&s, 60, -1).create(uid, None, None).await.unwrap();
+        assert_eq!(stored(&s).len(), 2, "{}", name);
+
+        let sessions = sessions(&s, 60, 60);
+        let listed: Vec<SessionId> = sessions.list(uid).unwrap().iter().map(|s| s.sid).collect();
+        assert_eq!(listed, vec![live.sid], "{}", name);
+        assert_eq!(sessions.authenticate(&live.lt), Some(uid), "{}", name);
+        assert_eq!(sessions.authenticate(&dead.lt), None, "{}", name);
+        // and they are gone from storage too
+        assert_eq!(stored(&s), vec![live.sid], "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn list_skips_expired_sessions() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let sessions = sessions(&s, 60, -1);
+        sessions.create(uid, None, None).await.unwrap();
+        // never touched again, so still held until the next restart
+        assert!(sessions.list(uid).unwrap().is_empty(), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn failed_refresh_ends_the_session() {
+    for (name, s) in stores() {
+        let uid = register(&s, "a");
+        let sessions = sessions(&s, 60, 60);
+        let tokens = sessions.create(uid, None, None).await.unwrap();
+        // rotating fails once storage lost the session
+        s.delete_session(tokens.sid).unwrap();
+        assert!(sessions.refresh(&tokens.refresh).await.is_none(), "{}", name);
+        assert!(sessions.list(uid).unwrap().is_empty(), "{}", name);
+        assert_eq!(sessions.authenticate(&tokens.lt), None, "{}", name);
+        assert!(stored(&s).is_empty(), "{}", name);
+    }
+}
@@ -1,369 +1,483 @@
+// all SQL goes through `query`, which only takes `Sql`
+#![deny(clippy::disallowed_methods)]
+
 use crate::imports::*;
 use crate::symbols::*;
-use mysql::*;
 
-pub struct Storage {
-    c: mysql::PooledConn,
-    tx_opts: mysql::TxOpts,
-}
+mod memory_store;
+mod mysql_store;
+mod query;
+mod sqlite_store;
+#[cfg(test)]
+mod tests;
 
-impl Storage {
-    /// Create a new storage container.
-    /// Support may be extended to other backends in the future, but for now, only mysql is supported.
-    pub fn new(sql_addr: &str) -> Result<Storage> {
-        let pool = mysql::Pool::new(sql_addr)?;
-        let mut conn = pool.get_conn()?;
-        let tx_opts = mysql::TxOpts::default();
-        Storage::init(&mut conn, tx_opts)?;
-        Ok(Storage { c: conn, tx_opts })
-    }
-    /// Initialize the storage in case it hasn't been set up.
-    /// SQL queries here include `IF NOT EXISTS` so they don't fail.
-    fn init(conn: &mut mysql::PooledConn, tx_opts: mysql::TxOpts) -> Result<()> {
-        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
-        tx.query_drop(Q_CREATE_YAP)?;
-        tx.query_drop(Q_USE_YAP)?;
-        tx.query_drop(Q_CREATE_TABLE_USERS)?;
-        tx.query_drop(Q_CREATE_TABLE_GROUPS)?;
-        tx.query_drop(Q_CREATE_TABLE_USER_MESSAGES)?;
-        tx.query_drop(Q_CREATE_TABLE_GROUP_MESSAGES)?;
-        tx.query_drop(Q_CREATE_TABLE_USER_READ_GROUP)?;
-        tx.query_drop(Q_CREATE_FRIENDS)?;
-        tx.commit()
-    }
+pub use self::memory_store::*;
+pub use self::mysql_store::*;
+pub use self::query::*;
+pub use self::sqlite_store::*;
+
+/**
+Operations a storage backend must provide for `Core`.
+
+Implementations are free to decide how data is persisted, but must follow the semantics documented on each method.
+Methods block and may be called from many threads at once, so implementations handle their own connection pooling.
+*/
+pub trait StorageBackend: Send + Sync {
     /**
-    Try to register a new user.
+    Try to register a new user. The submitted password is stored hashed.
     Will fail if
     - user already exists
     - db error
     */
-    pub fn try_register(
-        &mut self,
-        req: RegisterRequest,
-    ) -> std::result::Result<UserId, RegisterError> {
-        let mut tx = self
-            .c
-            .start_transaction(self.tx_opts)
-            .map_err(RegisterError::DbError)?;
-        // try to get associated userid from email
-        let uids = tx
-            .query::<String, _>(format!("SELECT uid FROM u WHERE email = '{}';", req.email))
-            .map_err(RegisterError::DbError)?;
-        if uids.len() > 0 {
-            // user already exists
-            Err(RegisterError::UserAlreadyExists)
-        } else {
-            // new user
-            let stmt = tx
-                .prep(
-                    "INSERT into u
-            (email, pubkey, hashed_pass, friends, groups, status, visibility) VALUES
-            (:email, :pubkey, :hashed_pass, :friends, :groups, :status, :visibility);
-            ",
-                )
-                .map_err(RegisterError::DbError)?;
-            tx.exec_drop(
-                stmt,
-                params! {
-                    "email" => req.email,
-                    "pubkey" => req.pubkey,
-                    "hashed_pass" => req.password_hash,
-                    "friends" => serde_json::to_string::<[UserId]>(&[]).unwrap(),
-                    "groups" => serde_json::to_string::<[UserId]>(&[]).unwrap(),
-                    "status" => serde_json::to_string(&UserStatus::default()).unwrap(),
-                    "visibility" => serde_json::to_string(&UserVisibility::default()).unwrap()
-                },
-            )
-            .map_err(RegisterError::DbError)?;
-            if let Some(cid) = tx.last_insert_id() {
-                tx.commit().map_err(RegisterError::DbError)?;
-                Ok((cid as u32).into())
-            } else {
-                Err(RegisterError::Unknown)
-            }
-        }
-    }
+    fn try_register(&self, req: RegisterRequest) -> Result<UserId, RegisterError>;
     /**
-    Try to login.
+    Try to login. A password stored verbatim or with outdated parameters is rehashed on success.
     Will fail if
     - invalid password
     - invalid email
     - db error
     */
-    pub fn try_login(&mut self, req: LoginRequest) -> std::result::Result<UserId, LoginError> {
-        debug!("storage: login request {:?}", &req);
-        let mut tx = self
-            .c
-            .start_transaction(self.tx_opts)
-            .map_err(|e| LoginError::DbError(e))?;
-        let stmt = tx
-            .prep("SELECT uid, hashed_pass FROM u WHERE email = :email;")
-            .map_err(LoginError::DbError)?;
-
-        match tx
-            .exec_first::<(u32, String), _, _>(
-                stmt,
-                params! {
-                    "email" => &req.email
-                },
-            )
-            .map_err(LoginError::DbError)?
-        {
-            Some((ref_uid, ref_pass)) => {
-                debug!("storage: login uid pass found");
-                if ref_pass == req.password_hash {
-                    tx.commit().map_err(LoginError::DbError)?;
-                    Ok(UserId::from(ref_uid))
-                } else {
-                    Err(LoginError::InvalidPassword)
-                }
-            }
-            None => {
-                debug!("storage: login unknown email");
-                Err(LoginError::InvalidEmail)
-            }
-        }
-    }
-    /**
-    Post a new message destined for a user. **Does not flag message as read.**
-    */
-    pub fn new_message_u(
-        &mut self,
+    fn try_login(&self, req: LoginRequest) -> Result<UserId, LoginError>;
+    /// Post a new message destined for a user. **Does not flag message as read.**
+    fn new_message_u(
+        &self,
         sender: UserId,
         receiver: UserId,
         msg: ClientMessage,
-    ) -> Option<PublicUserMessage> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx
-            .prep(
-                "INSERT INTO u_message (sender_id, receiver_id, msg_content, time_posted, r)
-        VALUES (:sender_id, :receiver_id, :msg_content, :time_posted, :r);",
-            )
-            .ok()?;
-        tx.exec_drop(
-            stmt,
-            params! {
-                "sender_id" => sender.to_string(),
-                "receiver_id" => receiver.to_string(),
-                "msg_content" => msg.to_string(),
-                "time_posted" => DateTime::<Utc>::from(SystemTime::now()).naive_utc(),
-                "r" => false
-            },
-        )
-        .ok()?;
-        match tx.last_insert_id().map(UserMessageId::from) {
-            Some(res) => {
-                let stmt = tx.prep("SELECT * FROM u_message WHERE umid = :umid").ok()?;
-                let echo_msg = tx
-                    .exec_first(stmt, params! {"umid" => res.to_string()})
-                    .ok()?
-                    .map(PublicUserMessage::from_sql_tup)
-                    .flatten()?;
-                tx.commit().ok()?;
-                Some(echo_msg)
-            }
-            None => None,
-        }
-    }
+    ) -> Option<PublicUserMessage>;
     /**
     Post a new message destined for a group. **Does not flag message as read.**
+    No check is done on whether `sender` is a member.
     */
-    pub fn new_message_g(
-        &mut self,
+    fn new_message_g(
+        &self,
         sender: UserId,
         group: GroupId,
         msg: ClientMessage,
-    ) -> Option<GroupMessageId> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx
-            .prep(
-                "INSERT INTO g_message (sender_id, gid, msg_content)
-        VALUES (:sender_id, :gid, :msg_content);",
-            )
-            .ok()?;
-        tx.exec_drop(
-            stmt,
-            params! {
-                "sender_id" => sender.to_string(),
-                "gid" => group.to_string(),
-                "msg_content" => msg.to_string()
-            },
-        )
-        .ok()?;
-        match tx.last_insert_id().map(GroupMessageId::from) {
-            Some(res) => {
-                tx.commit().ok()?;
-                Some(res)
-            }
-            None => None,
-        }
-    }
+    ) -> Option<PublicGroupMessage>;
+    /// Get a user profile. Returns a `Serialize` public-facing version. The inner `None` means there is no such user.
+    fn get_user_data(&self, u: UserId, requester: Option<UserId>)
+        -> Option<Option<PublicUserRecord>>;
     /**
-    Get a user profile. Returns a `Serialize` public-facing version.
+    Find users whose alias starts with `alias` and whose email is `email`, whichever are set, by ascending uid.
+    Only users after `after` are included, and at most `amt` of them.
+    Private users are never found, friends only users only by their friends, and nobody by users they blocked.
+    Results are masked as in `get_user_data`.
     */
-    pub fn get_user_data(
-        &mut self,
-        u: UserId,
+    fn search_users(
+        &self,
+        alias: Option<&str>,
+        email: Option<&str>,
         requester: Option<UserId>,
-    ) -> Option<PublicUserRecord> {
-        let are_friends = self.are_friends(u.clone(), requester.clone())?;
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx.prep("SELECT * FROM u WHERE uid = :uid;").ok()?;
-        match tx
-            .exec_first::<SqlUserRecord, _, _>(
-                stmt,
-                params! {
-                    "uid" => {
-                        let tmp: u32 = u.clone().into();
-                        tmp
-                    }
-                },
-            )
-            .ok()?
-        {
-            Some(res) => UserRecord::from_sql_tup(res).map(|ur| {
-                let u_visibility = ur.visibility.clone();
-                let mask_lvl = if let Some(r) = requester {
-                    if u == r {
-                        // self is requesting access
-                        UserMaskLevel::SelfUse
-                    } else {
-                        // maybe a friend is requesting?
-                        if are_friends {
-                            UserMaskLevel::HidePass
-                        } else {
-                            // treat as public
-                            match u_visibility {
-                                UserVisibility::Private => UserMaskLevel::HidePassEmailMembership,
-                                _ => UserMaskLevel::HidePassEmail,
-                            }
-                        }
-                    }
-                } else {
-                    // public access
-                    match u_visibility {
-                        UserVisibility::Private => UserMaskLevel::HidePassEmailMembership,
-                        _ => UserMaskLevel::HidePassEmail,
-                    }
-                };
-                ur.mask(mask_lvl)
-            }),
-            None => None,
-        }
-    }
-    /// Get a group profile.
-    pub fn get_group_data(&mut self, g: GroupId) -> Option<GroupRecord> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        todo!()
-    }
+        after: Option<UserId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserRecord>>;
+    /// Get a group profile, including its members.
+    fn get_group_data(&self, g: GroupId) -> Option<GroupRecord>;
     /**
     Get unread messages a user hasn't read *from a user*.
-    Primary purpose is for the client to catch up.
     **Will not flag messages as read.**
     */
-    pub fn get_user_user_unread(&mut self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx
-            .prep(
-                "SELECT * FROM u_message WHERE
-        sender_id = :sender_id AND
-        receiver_id = :receiver_id AND
-        r = 'false';",
-            )
-            .ok()?;
-        let res = tx
-            .exec_iter(
-                stmt,
-                params! {
-                    "sender_id" => s.into_sql(),
-                    "receiver_id" => r.into_sql()
-                },
-            )
-            .ok()?
-            .filter_map(Result::ok)
-            .map(from_row)
-            .map(PublicUserMessage::from_sql_tup)
-            .collect();
-        res
-    }
+    fn get_user_user_unread(&self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>>;
+    /**
+    Get every message sent to `r` that `r` hasn't read, from anyone, oldest first.
+    **Will not flag messages as read.**
+    */
+    fn get_user_unread(&self, r: UserId) -> Option<Vec<PublicUserMessage>>;
     /**
     Get unread messages a user hasn't read *from a group*.
-    Primary purpose is for the client to catch up.
     **Will not flag messages as read.**
     */
-    pub fn get_user_group_unread(
-        &mut self,
+    fn get_user_group_unread(&self, s: UserId, g: GroupId)
+        -> Option<Vec<PublicGroupMessage>>;
+    /**
+    Get a page of the conversation between `s` and `r`, both directions, newest first.
+    Only messages older than `before` are included, and at most `amt` of them.
+    `HistoryQuery::Unseen` means messages sent to `s` that `s` hasn't read.
+    **Will not flag messages as read.**
+    */
+    fn get_user_history(
+        &self,
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserMessage>>;
+    /// Like `get_user_history`, for a group. `s` is the asker, used for `HistoryQuery::Unseen`.
+    fn get_group_history(
+        &self,
         s: UserId,
         g: GroupId,
-    ) -> Option<Vec<PublicUserMessage>> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        todo!()
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicGroupMessage>>;
+    /**
+    Record that the device logged in as session `device` of receiver `r` confirmed it got a user message.
+    The message is flagged as delivered too, if it wasn't yet.
+    Returns the sender and how many of `r`'s devices have confirmed it, only if `device` hadn't before.
+    */
+    fn flag_u_delivered(
+        &self,
+        umid: UserMessageId,
+        r: UserId,
+        device: SessionId,
+        at: DateTime<Utc>,
+    ) -> Option<(UserId, u32)>;
+    /**
+    Flag a user message as read by its receiver `r`, and as delivered if it wasn't yet.
+    Returns the sender, only if the message wasn't read before.
+    */
+    fn flag_u_read(&self, umid: UserMessageId, r: UserId, at: DateTime<Utc>) -> Option<UserId>;
+    /// Flag a group message as read by `reader`. Fails if `gmid` wasn't posted to `g`.
+    fn flag_g_read(&self, g: GroupId, gmid: GroupMessageId, reader: UserId) -> Option<()>;
+    /// Replace a user's stored password.
+    fn set_password(&self, u: UserId, hashed: HashedPassword) -> Option<()>;
+    /// Get the status a user picked, and their status text.
+    fn get_status(&self, u: UserId) -> Option<(UserStatus, Option<String>)>;
+    /// Set the status a user picked, and their status text. Fails if there is no such user.
+    fn set_status(&self, u: UserId, status: UserStatus, text: Option<String>) -> Option<()>;
+    /// Apply the fields of `update` that are set. Fails if there is no such user.
+    /// Nothing is checked against column limits.
+    fn set_profile(&self, u: UserId, update: &ProfileUpdate) -> Option<()>;
+    /// Get a user's friends.
+    fn get_friends(&self, u: UserId) -> Option<Vec<UserId>>;
+    /// Check if two users are friends.
+    fn are_friends(&self, l: UserId, r: Option<UserId>) -> Option<bool>;
+    /// Add two users as friends.
+    fn add_friend(&self, l: UserId, r: UserId) -> Option<()>;
+    /// Remove a friend pairing. Fails if they weren't friends.
+    fn remove_friend(&self, l: UserId, r: UserId) -> Option<()>;
+    /// Get the request `s` sent to `r`, if any, whatever its state.
+    fn get_friend_request(&self, s: UserId, r: UserId) -> Option<Option<FriendRequest>>;
+    /// Send a request, or change the state of one, replacing whatever was there.
+    fn set_friend_request(
+        &self,
+        s: UserId,
+        r: UserId,
+        state: FriendRequestState,
+        at: DateTime<Utc>,
+    ) -> Option<FriendRequest>;
+    /// Accept a pending request from `s` to `r`, making them friends at the same time.
+    fn accept_friend_request(&self, s: UserId, r: UserId, at: DateTime<Utc>) -> Option<FriendRequest>;
+    /// Get pending requests sent or received by a user.
+    fn get_friend_requests(&self, u: UserId) -> Option<Vec<FriendRequest>>;
+    /// Block `other` for `u`. Also ends their friendship and drops requests either way.
+    fn block_user(&self, u: UserId, other: UserId) -> Option<()>;
+    /// Fails if `other` wasn't blocked.
+    fn unblock_user(&self, u: UserId, other: UserId) -> Option<()>;
+    /// Check if `u` has blocked `other`.
+    fn has_blocked(&self, u: UserId, other: UserId) -> Option<bool>;
+    /// Get the users `u` has blocked.
+    fn get_blocked(&self, u: UserId) -> Option<Vec<UserId>>;
+    /// Create a group with `owner` as its first member, holding `GroupRole::Owner`.
+    fn create_group(
+        &self,
+        owner: UserId,
+        name: Option<String>,
+        motd: Option<String>,
+    ) -> Option<GroupRecord>;
+    /// Rename a group. Fails if there is no such group.
+    fn set_group_name(&self, g: GroupId, name: Option<String>) -> Option<()>;
+    /// Change the motd of a group. Fails if there is no such group.
+    fn set_group_motd(&self, g: GroupId, motd: Option<String>) -> Option<()>;
+    /// Get the members of a group.
+    fn get_group_members(&self, g: GroupId) -> Option<Vec<UserId>>;
+    /// Get the groups a user is a member of.
+    fn get_user_groups(&self, u: UserId) -> Option<Vec<GroupId>>;
+    /// Check if a user is a member of a group.
+    fn is_group_member(&self, u: UserId, g: GroupId) -> Option<bool>;
+    /// Record an invite for `invitee` to join `g`. No check is done on whether `inviter` is a member.
+    fn invite_to_group(&self, inviter: UserId, invitee: UserId, g: GroupId) -> Option<()>;
+    /// Join a group as a `GroupRole::Member`. Fails unless `u` has a pending invite, which is used up, and isn't banned.
+    fn join_group(&self, u: UserId, g: GroupId) -> Option<()>;
+    /// Leave a group.
+    fn leave_group(&self, u: UserId, g: GroupId) -> Option<()>;
+    /// Get the role of a member. The inner `None` means `u` isn't a member.
+    fn get_member_role(&self, u: UserId, g: GroupId) -> Option<Option<GroupRole>>;
+    /// Change the role of a member. Fails if `u` isn't a member. No permission checks are done.
+    fn set_member_role(&self, u: UserId, g: GroupId, role: GroupRole) -> Option<()>;
+    /// Make member `to` the owner of a group and its owner `from` an admin, both or neither.
+    fn transfer_ownership(&self, from: UserId, to: UserId, g: GroupId) -> Option<()>;
+    /**
+    Ban a user from a group, removing their membership and pending invite if any.
+    Banning a user that isn't a member is allowed. No permission checks are done.
+    */
+    fn ban_from_group(&self, banner: UserId, u: UserId, g: GroupId) -> Option<()>;
+    /// Lift a ban. Fails if `u` wasn't banned.
+    fn unban_from_group(&self, u: UserId, g: GroupId) -> Option<()>;
+    /// Check if a user is banned from a group.
+    fn is_banned(&self, u: UserId, g: GroupId) -> Option<bool>;
+    /// Store a new session. Fails if the id or either token hash is taken.
+    fn create_session(&self, rec: &SessionRecord) -> Option<()>;
+    /// Write the token hashes and times of a session. Fails if it doesn't exist.
+    fn rotate_session(&self, rec: &SessionRecord) -> Option<()>;
+    /// Record that a session was used. Leaves the tokens alone.
+    fn touch_session(&self, sid: SessionId, last_seen: DateTime<Utc>, expires: DateTime<Utc>) -> Option<()>;
+    /// Fails if the session doesn't exist.
+    fn delete_session(&self, sid: SessionId) -> Option<()>;
+    /// Delete every session of a user.
+    fn delete_user_sessions(&self, u: UserId) -> Option<()>;
+    /// Drop sessions that can't be refreshed anymore as of `now`, then get the rest.
+    fn load_sessions(&self, now: DateTime<Utc>) -> Option<Vec<SessionRecord>>;
+}
+
+/**
+Storage container used by `Core`. Cheap to clone; clones share the same backend.
+
+The backend is picked from the scheme of the database address, e.g. `mysql://...`, `sqlite:///path/yap.db` or `memory://`.
+*/
+#[derive(Clone)]
+pub struct Storage {
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl Storage {
+    /// Create a new storage container from a database address.
+    pub fn new(db_addr: &str, pc: &DbPoolConfig) -> Result<Storage, StorageError> {
+        let inner: Arc<dyn StorageBackend> = match db_addr.split("://").next() {
+            Some("mysql") => Arc::new(MysqlStorage::new(db_addr, pc)?),
+            Some("sqlite") => Arc::new(SqliteStorage::new(db_addr, pc)?),
+            Some("memory") => Arc::new(MemoryStorage::new()),
+            _ => return Err(StorageError::UnsupportedBackend(db_addr.to_owned())),
+        };
+        Ok(Storage { inner })
     }
-    /// Flag a user message as read.
-    pub fn flag_u_read(&mut self, umid: UserMessageId) -> Option<()> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx
-            .prep("UPDATE u_message SET r = 'true' WHERE umid = :umid;")
-            .ok()?;
-        tx.exec_drop(
-            stmt,
-            params! {
-                "umid" => umid.into_sql()
-            },
-        )
-        .ok()
+}
+
+impl std::ops::Deref for Storage {
+    type Target = dyn StorageBackend;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner.as_ref()
     }
-    /// Flag a group message as read.
-    pub fn flag_g_read(&mut self, gmid: GroupMessageId) -> Option<()> {
-        todo!()
+}
+
+/**
+Fixed-size pool of connections for backends whose driver doesn't pool by itself.
+
+Checking out blocks until a connection is free, which bounds how many queries run at once.
+It gives up after the timeout it was made with, so a stuck query can't hang every caller.
+*/
+pub struct ConnPool<C> {
+    s: crossbeam::channel::Sender<C>,
+    r: crossbeam::channel::Receiver<C>,
+    timeout: Duration,
+}
+
+impl<C> ConnPool<C> {
+    /// Pool `conns`, waiting at most `timeout` for one to be free when checking out.
+    pub fn new(conns: Vec<C>, timeout: Duration) -> ConnPool<C> {
+        let (s, r) = crossbeam::channel::bounded(conns.len());
+        for c in conns {
+            s.send(c).unwrap();
+        }
+        ConnPool { s, r, timeout }
     }
-    /// Check if two users are friends.
-    /// Assumes `(l, r)` and `(r, l)` were added on entry.
-    pub fn are_friends(&mut self, l: UserId, r: Option<UserId>) -> Option<bool> {
-        match r {
-            Some(r) => {
-                let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-                let stmt = tx.prep("SELECT (r) FROM u_friend WHERE l = :l;").ok()?;
-                let it = tx
-                    .exec_iter(
-                        stmt,
-                        params! {
-                            "l" => l.into_sql()
-                        },
-                    )
-                    .ok()?;
-                let r: u32 = r.into();
-                // necessary for lifetime reasons
-                let res = it
-                    .filter_map(Result::ok)
-                    .map(from_row::<u32>)
-                    .any(|r_db| r == r_db);
-                Some(res)
-            }
-            None => Some(false),
+    /// Check out a connection. It is returned to the pool when the guard is dropped.
+    pub fn get(&self) -> Result<PooledGuard<C>, StorageError> {
+        // pool owns both ends, so the only way to fail is running out of time
+        let c = self
+            .r
+            .recv_timeout(self.timeout)
+            .map_err(|_| StorageError::CheckoutTimeout)?;
+        Ok(PooledGuard {
+            c: Some(c),
+            s: self.s.clone(),
+        })
+    }
+}
+
+pub struct PooledGuard<C> {
+    c: Option<C>,
+    s: crossbeam::channel::Sender<C>,
+}
+
+impl<C> std::ops::Deref for PooledGuard<C> {
+    type Target = C;
+
+    fn deref(&self) -> &Self::Target {
+        self.c.as_ref().unwrap()
+    }
+}
+
+impl<C> std::ops::DerefMut for PooledGuard<C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.c.as_mut().unwrap()
+    }
+}
+
+impl<C> Drop for PooledGuard<C> {
+    fn drop(&mut self) {
+        if let Some(c) = self.c.take() {
+            // pool owns both ends, so this can't fail
+            let _ = self.s.send(c);
         }
     }
-    /**
-    Add two users as friends.
-    No manual validation of whether the uids are valid is done, as in the database should handle it because of foreign key relations.
-    */
-    pub fn add_friend(&mut self, l: UserId, r: UserId) -> Option<()> {
-        let mut tx = self.c.start_transaction(self.tx_opts).ok()?;
-        let stmt = tx.prep("INSERT INTO u_friend (l, r) VALUES (:l, :r); INSERT INTO u_friend (l, r) VALUES (:r, :l);").ok()?;
-        tx.exec_drop(
-            stmt,
-            params! {
-                "l" => {let tmp: u32 = l.into(); tmp},
-                "r" => {let tmp: u32 = r.into(); tmp}
-            },
-        )
+}
+
+/**
+Decide how much of `u`'s profile `requester` is allowed to see.
+Shared by all backends so masking behaves the same regardless of where data lives.
+*/
+pub fn mask_level(
+    u: UserId,
+    requester: Option<UserId>,
+    are_friends: bool,
+    blocked: bool,
+    u_visibility: &UserVisibility,
+) -> UserMaskLevel {
+    // friends only profiles look private to anyone but friends
+    let public = match u_visibility {
+        UserVisibility::Public => UserMaskLevel::HidePassEmail,
+        _ => UserMaskLevel::HidePassEmailMembership,
+    };
+    match requester {
+        // self is requesting access
+        Some(r) if u == r => UserMaskLevel::SelfUse,
+        // `u` blocked the requester, whatever the visibility
+        Some(_) if blocked => UserMaskLevel::HidePassEmailMembership,
+        // maybe a friend is requesting?
+        Some(_) if are_friends => UserMaskLevel::HidePass,
+        // treat as public
+        _ => public,
+    }
+}
+
+/**
+Mask a row found by `Q_SEARCH_USERS` for `requester`.
+The query already left out users who blocked the requester, and its memberships are the real ones.
+*/
+pub fn mask_search_hit(tup: SqlUserRecord, requester: Option<UserId>) -> Option<PublicUserRecord> {
+    let ur = UserRecord::from_sql_tup(tup)?;
+    let are_friends = requester.is_some_and(|r| ur.friends.contains(&r));
+    let mask_lvl = mask_level(ur.uid, requester, are_friends, false, &ur.visibility);
+    Some(ur.mask(mask_lvl))
+}
+
+/// Escape `LIKE` wildcards in a prefix, for `Q_SEARCH_USERS`, which uses `!` as its escape character.
+pub fn like_prefix(prefix: &str) -> String {
+    let mut res = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '!' | '%' | '_') {
+            res.push('!');
+        }
+        res.push(c);
+    }
+    res.push('%');
+    res
+}
+
+/// What checking a submitted password against the stored one found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Invalid,
+    Valid,
+    /// Valid, but stored verbatim or with outdated parameters, so it should be replaced.
+    NeedsRehash,
+}
+
+/// Hash a submitted password for storage, with Argon2id and a fresh salt.
+pub fn hash_password(pass: &str) -> Option<HashedPassword> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(pass.as_bytes(), &salt)
         .ok()
+        .map(|hash| HashedPassword::from(hash.to_string()))
+}
+
+/**
+Check a submitted password against the stored one, in constant time.
+Anything that doesn't parse as a PHC string is a legacy row holding the credential verbatim.
+*/
+pub fn check_password(stored: &HashedPassword, pass: &str) -> PasswordCheck {
+    match PasswordHash::new(stored.as_str()) {
+        Ok(hash) => {
+            if Argon2::default()
+                .verify_password(pass.as_bytes(), &hash)
+                .is_err()
+            {
+                PasswordCheck::Invalid
+            } else if hash_outdated(&hash) {
+                PasswordCheck::NeedsRehash
+            } else {
+                PasswordCheck::Valid
+            }
+        }
+        Err(_) => {
+            let (l, r) = (stored.as_str().as_bytes(), pass.as_bytes());
+            let diff = l.iter().zip(r).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+            if l.len() == r.len() && diff == 0 {
+                PasswordCheck::NeedsRehash
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
     }
-    /**
-    Remove a friend pairing.
-    No manual validation of whether the uids are valid is done, as in the database should handle it because of foreign key relations.
-    */
-    pub fn remove_friend(&mut self, l: UserId, r: UserId) -> Option<()> {
-        todo!()
+}
+
+/// Check if a hash was made with anything other than what `hash_password` uses now.
+fn hash_outdated(hash: &PasswordHash) -> bool {
+    let current = Argon2::default();
+    hash.algorithm != argon2::Algorithm::default().ident()
+        || hash.version != Some(argon2::Version::default().into())
+        || argon2::Params::try_from(hash).ok().as_ref() != Some(current.params())
+}
+
+/**
+Finish a login once a backend found the user's stored password.
+Legacy and outdated hashes are replaced through `rehash`; failing to do so doesn't fail the login.
+*/
+pub fn verify_login<F>(
+    uid: UserId,
+    stored: HashedPassword,
+    pass: &str,
+    rehash: F,
+) -> Result<UserId, LoginError>
+where
+    F: FnOnce(HashedPassword) -> Option<()>,
+{
+    match check_password(&stored, pass) {
+        PasswordCheck::Invalid => Err(LoginError::InvalidPassword),
+        PasswordCheck::Valid => Ok(uid),
+        PasswordCheck::NeedsRehash => {
+            match hash_password(pass).and_then(rehash) {
+                Some(()) => debug!("storage: rehashed password of uid {}", &uid),
+                None => warn!("storage: failed to rehash password of uid {}", &uid),
+            }
+            Ok(uid)
+        }
+    }
+}
+
+/**
+Finish a login for an email nobody registered.
+Still checks the password against a throwaway hash, so the reply takes as long as a wrong password.
+*/
+pub fn reject_unknown_login(pass: &str) -> LoginError {
+    static DUMMY: std::sync::OnceLock<Option<HashedPassword>> = std::sync::OnceLock::new();
+    if let Some(dummy) = DUMMY.get_or_init(|| hash_password("")) {
+        check_password(dummy, pass);
+    }
+    LoginError::InvalidEmail
+}
+
+/**
+Pick the migrations that still have to run on a database at schema `current`.
+
+Refuses to continue if the database is newer than this binary understands, since older code
+could silently corrupt data it doesn't know about.
+*/
+pub fn pending_migrations(
+    current: u32,
+    migrations: &'static [Migration],
+) -> Result<&'static [Migration], StorageError> {
+    let supported = migrations.last().map(|m| m.version).unwrap_or(0);
+    if current > supported {
+        Err(StorageError::SchemaTooNew { current, supported })
+    } else {
+        let applied = migrations.iter().take_while(|m| m.version <= current).count();
+        Ok(&migrations[applied..])
     }
 }
@@ -0,0 +1,854 @@
+use crate::imports::*;
+use crate::symbols::*;
+
+/**
+In-memory storage backend. Nothing is persisted; everything is lost when the server stops.
+
+Useful for tests and throwaway servers. Selected with `memory://`.
+*/
+#[derive(Default)]
+pub struct MemoryStorage {
+    t: std::sync::Mutex<MemoryTables>,
+}
+
+/// Everything `MemoryStorage` holds. Locked as a whole for each operation.
+#[derive(Default)]
+struct MemoryTables {
+    /// Indexed by `uid - 1`, like an auto increment column.
+    users: Vec<UserRecord>,
+    /// Indexed by `umid - 1`.
+    u_messages: Vec<SqlUserMessage>,
+    /// Indexed by `gid - 1`.
+    groups: Vec<MemoryGroup>,
+    /// Memberships in the order they were made.
+    g_members: Vec<(UserId, GroupId, GroupRole)>,
+    g_invites: HashSet<(GroupId, UserId)>,
+    g_bans: HashSet<(GroupId, UserId)>,
+    /// Indexed by `gmid - 1`.
+    g_messages: Vec<SqlGroupMessage>,
+    g_read: HashSet<(GroupMessageId, UserId)>,
+    /// `(umid, device)` -> when it was confirmed.
+    u_deliveries: HashMap<(u64, SessionId), DateTime<Utc>>,
+    /// Holds both `(l, r)` and `(r, l)`.
+    friends: HashSet<(UserId, UserId)>,
+    /// Keyed by `(from, to)`.
+    friend_requests: HashMap<(UserId, UserId), FriendRequest>,
+    /// `(uid, blocked)`.
+    blocks: HashSet<(UserId, UserId)>,
+    sessions: HashMap<SessionId, SessionRecord>,
+}
+
+#[derive(Default)]
+struct MemoryGroup {
+    name: Option<String>,
+    motd: Option<String>,
+}
+
+impl MemoryStorage {
+    /// Create a new, empty in-memory storage container.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryTables> {
+        // keep serving after a panic elsewhere; no operation leaves the tables half written
+        self.t.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MemoryTables {
+    fn user(&self, u: UserId) -> Option<&UserRecord> {
+        let idx: u32 = u.into();
+        self.users.get((idx as usize).checked_sub(1)?)
+    }
+    fn group(&self, g: GroupId) -> Option<&MemoryGroup> {
+        let idx: u32 = g.into();
+        self.groups.get((idx as usize).checked_sub(1)?)
+    }
+    fn group_mut(&mut self, g: GroupId) -> Option<&mut MemoryGroup> {
+        let idx: u32 = g.into();
+        self.groups.get_mut((idx as usize).checked_sub(1)?)
+    }
+    fn member_mut(&mut self, u: UserId, g: GroupId) -> Option<&mut (UserId, GroupId, GroupRole)> {
+        self.g_members
+            .iter_mut()
+            .find(|(uid, gid, _)| *uid == u && *gid == g)
+    }
+    fn user_message(&mut self, umid: UserMessageId) -> Option<&mut SqlUserMessage> {
+        let idx: u64 = umid.into();
+        self.u_messages.get_mut((idx as usize).checked_sub(1)?)
+    }
+    /// Store a user whose password is already hashed. The email is checked again, in case it was taken meanwhile.
+    fn add_user(
+        &mut self,
+        req: RegisterRequest,
+        hashed_pass: HashedPassword,
+    ) -> Result<UserId, RegisterError> {
+        if self.login_row(&req.email).is_some() {
+            // user already exists
+            return Err(RegisterError::UserAlreadyExists);
+        }
+        let uid = UserId::from(self.users.len() as u32 + 1);
+        self.users.push(UserRecord {
+            uid,
+            email: req.email,
+            pubkey: Pubkey::from(req.pubkey),
+            hashed_pass,
+            alias: None,
+            friends: Vec::new(),
+            groups: Vec::new(),
+            motd: None,
+            status: UserStatus::default(),
+            visibility: UserVisibility::default(),
+            status_text: None,
+        });
+        Ok(uid)
+    }
+    /// The uid and stored password of whoever registered `email`.
+    fn login_row(&self, email: &str) -> Option<(UserId, HashedPassword)> {
+        self.users
+            .iter()
+            .find(|ur| ur.email == email)
+            .map(|ur| (ur.uid, ur.hashed_pass.clone()))
+    }
+    fn set_password(&mut self, u: UserId, hashed: HashedPassword) -> Option<()> {
+        let idx: u32 = u.into();
+        let ur = self.users.get_mut((idx as usize).checked_sub(1)?)?;
+        ur.hashed_pass = hashed;
+        Some(())
+    }
+    fn new_message_u(
+        &mut self,
+        sender: UserId,
+        receiver: UserId,
+        msg: ClientMessage,
+    ) -> Option<PublicUserMessage> {
+        // mirror the foreign keys of the sql backends
+        self.user(sender)?;
+        self.user(receiver)?;
+        if self.has_blocked(receiver, sender)? {
+            return None;
+        }
+        let row = (
+            self.u_messages.len() as u64 + 1,
+            sender.into(),
+            receiver.into(),
+            msg.to_string(),
+            DateTime::<Utc>::from(SystemTime::now()).naive_utc(),
+            false,
+            None,
+            None,
+        );
+        self.u_messages.push(row.clone());
+        PublicUserMessage::from_sql_tup(row)
+    }
+    fn new_message_g(
+        &mut self,
+        sender: UserId,
+        group: GroupId,
+        msg: ClientMessage,
+    ) -> Option<PublicGroupMessage> {
+        self.user(sender)?;
+        self.group(group)?;
+        let row = (
+            self.g_messages.len() as u64 + 1,
+            sender.into(),
+            group.into(),
+            msg.to_string(),
+            DateTime::<Utc>::from(SystemTime::now()).naive_utc(),
+        );
+        self.g_messages.push(row.clone());
+        PublicGroupMessage::from_sql_tup(row)
+    }
+    fn get_user_data(
+        &mut self,
+        u: UserId,
+        requester: Option<UserId>,
+    ) -> Option<Option<PublicUserRecord>> {
+        let are_friends = self.are_friends(u, requester)?;
+        let blocked = match requester {
+            Some(r) => self.has_blocked(u, r)?,
+            None => false,
+        };
+        let mut ur = match self.user(u) {
+            Some(ur) => ur.clone(),
+            None => return Some(None),
+        };
+        ur.friends = self.get_friends(u)?;
+        ur.groups = self.get_user_groups(u)?;
+        let mask_lvl = mask_level(u, requester, are_friends, blocked, &ur.visibility);
+        Some(Some(ur.mask(mask_lvl)))
+    }
+    fn search_users(
+        &mut self,
+        alias: Option<&str>,
+        email: Option<&str>,
+        requester: Option<UserId>,
+        after: Option<UserId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserRecord>> {
+        let prefix = alias.map(str::to_ascii_lowercase);
+        let after = after.map(u32::from).unwrap_or(0);
+        let found: Vec<UserId> = self
+            .users
+            .iter()
+            .filter(|ur| u32::from(ur.uid) > after)
+            .filter(|ur| match (&prefix, &ur.alias) {
+                (Some(p), Some(a)) => a.to_ascii_lowercase().starts_with(p.as_str()),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .filter(|ur| email.is_none() || email == Some(ur.email.as_str()))
+            .filter(|ur| {
+                let friend = requester.is_some_and(|r| self.friends.contains(&(r, ur.uid)));
+                let blocked = requester.is_some_and(|r| self.blocks.contains(&(ur.uid, r)));
+                let visible = match ur.visibility {
+                    UserVisibility::Public => true,
+                    UserVisibility::FriendsOnly => friend,
+                    UserVisibility::Private => false,
+                };
+                visible && !blocked
+            })
+            .take(amt as usize)
+            .map(|ur| ur.uid)
+            .collect();
+        found
+            .into_iter()
+            .map(|u| self.get_user_data(u, requester).flatten())
+            .collect()
+    }
+    fn get_group_data(&mut self, g: GroupId) -> Option<GroupRecord> {
+        let mg = self.group(g)?;
+        Some(GroupRecord {
+            gid: g,
+            name: mg.name.clone(),
+            motd: mg.motd.clone(),
+            members: self
+                .g_members
+                .iter()
+                .filter(|(_, gid, _)| *gid == g)
+                .map(|(uid, _, role)| GroupMember {
+                    uid: *uid,
+                    role: *role,
+                })
+                .collect(),
+        })
+    }
+    fn get_user_user_unread(&mut self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let (s, r): (u32, u32) = (s.into(), r.into());
+        self.u_messages
+            .iter()
+            .filter(|row| row.1 == s && row.2 == r && !row.5)
+            .cloned()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_unread(&mut self, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let r: u32 = r.into();
+        self.u_messages
+            .iter()
+            .filter(|row| row.2 == r && !row.5)
+            .cloned()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_group_unread(
+        &mut self,
+        s: UserId,
+        g: GroupId,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let g_read = &self.g_read;
+        let (uid, gid): (u32, u32) = (s.into(), g.into());
+        self.g_messages
+            .iter()
+            .filter(|row| {
+                row.2 == gid && row.1 != uid && !g_read.contains(&(row.0.into(), s))
+            })
+            .cloned()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_history(
+        &mut self,
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let before = before.map(u64::from);
+        let (s, r): (u32, u32) = (s.into(), r.into());
+        self.u_messages
+            .iter()
+            .rev()
+            .filter(|row| (row.1 == s && row.2 == r) || (row.1 == r && row.2 == s))
+            .filter(|row| before.is_none_or(|b| row.0 < b))
+            .filter(|row| from.is_none_or(|t| row.4 >= t) && to.is_none_or(|t| row.4 < t))
+            .filter(|row| !unseen || (row.2 == s && !row.5))
+            .take(amt as usize)
+            .cloned()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_group_history(
+        &mut self,
+        s: UserId,
+        g: GroupId,
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let before = before.map(u64::from);
+        let g_read = &self.g_read;
+        let (uid, gid): (u32, u32) = (s.into(), g.into());
+        self.g_messages
+            .iter()
+            .rev()
+            .filter(|row| row.2 == gid)
+            .filter(|row| before.is_none_or(|b| row.0 < b))
+            .filter(|row| from.is_none_or(|t| row.4 >= t) && to.is_none_or(|t| row.4 < t))
+            .filter(|row| !unseen || (row.1 != uid && !g_read.contains(&(row.0.into(), s))))
+            .take(amt as usize)
+            .cloned()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect()
+    }
+    fn flag_u_delivered(
+        &mut self,
+        umid: UserMessageId,
+        r: UserId,
+        device: SessionId,
+        at: DateTime<Utc>,
+    ) -> Option<(UserId, u32)> {
+        let idx = u64::from(umid.clone());
+        let row = self.user_message(umid)?;
+        if row.2 != u32::from(r) {
+            return None;
+        }
+        row.6.get_or_insert(at.naive_utc());
+        let sender = UserId::from(row.1);
+        if self.u_deliveries.insert((idx, device), at).is_some() {
+            return None;
+        }
+        let devices = self.u_deliveries.keys().filter(|(m, _)| *m == idx).count();
+        Some((sender, devices as u32))
+    }
+    fn flag_u_read(&mut self, umid: UserMessageId, r: UserId, at: DateTime<Utc>) -> Option<UserId> {
+        let row = self.user_message(umid)?;
+        if row.2 != u32::from(r) || row.5 {
+            return None;
+        }
+        row.5 = true;
+        row.6.get_or_insert(at.naive_utc());
+        row.7 = Some(at.naive_utc());
+        Some(UserId::from(row.1))
+    }
+    fn flag_g_read(&mut self, g: GroupId, gmid: GroupMessageId, reader: UserId) -> Option<()> {
+        let idx: u64 = gmid.into();
+        let row = self.g_messages.get((idx as usize).checked_sub(1)?)?;
+        if row.2 != u32::from(g) {
+            return None;
+        }
+        self.g_read.insert((gmid, reader));
+        Some(())
+    }
+    fn get_status(&mut self, u: UserId) -> Option<(UserStatus, Option<String>)> {
+        let ur = self.user(u)?;
+        Some((ur.status, ur.status_text.clone()))
+    }
+    fn set_status(&mut self, u: UserId, status: UserStatus, text: Option<String>) -> Option<()> {
+        let idx: u32 = u.into();
+        let ur = self.users.get_mut((idx as usize).checked_sub(1)?)?;
+        ur.status = status;
+        ur.status_text = text;
+        Some(())
+    }
+    fn set_profile(&mut self, u: UserId, update: &ProfileUpdate) -> Option<()> {
+        let idx: u32 = u.into();
+        let ur = self.users.get_mut((idx as usize).checked_sub(1)?)?;
+        if let Some(alias) = &update.alias {
+            ur.alias = alias.clone();
+        }
+        if let Some(motd) = &update.motd {
+            ur.motd = motd.clone();
+        }
+        if let Some(visibility) = &update.visibility {
+            ur.visibility = visibility.clone();
+        }
+        if let Some(pubkey) = &update.pubkey {
+            ur.pubkey = Pubkey::from(pubkey.clone());
+        }
+        Some(())
+    }
+    fn get_friends(&self, u: UserId) -> Option<Vec<UserId>> {
+        Some(
+            self.friends
+                .iter()
+                .filter(|(l, _)| *l == u)
+                .map(|(_, r)| *r)
+                .collect(),
+        )
+    }
+    fn are_friends(&mut self, l: UserId, r: Option<UserId>) -> Option<bool> {
+        match r {
+            Some(r) => Some(self.friends.contains(&(l, r))),
+            None => Some(false),
+        }
+    }
+    fn add_friend(&mut self, l: UserId, r: UserId) -> Option<()> {
+        self.user(l)?;
+        self.user(r)?;
+        self.friends.insert((l, r));
+        self.friends.insert((r, l));
+        Some(())
+    }
+    fn remove_friend(&mut self, l: UserId, r: UserId) -> Option<()> {
+        self.friends.remove(&(r, l));
+        if self.friends.remove(&(l, r)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+    fn get_friend_request(&self, s: UserId, r: UserId) -> Option<Option<FriendRequest>> {
+        Some(self.friend_requests.get(&(s, r)).cloned())
+    }
+    fn set_friend_request(
+        &mut self,
+        s: UserId,
+        r: UserId,
+        state: FriendRequestState,
+        at: DateTime<Utc>,
+    ) -> Option<FriendRequest> {
+        self.user(s)?;
+        self.user(r)?;
+        let req = FriendRequest {
+            from: s,
+            to: r,
+            state,
+            at,
+        };
+        self.friend_requests.insert((s, r), req.clone());
+        Some(req)
+    }
+    fn accept_friend_request(&mut self, s: UserId, r: UserId, at: DateTime<Utc>) -> Option<FriendRequest> {
+        let req = self
+            .friend_requests
+            .get_mut(&(s, r))
+            .filter(|req| req.state == FriendRequestState::Pending)?;
+        req.state = FriendRequestState::Accepted;
+        req.at = at;
+        let req = req.clone();
+        self.friends.insert((s, r));
+        self.friends.insert((r, s));
+        Some(req)
+    }
+    fn block_user(&mut self, u: UserId, other: UserId) -> Option<()> {
+        self.user(u)?;
+        self.user(other)?;
+        self.blocks.insert((u, other));
+        self.friends.remove(&(u, other));
+        self.friends.remove(&(other, u));
+        self.friend_requests.remove(&(u, other));
+        self.friend_requests.remove(&(other, u));
+        Some(())
+    }
+    fn unblock_user(&mut self, u: UserId, other: UserId) -> Option<()> {
+        if self.blocks.remove(&(u, other)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+    fn has_blocked(&self, u: UserId, other: UserId) -> Option<bool> {
+        Some(self.blocks.contains(&(u, other)))
+    }
+    fn get_blocked(&self, u: UserId) -> Option<Vec<UserId>> {
+        Some(
+            self.blocks
+                .iter()
+                .filter(|(l, _)| *l == u)
+                .map(|(_, r)| *r)
+                .collect(),
+        )
+    }
+    fn get_friend_requests(&self, u: UserId) -> Option<Vec<FriendRequest>> {
+        Some(
+            self.friend_requests
+                .values()
+                .filter(|req| req.state == FriendRequestState::Pending && (req.from == u || req.to == u))
+                .cloned()
+                .collect(),
+        )
+    }
+    fn create_group(
+        &mut self,
+        owner: UserId,
+        name: Option<String>,
+        motd: Option<String>,
+    ) -> Option<GroupRecord> {
+        self.user(owner)?;
+        self.groups.push(MemoryGroup {
+            name: name.clone(),
+            motd: motd.clone(),
+        });
+        let gid = GroupId::from(self.groups.len() as u32);
+        self.g_members.push((owner, gid, GroupRole::Owner));
+        Some(GroupRecord {
+            gid,
+            name,
+            motd,
+            members: vec![GroupMember {
+                uid: owner,
+                role: GroupRole::Owner,
+            }],
+        })
+    }
+    fn set_group_name(&mut self, g: GroupId, name: Option<String>) -> Option<()> {
+        self.group_mut(g)?.name = name;
+        Some(())
+    }
+    fn set_group_motd(&mut self, g: GroupId, motd: Option<String>) -> Option<()> {
+        self.group_mut(g)?.motd = motd;
+        Some(())
+    }
+    fn get_group_members(&self, g: GroupId) -> Option<Vec<UserId>> {
+        Some(
+            self.g_members
+                .iter()
+                .filter(|(_, gid, _)| *gid == g)
+                .map(|(uid, _, _)| *uid)
+                .collect(),
+        )
+    }
+    fn get_user_groups(&self, u: UserId) -> Option<Vec<GroupId>> {
+        Some(
+            self.g_members
+                .iter()
+                .filter(|(uid, _, _)| *uid == u)
+                .map(|(_, gid, _)| *gid)
+                .collect(),
+        )
+    }
+    fn is_group_member(&mut self, u: UserId, g: GroupId) -> Option<bool> {
+        Some(self.member_mut(u, g).is_some())
+    }
+    fn invite_to_group(&mut self, inviter: UserId, invitee: UserId, g: GroupId) -> Option<()> {
+        self.user(inviter)?;
+        self.user(invitee)?;
+        self.group(g)?;
+        self.g_invites.insert((g, invitee));
+        Some(())
+    }
+    fn join_group(&mut self, u: UserId, g: GroupId) -> Option<()> {
+        if self.g_bans.contains(&(g, u)) || !self.g_invites.remove(&(g, u)) {
+            // banned or not invited
+            return None;
+        }
+        if self.member_mut(u, g).is_none() {
+            self.g_members.push((u, g, GroupRole::Member));
+        }
+        Some(())
+    }
+    fn leave_group(&mut self, u: UserId, g: GroupId) -> Option<()> {
+        let before = self.g_members.len();
+        self.g_members
+            .retain(|(uid, gid, _)| *uid != u || *gid != g);
+        if self.g_members.len() == before {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_member_role(&mut self, u: UserId, g: GroupId) -> Option<Option<GroupRole>> {
+        Some(self.member_mut(u, g).map(|(_, _, role)| *role))
+    }
+    fn set_member_role(&mut self, u: UserId, g: GroupId, role: GroupRole) -> Option<()> {
+        self.member_mut(u, g)?.2 = role;
+        Some(())
+    }
+    fn transfer_ownership(&mut self, from: UserId, to: UserId, g: GroupId) -> Option<()> {
+        if from == to || self.get_member_role(from, g)?? != GroupRole::Owner {
+            return None;
+        }
+        self.member_mut(to, g)?.2 = GroupRole::Owner;
+        self.member_mut(from, g)?.2 = GroupRole::Admin;
+        Some(())
+    }
+    fn ban_from_group(&mut self, banner: UserId, u: UserId, g: GroupId) -> Option<()> {
+        self.user(banner)?;
+        self.user(u)?;
+        self.group(g)?;
+        self.g_members
+            .retain(|(uid, gid, _)| *uid != u || *gid != g);
+        self.g_invites.remove(&(g, u));
+        self.g_bans.insert((g, u));
+        Some(())
+    }
+    fn unban_from_group(&mut self, u: UserId, g: GroupId) -> Option<()> {
+        if self.g_bans.remove(&(g, u)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+    fn is_banned(&self, u: UserId, g: GroupId) -> Option<bool> {
+        Some(self.g_bans.contains(&(g, u)))
+    }
+    fn create_session(&mut self, rec: &SessionRecord) -> Option<()> {
+        let taken = self.sessions.values().any(|s| {
+            s.token_hash == rec.token_hash || s.refresh_hash == rec.refresh_hash
+        });
+        if taken || self.sessions.contains_key(&rec.session.sid) {
+            return None;
+        }
+        self.sessions.insert(rec.session.sid, rec.clone());
+        Some(())
+    }
+    fn rotate_session(&mut self, rec: &SessionRecord) -> Option<()> {
+        let stored = self.sessions.get_mut(&rec.session.sid)?;
+        stored.token_hash = rec.token_hash.clone();
+        stored.refresh_hash = rec.refresh_hash.clone();
+        stored.session.last_seen = rec.session.last_seen;
+        stored.session.expires = rec.session.expires;
+        stored.session.refresh_expires = rec.session.refresh_expires;
+        Some(())
+    }
+    fn touch_session(&mut self, sid: SessionId, last_seen: DateTime<Utc>, expires: DateTime<Utc>) -> Option<()> {
+        let stored = self.sessions.get_mut(&sid)?;
+        stored.session.last_seen = last_seen;
+        stored.session.expires = expires;
+        Some(())
+    }
+    fn delete_session(&mut self, sid: SessionId) -> Option<()> {
+        self.sessions.remove(&sid).map(|_| ())
+    }
+    fn delete_user_sessions(&mut self, u: UserId) -> Option<()> {
+        self.sessions.retain(|_, s| s.session.uid != u);
+        Some(())
+    }
+    fn load_sessions(&mut self, now: DateTime<Utc>) -> Option<Vec<SessionRecord>> {
+        self.sessions.retain(|_, s| s.session.refresh_expires > now);
+        Some(self.sessions.values().cloned().collect())
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    // argon2 is slow on purpose, so it runs with the tables unlocked
+    fn try_register(&self, req: RegisterRequest) -> Result<UserId, RegisterError> {
+        if self.lock().login_row(&req.email).is_some() {
+            return Err(RegisterError::UserAlreadyExists);
+        }
+        let hashed_pass = hash_password(&req.password_hash).ok_or(RegisterError::Unknown)?;
+        self.lock().add_user(req, hashed_pass)
+    }
+    fn try_login(&self, req: LoginRequest) -> Result<UserId, LoginError> {
+        debug!("storage: login request for {}", &req.email);
+        let row = self.lock().login_row(&req.email);
+        match row {
+            Some((uid, stored)) => verify_login(uid, stored, &req.password_hash, |hashed| {
+                self.lock().set_password(uid, hashed)
+            }),
+            None => Err(reject_unknown_login(&req.password_hash)),
+        }
+    }
+    fn new_message_u(
+        &self,
+        sender: UserId,
+        receiver: UserId,
+        msg: ClientMessage,
+    ) -> Option<PublicUserMessage> {
+        self.lock().new_message_u(sender, receiver, msg)
+    }
+    fn new_message_g(
+        &self,
+        sender: UserId,
+        group: GroupId,
+        msg: ClientMessage,
+    ) -> Option<PublicGroupMessage> {
+        self.lock().new_message_g(sender, group, msg)
+    }
+    fn get_user_data(&self, u: UserId, requester: Option<UserId>) -> Option<Option<PublicUserRecord>> {
+        self.lock().get_user_data(u, requester)
+    }
+    fn search_users(
+        &self,
+        alias: Option<&str>,
+        email: Option<&str>,
+        requester: Option<UserId>,
+        after: Option<UserId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserRecord>> {
+        self.lock().search_users(alias, email, requester, after, amt)
+    }
+    fn get_group_data(&self, g: GroupId) -> Option<GroupRecord> {
+        self.lock().get_group_data(g)
+    }
+    fn get_user_user_unread(&self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        self.lock().get_user_user_unread(s, r)
+    }
+    fn get_user_unread(&self, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        self.lock().get_user_unread(r)
+    }
+    fn get_user_group_unread(&self, s: UserId, g: GroupId) -> Option<Vec<PublicGroupMessage>> {
+        self.lock().get_user_group_unread(s, g)
+    }
+    fn get_user_history(
+        &self,
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserMessage>> {
+        self.lock().get_user_history(s, r, q, before, amt)
+    }
+    fn get_group_history(
+        &self,
+        s: UserId,
+        g: GroupId,
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        self.lock().get_group_history(s, g, q, before, amt)
+    }
+    fn flag_u_delivered(
+        &self,
+        umid: UserMessageId,
+        r: UserId,
+        device: SessionId,
+        at: DateTime<Utc>,
+    ) -> Option<(UserId, u32)> {
+        self.lock().flag_u_delivered(umid, r, device, at)
+    }
+    fn flag_u_read(&self, umid: UserMessageId, r: UserId, at: DateTime<Utc>) -> Option<UserId> {
+        self.lock().flag_u_read(umid, r, at)
+    }
+    fn flag_g_read(&self, g: GroupId, gmid: GroupMessageId, reader: UserId) -> Option<()> {
+        self.lock().flag_g_read(g, gmid, reader)
+    }
+    fn set_password(&self, u: UserId, hashed: HashedPassword) -> Option<()> {
+        self.lock().set_password(u, hashed)
+    }
+    fn get_status(&self, u: UserId) -> Option<(UserStatus, Option<String>)> {
+        self.lock().get_status(u)
+    }
+    fn set_status(&self, u: UserId, status: UserStatus, text: Option<String>) -> Option<()> {
+        self.lock().set_status(u, status, text)
+    }
+    fn set_profile(&self, u: UserId, update: &ProfileUpdate) -> Option<()> {
+        self.lock().set_profile(u, update)
+    }
+    fn get_friends(&self, u: UserId) -> Option<Vec<UserId>> {
+        self.lock().get_friends(u)
+    }
+    fn are_friends(&self, l: UserId, r: Option<UserId>) -> Option<bool> {
+        self.lock().are_friends(l, r)
+    }
+    fn add_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        self.lock().add_friend(l, r)
+    }
+    fn remove_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        self.lock().remove_friend(l, r)
+    }
+    fn get_friend_request(&self, s: UserId, r: UserId) -> Option<Option<FriendRequest>> {
+        self.lock().get_friend_request(s, r)
+    }
+    fn set_friend_request(
+        &self,
+        s: UserId,
+        r: UserId,
+        state: FriendRequestState,
+        at: DateTime<Utc>,
+    ) -> Option<FriendRequest> {
+        self.lock().set_friend_request(s, r, state, at)
+    }
+    fn accept_friend_request(&self, s: UserId, r: UserId, at: DateTime<Utc>) -> Option<FriendRequest> {
+        self.lock().accept_friend_request(s, r, at)
+    }
+    fn get_friend_requests(&self, u: UserId) -> Option<Vec<FriendRequest>> {
+        self.lock().get_friend_requests(u)
+    }
+    fn block_user(&self, u: UserId, other: UserId) -> Option<()> {
+        self.lock().block_user(u, other)
+    }
+    fn unblock_user(&self, u: UserId, other: UserId) -> Option<()> {
+        self.lock().unblock_user(u, other)
+    }
+    fn has_blocked(&self, u: UserId, other: UserId) -> Option<bool> {
+        self.lock().has_blocked(u, other)
+    }
+    fn get_blocked(&self, u: UserId) -> Option<Vec<UserId>> {
+        self.lock().get_blocked(u)
+    }
+    fn create_group(
+        &self,
+        owner: UserId,
+        name: Option<String>,
+        motd: Option<String>,
+    ) -> Option<GroupRecord> {
+        self.lock().create_group(owner, name, motd)
+    }
+    fn set_group_name(&self, g: GroupId, name: Option<String>) -> Option<()> {
+        self.lock().set_group_name(g, name)
+    }
+    fn set_group_motd(&self, g: GroupId, motd: Option<String>) -> Option<()> {
+        self.lock().set_group_motd(g, motd)
+    }
+    fn get_group_members(&self, g: GroupId) -> Option<Vec<UserId>> {
+        self.lock().get_group_members(g)
+    }
+    fn get_user_groups(&self, u: UserId) -> Option<Vec<GroupId>> {
+        self.lock().get_user_groups(u)
+    }
+    fn is_group_member(&self, u: UserId, g: GroupId) -> Option<bool> {
+        self.lock().is_group_member(u, g)
+    }
+    fn invite_to_group(&self, inviter: UserId, invitee: UserId, g: GroupId) -> Option<()> {
+        self.lock().invite_to_group(inviter, invitee, g)
+    }
+    fn join_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        self.lock().join_group(u, g)
+    }
+    fn leave_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        self.lock().leave_group(u, g)
+    }
+    fn get_member_role(&self, u: UserId, g: GroupId) -> Option<Option<GroupRole>> {
+        self.lock().get_member_role(u, g)
+    }
+    fn set_member_role(&self, u: UserId, g: GroupId, role: GroupRole) -> Option<()> {
+        self.lock().set_member_role(u, g, role)
+    }
+    fn transfer_ownership(&self, from: UserId, to: UserId, g: GroupId) -> Option<()> {
+        self.lock().transfer_ownership(from, to, g)
+    }
+    fn ban_from_group(&self, banner: UserId, u: UserId, g: GroupId) -> Option<()> {
+        self.lock().ban_from_group(banner, u, g)
+    }
+    fn unban_from_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        self.lock().unban_from_group(u, g)
+    }
+    fn is_banned(&self, u: UserId, g: GroupId) -> Option<bool> {
+        self.lock().is_banned(u, g)
+    }
+    fn create_session(&self, rec: &SessionRecord) -> Option<()> {
+        self.lock().create_session(rec)
+    }
+    fn rotate_session(&self, rec: &SessionRecord) -> Option<()> {
+        self.lock().rotate_session(rec)
+    }
+    fn touch_session(&self, sid: SessionId, last_seen: DateTime<Utc>, expires: DateTime<Utc>) -> Option<()> {
+        self.lock().touch_session(sid, last_seen, expires)
+    }
+    fn delete_session(&self, sid: SessionId) -> Option<()> {
+        self.lock().delete_session(sid)
+    }
+    fn delete_user_sessions(&self, u: UserId) -> Option<()> {
+        self.lock().delete_user_sessions(u)
+    }
+    fn load_sessions(&self, now: DateTime<Utc>) -> Option<Vec<SessionRecord>> {
+        self.lock().load_sessions(now)
+    }
+}
@@ -0,0 +1,1283 @@
+use crate::imports::*;
+use crate::symbols::*;
+use mysql::*;
+
+/// Longest wait between two checkout attempts, however many failed before.
+const RECONNECT_DELAY_MAX_MS: u64 = 5000;
+
+/// How long to wait before checkout attempt `attempt`, counting retries from 1.
+pub(super) fn reconnect_delay(attempt: u32) -> Duration {
+    let ms = 2u64
+        .checked_pow(attempt)
+        .and_then(|factor| factor.checked_mul(100))
+        .unwrap_or(RECONNECT_DELAY_MAX_MS);
+    Duration::from_millis(ms.min(RECONNECT_DELAY_MAX_MS))
+}
+
+/// MySQL storage backend. Every operation checks out its own connection from the pool.
+pub struct MysqlStorage {
+    pool: mysql::Pool,
+    tx_opts: mysql::TxOpts,
+    pc: DbPoolConfig,
+}
+
+impl MysqlStorage {
+    /// Create a new mysql storage container.
+    pub fn new(
+        sql_addr: &str,
+        pc: &DbPoolConfig,
+    ) -> std::result::Result<MysqlStorage, StorageError> {
+        let opts: mysql::Opts = OptsBuilder::from_opts(
+            mysql::Opts::from_url(sql_addr).map_err(mysql::Error::from)?,
+        )
+        .tcp_connect_timeout(Some(Duration::from_millis(pc.connect_timeout_ms)))
+        .into();
+        // the database has to exist before pooled connections can select it
+        mysql::Conn::new(opts.clone())?.run_text(Q_CREATE_YAP)?;
+        let pool = mysql::Pool::new_manual(
+            pc.min,
+            pc.max,
+            OptsBuilder::from_opts(opts).db_name(Some(YAP_DB_NAME)),
+        )?;
+        let tx_opts = mysql::TxOpts::default();
+        MysqlStorage::init(&mut pool.get_conn()?, tx_opts)?;
+        Ok(MysqlStorage {
+            pool,
+            tx_opts,
+            pc: pc.clone(),
+        })
+    }
+    /**
+    Check out a connection for one operation.
+
+    The pool pings connections before handing them out and reconnects dead ones,
+    so a restarted database is picked up again. If the database is unreachable,
+    checking out is retried a few times with growing delays before giving up.
+    */
+    fn conn(&self) -> Result<mysql::PooledConn> {
+        let mut attempt = 0;
+        loop {
+            match self.pool.try_get_conn(self.pc.checkout_timeout_ms) {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt < self.pc.reconnect_attempts => {
+                    attempt += 1;
+                    warn!("storage: mysql checkout failed ({}), retry {}", e, attempt);
+                    std::thread::sleep(reconnect_delay(attempt));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Initialize the storage in case it hasn't been set up, then bring the schema up to date.
+    fn init(
+        conn: &mut mysql::PooledConn,
+        tx_opts: mysql::TxOpts,
+    ) -> std::result::Result<(), StorageError> {
+        conn.run_text(Q_CREATE_SCHEMA_VERSION)?;
+        let current = conn
+            .first_text::<Option<u32>>(Q_GET_SCHEMA_VERSION)?
+            .flatten()
+            .unwrap_or(0);
+        for m in pending_migrations(current, MYSQL_MIGRATIONS)? {
+            info!("storage: applying migration {} ({})", m.version, m.name);
+            // DDL commits implicitly in mysql, so a failed migration may be partially applied.
+            let mut tx = conn.start_transaction(tx_opts)?;
+            for q in m.up {
+                tx.run_text(*q)?;
+            }
+            tx.run(
+                Q_SET_SCHEMA_VERSION,
+                params! {
+                    "version" => m.version,
+                    "name" => m.name
+                },
+            )?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for MysqlStorage {
+    /**
+    Try to register a new user.
+    Will fail if
+    - user already exists
+    - db error
+    */
+    fn try_register(
+        &self,
+        req: RegisterRequest,
+    ) -> std::result::Result<UserId, RegisterError> {
+        // hashing is slow on purpose, so do it before holding a connection
+        let hashed_pass = hash_password(&req.password_hash).ok_or(RegisterError::Unknown)?;
+        let mut conn = self.conn().map_err(RegisterError::from)?;
+        let mut tx = conn
+            .start_transaction(self.tx_opts)
+            .map_err(RegisterError::from)?;
+        // try to get associated userid from email
+        let existing = tx
+            .first::<u32, _>(
+                sql!("SELECT uid FROM u WHERE email = :email;"),
+                params! {"email" => &req.email},
+            )
+            .map_err(RegisterError::from)?;
+        if existing.is_some() {
+            // user already exists
+            Err(RegisterError::UserAlreadyExists)
+        } else {
+            // new user
+            tx.run(
+                sql!("INSERT into u
+            (email, pubkey, hashed_pass, friends, groups, status, visibility) VALUES
+            (:email, :pubkey, :hashed_pass, :friends, :groups, :status, :visibility);
+            "),
+                params! {
+                    "email" => req.email,
+                    "pubkey" => req.pubkey,
+                    "hashed_pass" => hashed_pass.as_str(),
+                    "friends" => serde_json::to_string::<[UserId]>(&[]).unwrap(),
+                    "groups" => serde_json::to_string::<[UserId]>(&[]).unwrap(),
+                    "status" => serde_json::to_string(&UserStatus::default()).unwrap(),
+                    "visibility" => serde_json::to_string(&UserVisibility::default()).unwrap()
+                },
+            )
+            .map_err(RegisterError::from)?;
+            if let Some(cid) = tx.last_insert_id() {
+                tx.commit().map_err(RegisterError::from)?;
+                Ok((cid as u32).into())
+            } else {
+                Err(RegisterError::Unknown)
+            }
+        }
+    }
+    /**
+    Try to login.
+    Will fail if
+    - invalid password
+    - invalid email
+    - db error
+    */
+    fn try_login(&self, req: LoginRequest) -> std::result::Result<UserId, LoginError> {
+        debug!("storage: login request for {}", &req.email);
+        // the connection goes back to the pool before a rehash needs one
+        let row = self
+            .conn()
+            .map_err(LoginError::from)?
+            .first::<(u32, String), _>(
+                sql!("SELECT uid, hashed_pass FROM u WHERE email = :email;"),
+                params! {
+                    "email" => &req.email
+                },
+            )
+            .map_err(LoginError::from)?;
+        match row {
+            Some((ref_uid, ref_pass)) => {
+                debug!("storage: login uid pass found");
+                let uid = UserId::from(ref_uid);
+                verify_login(uid, HashedPassword::from(ref_pass), &req.password_hash, |hashed| {
+                    self.set_password(uid, hashed)
+                })
+            }
+            None => {
+                debug!("storage: login unknown email");
+                Err(reject_unknown_login(&req.password_hash))
+            }
+        }
+    }
+    /**
+    Post a new message destined for a user. **Does not flag message as read.**
+    */
+    fn new_message_u(
+        &self,
+        sender: UserId,
+        receiver: UserId,
+        msg: ClientMessage,
+    ) -> Option<PublicUserMessage> {
+        if self.has_blocked(receiver, sender)? {
+            return None;
+        }
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("INSERT INTO u_message (sender_id, receiver_id, msg_content, time_posted, r)
+        VALUES (:sender_id, :receiver_id, :msg_content, :time_posted, :r);"),
+            params! {
+                "sender_id" => sender.to_string(),
+                "receiver_id" => receiver.to_string(),
+                "msg_content" => msg.to_string(),
+                "time_posted" => DateTime::<Utc>::from(SystemTime::now()).naive_utc(),
+                "r" => false
+            },
+        )
+        .ok()?;
+        match tx.last_insert_id().map(UserMessageId::from) {
+            Some(res) => {
+                let echo_msg = tx
+                    .first(
+                        sql!("SELECT * FROM u_message WHERE umid = :umid"),
+                        params! {"umid" => res.to_string()},
+                    )
+                    .ok()?
+                    .and_then(PublicUserMessage::from_sql_tup)?;
+                tx.commit().ok()?;
+                Some(echo_msg)
+            }
+            None => None,
+        }
+    }
+    /**
+    Post a new message destined for a group. **Does not flag message as read.**
+    */
+    fn new_message_g(
+        &self,
+        sender: UserId,
+        group: GroupId,
+        msg: ClientMessage,
+    ) -> Option<PublicGroupMessage> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("INSERT INTO g_message (sender_id, gid, msg_content, time_posted)
+        VALUES (:sender_id, :gid, :msg_content, :time_posted);"),
+            params! {
+                "sender_id" => sender.into_sql(),
+                "gid" => group.into_sql(),
+                "msg_content" => msg.to_string(),
+                "time_posted" => DateTime::<Utc>::from(SystemTime::now()).naive_utc()
+            },
+        )
+        .ok()?;
+        match tx.last_insert_id().map(GroupMessageId::from) {
+            Some(res) => {
+                let echo_msg = tx
+                    .first(
+                        sql!("SELECT * FROM g_message WHERE gmid = :gmid"),
+                        params! {"gmid" => res.into_sql()},
+                    )
+                    .ok()?
+                    .and_then(PublicGroupMessage::from_sql_tup)?;
+                tx.commit().ok()?;
+                Some(echo_msg)
+            }
+            None => None,
+        }
+    }
+    /**
+    Get a user profile. Returns a `Serialize` public-facing version.
+    */
+    fn get_user_data(
+        &self,
+        u: UserId,
+        requester: Option<UserId>,
+    ) -> Option<Option<PublicUserRecord>> {
+        let are_friends = self.are_friends(u, requester)?;
+        let blocked = match requester {
+            Some(r) => self.has_blocked(u, r)?,
+            None => false,
+        };
+        // memberships live in g_member and u_friend, not the legacy columns
+        let groups = self.get_user_groups(u)?;
+        let friends = self.get_friends(u)?;
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        match tx
+            .first::<SqlUserRecord, _>(
+                sql!("SELECT * FROM u WHERE uid = :uid;"),
+                params! {"uid" => u.into_sql()},
+            )
+            .ok()?
+        {
+            Some(res) => {
+                let mut ur = UserRecord::from_sql_tup(res)?;
+                ur.groups = groups;
+                ur.friends = friends;
+                let mask_lvl = mask_level(u, requester, are_friends, blocked, &ur.visibility);
+                Some(Some(ur.mask(mask_lvl)))
+            }
+            None => Some(None),
+        }
+    }
+    /// Find users by alias prefix or email, leaving out those `requester` may not discover.
+    fn search_users(
+        &self,
+        alias: Option<&str>,
+        email: Option<&str>,
+        requester: Option<UserId>,
+        after: Option<UserId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserRecord>> {
+        let found = self
+            .conn()
+            .ok()?
+            .all::<SqlUserRecord, _>(
+                Q_SEARCH_USERS,
+                params! {
+                    "alias" => alias.map(like_prefix),
+                    "email" => email,
+                    "after" => after.map(u32::from),
+                    "public" => serde_json::to_string(&UserVisibility::Public).ok()?,
+                    "friends_only" => serde_json::to_string(&UserVisibility::FriendsOnly).ok()?,
+                    "requester" => requester.map(u32::from),
+                    "amt" => amt
+                },
+            )
+            .ok()?;
+        found
+            .into_iter()
+            .map(|tup| mask_search_hit(tup, requester))
+            .collect()
+    }
+    /// Get a group profile.
+    fn get_group_data(&self, g: GroupId) -> Option<GroupRecord> {
+        let mut conn = self.conn().ok()?;
+        let (name, motd) = conn
+            .first::<(Option<String>, Option<String>), _>(
+                sql!("SELECT name, motd FROM g WHERE gid = :gid;"),
+                params! {"gid" => g.into_sql()},
+            )
+            .ok()??;
+        let members = conn
+            .all::<(u32, u8), _>(
+                sql!("SELECT uid, role FROM g_member WHERE gid = :gid;"),
+                params! {"gid" => g.into_sql()},
+            )
+            .ok()?
+            .into_iter()
+            .map(|(uid, role)| {
+                Some(GroupMember {
+                    uid: UserId::from(uid),
+                    role: GroupRole::try_from(role).ok()?,
+                })
+            })
+            .collect::<Option<Vec<GroupMember>>>()?;
+        Some(GroupRecord {
+            gid: g,
+            name,
+            motd,
+            members,
+        })
+    }
+    /**
+    Get unread messages a user hasn't read *from a user*.
+    Primary purpose is for the client to catch up.
+    **Will not flag messages as read.**
+    */
+    fn get_user_user_unread(&self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        let res = tx
+            .all::<SqlUserMessage, _>(
+                sql!("SELECT * FROM u_message WHERE
+        sender_id = :sender_id AND
+        receiver_id = :receiver_id AND
+        r = FALSE;"),
+                params! {
+                    "sender_id" => s.into_sql(),
+                    "receiver_id" => r.into_sql()
+                },
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect();
+        res
+    }
+    /**
+    Get every message sent to a user that they haven't read.
+    Primary purpose is catching up a device that just connected.
+    **Will not flag messages as read.**
+    */
+    fn get_user_unread(&self, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let mut conn = self.conn().ok()?;
+        let res = conn
+            .all::<SqlUserMessage, _>(
+                sql!("SELECT * FROM u_message WHERE receiver_id = :receiver_id AND r = 0 ORDER BY umid;"),
+                params! {"receiver_id" => r.into_sql()},
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect();
+        res
+    }
+    /**
+    Get unread messages a user hasn't read *from a group*.
+    Primary purpose is for the client to catch up.
+    **Will not flag messages as read.**
+    */
+    fn get_user_group_unread(
+        &self,
+        s: UserId,
+        g: GroupId,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let mut conn = self.conn().ok()?;
+        let res = conn
+            .all::<SqlGroupMessage, _>(
+                sql!("SELECT * FROM g_message m WHERE
+        m.gid = :gid AND
+        m.sender_id <> :uid AND
+        NOT EXISTS (SELECT 1 FROM g_message_read r WHERE r.gmid = m.gmid AND r.reader_id = :uid)
+        ORDER BY m.gmid;"),
+                params! {
+                    "gid" => g.into_sql(),
+                    "uid" => s.into_sql()
+                },
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect();
+        res
+    }
+    /// Get a page of the conversation between two users, newest first.
+    fn get_user_history(
+        &self,
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let mut conn = self.conn().ok()?;
+        let res = conn
+            .all::<SqlUserMessage, _>(
+                Q_GET_USER_HISTORY,
+                params! {
+                    "s" => s.into_sql(),
+                    "r" => r.into_sql(),
+                    "before" => before.map(u64::from),
+                    "from" => from,
+                    "to" => to,
+                    "unseen" => unseen,
+                    "amt" => amt
+                },
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect();
+        res
+    }
+    /// Get a page of a group's messages, newest first.
+    fn get_group_history(
+        &self,
+        s: UserId,
+        g: GroupId,
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let mut conn = self.conn().ok()?;
+        let res = conn
+            .all::<SqlGroupMessage, _>(
+                Q_GET_GROUP_HISTORY,
+                params! {
+                    "gid" => g.into_sql(),
+                    "uid" => s.into_sql(),
+                    "before" => before.map(u64::from),
+                    "from" => from,
+                    "to" => to,
+                    "unseen" => unseen,
+                    "amt" => amt
+                },
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect();
+        res
+    }
+    /// Record a device's delivery confirmation. Returns the sender and device count the first time per device.
+    fn flag_u_delivered(
+        &self,
+        umid: UserMessageId,
+        r: UserId,
+        device: SessionId,
+        at: DateTime<Utc>,
+    ) -> Option<(UserId, u32)> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        let sender = tx
+            .first::<u32, _>(
+                sql!("SELECT sender_id FROM u_message WHERE umid = :umid AND receiver_id = :r;"),
+                params! {
+                    "umid" => umid.clone().into_sql(),
+                    "r" => r.into_sql()
+                },
+            )
+            .ok()??;
+        tx.run(
+            sql!("INSERT IGNORE INTO u_message_delivery (umid, sid, at) VALUES (:umid, :sid, :at);"),
+            params! {
+                "umid" => umid.clone().into_sql(),
+                "sid" => device.into_sql(),
+                "at" => at.naive_utc()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            // this device already confirmed it
+            return None;
+        }
+        tx.run(
+            sql!("UPDATE u_message SET delivered = :at WHERE umid = :umid AND delivered IS NULL;"),
+            params! {
+                "at" => at.naive_utc(),
+                "umid" => umid.clone().into_sql()
+            },
+        )
+        .ok()?;
+        let devices = tx
+            .first::<u32, _>(
+                sql!("SELECT COUNT(*) FROM u_message_delivery WHERE umid = :umid;"),
+                params! {"umid" => umid.into_sql()},
+            )
+            .ok()??;
+        tx.commit().ok()?;
+        Some((UserId::from(sender), devices))
+    }
+    /// Flag a user message as read by its receiver. Returns the sender if it wasn't read before.
+    fn flag_u_read(&self, umid: UserMessageId, r: UserId, at: DateTime<Utc>) -> Option<UserId> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("UPDATE u_message SET r = TRUE, read_at = :at, delivered = COALESCE(delivered, :at)
+        WHERE umid = :umid AND receiver_id = :r AND r = FALSE;"),
+            params! {
+                "at" => at.naive_utc(),
+                "umid" => umid.clone().into_sql(),
+                "r" => r.into_sql()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            return None;
+        }
+        let sender = tx
+            .first::<u32, _>(
+                sql!("SELECT sender_id FROM u_message WHERE umid = :umid;"),
+                params! {"umid" => umid.into_sql()},
+            )
+            .ok()??;
+        tx.commit().ok()?;
+        Some(UserId::from(sender))
+    }
+    /// Flag a group message as read.
+    fn flag_g_read(&self, g: GroupId, gmid: GroupMessageId, reader: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        let posted: bool = tx
+            .first(
+                sql!("SELECT EXISTS (SELECT 1 FROM g_message WHERE gmid = :gmid AND gid = :gid);"),
+                params! {
+                    "gmid" => gmid.into_sql(),
+                    "gid" => g.into_sql()
+                },
+            )
+            .ok()??;
+        if !posted {
+            return None;
+        }
+        tx.run(
+            sql!("INSERT IGNORE INTO g_message_read (gmid, reader_id) VALUES (:gmid, :reader_id);"),
+            params! {
+                "gmid" => gmid.into_sql(),
+                "reader_id" => reader.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Replace a user's stored password.
+    fn set_password(&self, u: UserId, hashed: HashedPassword) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("UPDATE u SET hashed_pass = :hashed_pass WHERE uid = :uid;"),
+            params! {
+                "hashed_pass" => hashed.as_str(),
+                "uid" => u.into_sql()
+            },
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    /// Get the status a user picked, and their status text.
+    fn get_status(&self, u: UserId) -> Option<(UserStatus, Option<String>)> {
+        let mut conn = self.conn().ok()?;
+        let (status, text) = conn
+            .first::<(String, Option<String>), _>(
+                sql!("SELECT status, status_text FROM u WHERE uid = :uid;"),
+                params! {"uid" => u.into_sql()},
+            )
+            .ok()??;
+        Some((serde_json::from_str(&status).ok()?, text))
+    }
+    /// Set the status a user picked, and their status text.
+    fn set_status(&self, u: UserId, status: UserStatus, text: Option<String>) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        // `affected_rows` leaves out rows that already held these values, so look for the user first
+        tx.first::<u32, _>(
+            sql!("SELECT uid FROM u WHERE uid = :uid FOR UPDATE;"),
+            params! {"uid" => u.into_sql()},
+        )
+        .ok()??;
+        tx.run(
+            sql!("UPDATE u SET status = :status, status_text = :status_text WHERE uid = :uid;"),
+            params! {
+                "status" => serde_json::to_string(&status).ok()?,
+                "status_text" => text,
+                "uid" => u.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Apply the fields of `update` that are set. Nothing is checked against column limits.
+    fn set_profile(&self, u: UserId, update: &ProfileUpdate) -> Option<()> {
+        let visibility = match &update.visibility {
+            Some(v) => Some(serde_json::to_string(v).ok()?),
+            None => None,
+        };
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        // as in `set_status`
+        tx.first::<u32, _>(
+            sql!("SELECT uid FROM u WHERE uid = :uid FOR UPDATE;"),
+            params! {"uid" => u.into_sql()},
+        )
+        .ok()??;
+        tx.run(
+            sql!("UPDATE u SET
+                alias = CASE WHEN :set_alias THEN :alias ELSE alias END,
+                motd = CASE WHEN :set_motd THEN :motd ELSE motd END,
+                visibility = COALESCE(:visibility, visibility),
+                pubkey = COALESCE(:pubkey, pubkey)
+                WHERE uid = :uid;"),
+            params! {
+                "set_alias" => update.alias.is_some(),
+                "alias" => update.alias.clone().flatten(),
+                "set_motd" => update.motd.is_some(),
+                "motd" => update.motd.clone().flatten(),
+                "visibility" => visibility,
+                "pubkey" => update.pubkey.clone(),
+                "uid" => u.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Get a user's friends.
+    fn get_friends(&self, u: UserId) -> Option<Vec<UserId>> {
+        let mut conn = self.conn().ok()?;
+        conn.all::<u32, _>(
+            sql!("SELECT r FROM u_friend WHERE l = :l;"),
+            params! {"l" => u.into_sql()},
+        )
+        .ok()
+        .map(|rows| rows.into_iter().map(UserId::from).collect())
+    }
+    /// Check if two users are friends.
+    /// Assumes `(l, r)` and `(r, l)` were added on entry.
+    fn are_friends(&self, l: UserId, r: Option<UserId>) -> Option<bool> {
+        match r {
+            Some(r) => {
+                let mut conn = self.conn().ok()?;
+                conn.first::<bool, _>(
+                    sql!("SELECT EXISTS (SELECT 1 FROM u_friend WHERE l = :l AND r = :r);"),
+                    params! {
+                        "l" => l.into_sql(),
+                        "r" => r.into_sql()
+                    },
+                )
+                .ok()?
+            }
+            None => Some(false),
+        }
+    }
+    /**
+    Add two users as friends.
+    No manual validation of whether the uids are valid is done, as in the database should handle it because of foreign key relations.
+    */
+    fn add_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("INSERT INTO u_friend (l, r) VALUES (:l, :r), (:r, :l);"),
+            params! {
+                "l" => l.into_sql(),
+                "r" => r.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /**
+    Remove a friend pairing.
+    No manual validation of whether the uids are valid is done, as in the database should handle it because of foreign key relations.
+    */
+    fn remove_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM u_friend WHERE (l = :l AND r = :r) OR (l = :r AND r = :l);"),
+            params! {
+                "l" => l.into_sql(),
+                "r" => r.into_sql()
+            },
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    /// Get the request `s` sent to `r`, if any, whatever its state.
+    fn get_friend_request(&self, s: UserId, r: UserId) -> Option<Option<FriendRequest>> {
+        let mut conn = self.conn().ok()?;
+        match conn
+            .first::<SqlFriendRequest, _>(
+                sql!("SELECT sender_id, receiver_id, state, at FROM u_friend_request
+            WHERE sender_id = :s AND receiver_id = :r;"),
+                params! {
+                    "s" => s.into_sql(),
+                    "r" => r.into_sql()
+                },
+            )
+            .ok()?
+        {
+            Some(row) => FriendRequest::from_sql_tup(row).map(Some),
+            None => Some(None),
+        }
+    }
+    /// Send a request, or change the state of one, replacing whatever was there.
+    fn set_friend_request(
+        &self,
+        s: UserId,
+        r: UserId,
+        state: FriendRequestState,
+        at: DateTime<Utc>,
+    ) -> Option<FriendRequest> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("INSERT INTO u_friend_request (sender_id, receiver_id, state, at)
+        VALUES (:s, :r, :state, :at)
+        ON DUPLICATE KEY UPDATE state = VALUES(state), at = VALUES(at);"),
+            params! {
+                "s" => s.into_sql(),
+                "r" => r.into_sql(),
+                "state" => state.into_sql(),
+                "at" => at.naive_utc()
+            },
+        )
+        .ok()?;
+        Some(FriendRequest {
+            from: s,
+            to: r,
+            state,
+            at,
+        })
+    }
+    /// Accept a pending request from `s` to `r`, making them friends at the same time.
+    fn accept_friend_request(&self, s: UserId, r: UserId, at: DateTime<Utc>) -> Option<FriendRequest> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("UPDATE u_friend_request SET state = :accepted, at = :at
+        WHERE sender_id = :s AND receiver_id = :r AND state = :pending;"),
+            params! {
+                "accepted" => FriendRequestState::Accepted.into_sql(),
+                "pending" => FriendRequestState::Pending.into_sql(),
+                "at" => at.naive_utc(),
+                "s" => s.into_sql(),
+                "r" => r.into_sql()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            return None;
+        }
+        tx.run(
+            sql!("INSERT INTO u_friend (l, r) VALUES (:l, :r), (:r, :l);"),
+            params! {
+                "l" => s.into_sql(),
+                "r" => r.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()?;
+        Some(FriendRequest {
+            from: s,
+            to: r,
+            state: FriendRequestState::Accepted,
+            at,
+        })
+    }
+    /// Get pending requests sent or received by a user.
+    fn get_friend_requests(&self, u: UserId) -> Option<Vec<FriendRequest>> {
+        let mut conn = self.conn().ok()?;
+        let rows = conn
+            .all::<SqlFriendRequest, _>(
+                sql!("SELECT sender_id, receiver_id, state, at FROM u_friend_request
+            WHERE (sender_id = :u OR receiver_id = :u) AND state = :pending ORDER BY at;"),
+                params! {
+                    "u" => u.into_sql(),
+                    "pending" => FriendRequestState::Pending.into_sql()
+                },
+            )
+            .ok()?;
+        rows.into_iter().map(FriendRequest::from_sql_tup).collect()
+    }
+    /// Block `other` for `u`. Also ends their friendship and drops requests either way.
+    fn block_user(&self, u: UserId, other: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("INSERT IGNORE INTO u_block (uid, blocked) VALUES (:u, :other);"),
+            params! {"u" => u.into_sql(), "other" => other.into_sql()},
+        )
+        .ok()?;
+        tx.run(
+            sql!("DELETE FROM u_friend WHERE (l = :u AND r = :other) OR (l = :other AND r = :u);"),
+            params! {"u" => u.into_sql(), "other" => other.into_sql()},
+        )
+        .ok()?;
+        tx.run(
+            sql!("DELETE FROM u_friend_request WHERE (sender_id = :u AND receiver_id = :other)
+        OR (sender_id = :other AND receiver_id = :u);"),
+            params! {"u" => u.into_sql(), "other" => other.into_sql()},
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Fails if `other` wasn't blocked.
+    fn unblock_user(&self, u: UserId, other: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM u_block WHERE uid = :u AND blocked = :other;"),
+            params! {"u" => u.into_sql(), "other" => other.into_sql()},
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    /// Check if `u` has blocked `other`.
+    fn has_blocked(&self, u: UserId, other: UserId) -> Option<bool> {
+        let mut conn = self.conn().ok()?;
+        conn.first::<bool, _>(
+            sql!("SELECT EXISTS (SELECT 1 FROM u_block WHERE uid = :u AND blocked = :other);"),
+            params! {"u" => u.into_sql(), "other" => other.into_sql()},
+        )
+        .ok()?
+    }
+    /// Get the users `u` has blocked.
+    fn get_blocked(&self, u: UserId) -> Option<Vec<UserId>> {
+        let mut conn = self.conn().ok()?;
+        conn.all::<u32, _>(
+            sql!("SELECT blocked FROM u_block WHERE uid = :u;"),
+            params! {"u" => u.into_sql()},
+        )
+        .ok()
+        .map(|rows| rows.into_iter().map(UserId::from).collect())
+    }
+    /// Create a group with `owner` as its first member.
+    fn create_group(
+        &self,
+        owner: UserId,
+        name: Option<String>,
+        motd: Option<String>,
+    ) -> Option<GroupRecord> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("INSERT INTO g (name, motd) VALUES (:name, :motd);"),
+            params! {"name" => &name, "motd" => &motd},
+        )
+        .ok()?;
+        let gid = GroupId::from(tx.last_insert_id()? as u32);
+        tx.run(
+            sql!("INSERT INTO g_member (uid, gid, role) VALUES (:uid, :gid, :role);"),
+            params! {
+                "uid" => owner.into_sql(),
+                "gid" => gid.into_sql(),
+                "role" => GroupRole::Owner.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()?;
+        Some(GroupRecord {
+            gid,
+            name,
+            motd,
+            members: vec![GroupMember {
+                uid: owner,
+                role: GroupRole::Owner,
+            }],
+        })
+    }
+    /// Rename a group.
+    fn set_group_name(&self, g: GroupId, name: Option<String>) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        // `affected_rows` leaves out rows that already held these values, so look for the group first
+        tx.first::<u32, _>(
+            sql!("SELECT gid FROM g WHERE gid = :gid FOR UPDATE;"),
+            params! {"gid" => g.into_sql()},
+        )
+        .ok()??;
+        tx.run(
+            sql!("UPDATE g SET name = :name WHERE gid = :gid;"),
+            params! {
+                "name" => name,
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Change the motd of a group.
+    fn set_group_motd(&self, g: GroupId, motd: Option<String>) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        // as in `set_group_name`
+        tx.first::<u32, _>(
+            sql!("SELECT gid FROM g WHERE gid = :gid FOR UPDATE;"),
+            params! {"gid" => g.into_sql()},
+        )
+        .ok()??;
+        tx.run(
+            sql!("UPDATE g SET motd = :motd WHERE gid = :gid;"),
+            params! {
+                "motd" => motd,
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Get the members of a group.
+    fn get_group_members(&self, g: GroupId) -> Option<Vec<UserId>> {
+        let mut conn = self.conn().ok()?;
+        conn.all::<u32, _>(
+            sql!("SELECT uid FROM g_member WHERE gid = :gid;"),
+            params! {"gid" => g.into_sql()},
+        )
+        .ok()
+        .map(|rows| rows.into_iter().map(UserId::from).collect())
+    }
+    /// Get the groups a user is a member of.
+    fn get_user_groups(&self, u: UserId) -> Option<Vec<GroupId>> {
+        let mut conn = self.conn().ok()?;
+        conn.all::<u32, _>(
+            sql!("SELECT gid FROM g_member WHERE uid = :uid;"),
+            params! {"uid" => u.into_sql()},
+        )
+        .ok()
+        .map(|rows| rows.into_iter().map(GroupId::from).collect())
+    }
+    /// Check if a user is a member of a group.
+    fn is_group_member(&self, u: UserId, g: GroupId) -> Option<bool> {
+        let mut conn = self.conn().ok()?;
+        conn.first(
+            sql!("SELECT EXISTS (SELECT 1 FROM g_member WHERE uid = :uid AND gid = :gid);"),
+            params! {
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?
+    }
+    /// Record an invite for `invitee` to join `g`. Inviting twice is not an error.
+    fn invite_to_group(&self, inviter: UserId, invitee: UserId, g: GroupId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("INSERT IGNORE INTO g_invite (gid, uid, inviter) VALUES (:gid, :uid, :inviter);"),
+            params! {
+                "gid" => g.into_sql(),
+                "uid" => invitee.into_sql(),
+                "inviter" => inviter.into_sql()
+            },
+        )
+        .ok()
+    }
+    /// Join a group. Fails unless `u` has a pending invite, which is used up.
+    fn join_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        if self.is_banned(u, g)? {
+            return None;
+        }
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("DELETE FROM g_invite WHERE gid = :gid AND uid = :uid;"),
+            params! {
+                "gid" => g.into_sql(),
+                "uid" => u.into_sql()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            // not invited
+            return None;
+        }
+        tx.run(
+            sql!("INSERT IGNORE INTO g_member (uid, gid) VALUES (:uid, :gid);"),
+            params! {
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Leave a group.
+    fn leave_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM g_member WHERE uid = :uid AND gid = :gid;"),
+            params! {
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    /// Get the role of a member. The inner `None` means `u` isn't a member.
+    fn get_member_role(&self, u: UserId, g: GroupId) -> Option<Option<GroupRole>> {
+        let mut conn = self.conn().ok()?;
+        match conn
+            .first::<u8, _>(
+                sql!("SELECT role FROM g_member WHERE uid = :uid AND gid = :gid;"),
+                params! {
+                    "uid" => u.into_sql(),
+                    "gid" => g.into_sql()
+                },
+            )
+            .ok()?
+        {
+            Some(role) => GroupRole::try_from(role).ok().map(Some),
+            None => Some(None),
+        }
+    }
+    /// Change the role of a member.
+    fn set_member_role(&self, u: UserId, g: GroupId, role: GroupRole) -> Option<()> {
+        // a member without a row would silently affect nothing
+        self.get_member_role(u, g)??;
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid;"),
+            params! {
+                "role" => role.into_sql(),
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()
+    }
+    /// Hand a group over from its owner to a member, in one transaction.
+    fn transfer_ownership(&self, from: UserId, to: UserId, g: GroupId) -> Option<()> {
+        if from == to {
+            return None;
+        }
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        tx.run(
+            sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid AND role = :was;"),
+            params! {
+                "role" => GroupRole::Admin.into_sql(),
+                "uid" => from.into_sql(),
+                "gid" => g.into_sql(),
+                "was" => GroupRole::Owner.into_sql()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            return None;
+        }
+        tx.run(
+            sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid;"),
+            params! {
+                "role" => GroupRole::Owner.into_sql(),
+                "uid" => to.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        if tx.affected_rows() == 0 {
+            return None;
+        }
+        tx.commit().ok()
+    }
+    /// Ban a user from a group, removing their membership and pending invite if any.
+    fn ban_from_group(&self, banner: UserId, u: UserId, g: GroupId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        let mut tx = conn.start_transaction(self.tx_opts).ok()?;
+        let target = params! {
+            "uid" => u.into_sql(),
+            "gid" => g.into_sql()
+        };
+        tx.run(
+            sql!("DELETE FROM g_member WHERE uid = :uid AND gid = :gid;"),
+            target.clone(),
+        )
+        .ok()?;
+        tx.run(
+            sql!("DELETE FROM g_invite WHERE uid = :uid AND gid = :gid;"),
+            target,
+        )
+        .ok()?;
+        tx.run(
+            sql!("INSERT IGNORE INTO g_ban (gid, uid, banned_by) VALUES (:gid, :uid, :banned_by);"),
+            params! {
+                "gid" => g.into_sql(),
+                "uid" => u.into_sql(),
+                "banned_by" => banner.into_sql()
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    /// Lift a ban.
+    fn unban_from_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM g_ban WHERE uid = :uid AND gid = :gid;"),
+            params! {
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    /// Check if a user is banned from a group.
+    fn is_banned(&self, u: UserId, g: GroupId) -> Option<bool> {
+        let mut conn = self.conn().ok()?;
+        conn.first(
+            sql!("SELECT EXISTS (SELECT 1 FROM g_ban WHERE uid = :uid AND gid = :gid);"),
+            params! {
+                "uid" => u.into_sql(),
+                "gid" => g.into_sql()
+            },
+        )
+        .ok()?
+    }
+    fn create_session(&self, rec: &SessionRecord) -> Option<()> {
+        let s = &rec.session;
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("INSERT INTO u_session
+        (sid, uid, token_hash, refresh_hash, created, last_seen, expires, refresh_expires, device, ip)
+        VALUES
+        (:sid, :uid, :token_hash, :refresh_hash, :created, :last_seen, :expires, :refresh_expires, :device, :ip);"),
+            params! {
+                "sid" => s.sid.into_sql(),
+                "uid" => s.uid.into_sql(),
+                "token_hash" => &rec.token_hash,
+                "refresh_hash" => &rec.refresh_hash,
+                "created" => s.created.naive_utc(),
+                "last_seen" => s.last_seen.naive_utc(),
+                "expires" => s.expires.naive_utc(),
+                "refresh_expires" => s.refresh_expires.naive_utc(),
+                "device" => &s.device,
+                "ip" => &s.ip
+            },
+        )
+        .ok()
+    }
+    fn rotate_session(&self, rec: &SessionRecord) -> Option<()> {
+        let s = &rec.session;
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("UPDATE u_session SET
+        token_hash = :token_hash, refresh_hash = :refresh_hash, last_seen = :last_seen,
+        expires = :expires, refresh_expires = :refresh_expires
+        WHERE sid = :sid;"),
+            params! {
+                "sid" => s.sid.into_sql(),
+                "token_hash" => &rec.token_hash,
+                "refresh_hash" => &rec.refresh_hash,
+                "last_seen" => s.last_seen.naive_utc(),
+                "expires" => s.expires.naive_utc(),
+                "refresh_expires" => s.refresh_expires.naive_utc()
+            },
+        )
+        .ok()?;
+        // mysql counts changed rows, so an update within the same second would look like a miss
+        conn.first::<bool, _>(
+            sql!("SELECT EXISTS (SELECT 1 FROM u_session WHERE sid = :sid);"),
+            params! {"sid" => s.sid.into_sql()},
+        )
+        .ok()?
+        .filter(|exists| *exists)
+        .map(|_| ())
+    }
+    fn touch_session(&self, sid: SessionId, last_seen: DateTime<Utc>, expires: DateTime<Utc>) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("UPDATE u_session SET last_seen = :last_seen, expires = :expires WHERE sid = :sid;"),
+            params! {
+                "sid" => sid.into_sql(),
+                "last_seen" => last_seen.naive_utc(),
+                "expires" => expires.naive_utc()
+            },
+        )
+        .ok()
+    }
+    fn delete_session(&self, sid: SessionId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM u_session WHERE sid = :sid;"),
+            params! {"sid" => sid.into_sql()},
+        )
+        .ok()?;
+        if conn.affected_rows() == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn delete_user_sessions(&self, u: UserId) -> Option<()> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM u_session WHERE uid = :uid;"),
+            params! {"uid" => u.into_sql()},
+        )
+        .ok()
+    }
+    fn load_sessions(&self, now: DateTime<Utc>) -> Option<Vec<SessionRecord>> {
+        let mut conn = self.conn().ok()?;
+        conn.run(
+            sql!("DELETE FROM u_session WHERE refresh_expires <= :now;"),
+            params! {"now" => now.naive_utc()},
+        )
+        .ok()?;
+        conn.all::<SqlSession, _>(
+            sql!("SELECT sid, uid, token_hash, refresh_hash, created, last_seen, expires, refresh_expires, device, ip
+        FROM u_session;"),
+            (),
+        )
+        .ok()?
+        .into_iter()
+        .map(SessionRecord::from_sql_tup)
+        .collect()
+    }
+}
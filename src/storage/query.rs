@@ -0,0 +1,88 @@
+/*! The only place storage calls the sql drivers. Everything here takes `Sql`, never a string. */
+#![allow(clippy::disallowed_methods)]
+
+use crate::symbols::*;
+use mysql::prelude::{FromRow, Queryable};
+use rusqlite::{Connection, Row, ToSql};
+
+/// Named parameters as made by `rusqlite::named_params!`.
+pub type SqliteParams<'a> = &'a [(&'a str, &'a dyn ToSql)];
+
+/// Run `Sql` on a sqlite connection, or a transaction through its deref.
+pub trait SqliteQuery {
+    /// Run a statement, returning how many rows it changed.
+    fn exec(&self, q: Sql, params: SqliteParams) -> rusqlite::Result<usize>;
+    /// Read the first row. Fails with `QueryReturnedNoRows` if there is none, see `OptionalExtension`.
+    fn row<T, F>(&self, q: Sql, params: SqliteParams, f: F) -> rusqlite::Result<T>
+    where
+        F: FnOnce(&Row) -> rusqlite::Result<T>;
+    /// Read every row.
+    fn rows<T, F>(&self, q: Sql, params: SqliteParams, f: F) -> rusqlite::Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> rusqlite::Result<T>;
+    /// Run one or more statements without parameters.
+    fn batch(&self, q: Sql) -> rusqlite::Result<()>;
+}
+
+impl SqliteQuery for Connection {
+    fn exec(&self, q: Sql, params: SqliteParams) -> rusqlite::Result<usize> {
+        self.execute_named(q.as_str(), params)
+    }
+    fn row<T, F>(&self, q: Sql, params: SqliteParams, f: F) -> rusqlite::Result<T>
+    where
+        F: FnOnce(&Row) -> rusqlite::Result<T>,
+    {
+        self.query_row_named(q.as_str(), params, f)
+    }
+    fn rows<T, F>(&self, q: Sql, params: SqliteParams, f: F) -> rusqlite::Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> rusqlite::Result<T>,
+    {
+        let mut stmt = self.prepare_cached(q.as_str())?;
+        let rows = stmt.query_map_named(params, f)?;
+        rows.collect()
+    }
+    fn batch(&self, q: Sql) -> rusqlite::Result<()> {
+        self.execute_batch(q.as_str())
+    }
+}
+
+/// Run `Sql` on anything mysql can query: pooled connections and transactions.
+pub trait MysqlQuery: Queryable {
+    /// Run a statement, see `affected_rows` for how many rows it changed.
+    fn run<P>(&mut self, q: Sql, params: P) -> mysql::Result<()>
+    where
+        P: Into<mysql::Params>,
+    {
+        self.exec_drop(q.as_str(), params)
+    }
+    /// Read the first row, if any.
+    fn first<T, P>(&mut self, q: Sql, params: P) -> mysql::Result<Option<T>>
+    where
+        T: FromRow,
+        P: Into<mysql::Params>,
+    {
+        self.exec_first(q.as_str(), params)
+    }
+    /// Read every row.
+    fn all<T, P>(&mut self, q: Sql, params: P) -> mysql::Result<Vec<T>>
+    where
+        T: FromRow,
+        P: Into<mysql::Params>,
+    {
+        self.exec(q.as_str(), params)
+    }
+    /// Run a statement without parameters over the text protocol, which DDL needs.
+    fn run_text(&mut self, q: Sql) -> mysql::Result<()> {
+        self.query_drop(q.as_str())
+    }
+    /// Read the first row of a statement without parameters over the text protocol.
+    fn first_text<T>(&mut self, q: Sql) -> mysql::Result<Option<T>>
+    where
+        T: FromRow,
+    {
+        self.query_first(q.as_str())
+    }
+}
+
+impl<Q: Queryable> MysqlQuery for Q {}
@@ -0,0 +1,1316 @@
+use crate::imports::*;
+use crate::symbols::*;
+use rusqlite::{named_params, Connection, OptionalExtension, Row};
+
+/// SQLite storage backend. Mirrors the mysql schema in a single file.
+pub struct SqliteStorage {
+    c: ConnPool<Connection>,
+}
+
+impl SqliteStorage {
+    /**
+    Create a new sqlite storage container.
+    `sql_addr` looks like `sqlite:///path/yap.db`; `sqlite://:memory:` gives a throwaway database.
+    Keeps `pc.max` connections open.
+    */
+    pub fn new(sql_addr: &str, pc: &DbPoolConfig) -> Result<SqliteStorage, StorageError> {
+        let path = sql_addr.trim_start_matches("sqlite://");
+        // every connection to `:memory:` is its own database, so share a single one
+        let size = if path == ":memory:" { 1 } else { pc.max.max(1) };
+        let conns = (0..size)
+            .map(|_| SqliteStorage::open(path))
+            .collect::<rusqlite::Result<Vec<Connection>>>()?;
+        let c = ConnPool::new(conns, Duration::from_millis(pc.checkout_timeout_ms.into()));
+        SqliteStorage::init(&mut *c.get()?)?;
+        Ok(SqliteStorage { c })
+    }
+    /// Open a connection with the per-connection settings applied.
+    fn open(path: &str) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(path)?;
+        conn.batch(Q_SQLITE_PRAGMAS)?;
+        // wait on other pooled connections instead of failing with SQLITE_BUSY
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(conn)
+    }
+    /// Initialize the storage in case it hasn't been set up, then bring the schema up to date.
+    fn init(conn: &mut Connection) -> Result<(), StorageError> {
+        conn.batch(Q_SQLITE_CREATE_SCHEMA_VERSION)?;
+        let current = conn
+            .row(Q_GET_SCHEMA_VERSION, &[], |row| {
+                row.get::<_, Option<u32>>(0)
+            })?
+            .unwrap_or(0);
+        for m in pending_migrations(current, SQLITE_MIGRATIONS)? {
+            info!("storage: applying migration {} ({})", m.version, m.name);
+            let tx = conn.transaction()?;
+            for q in m.up {
+                tx.batch(*q)?;
+            }
+            tx.exec(
+                Q_SET_SCHEMA_VERSION,
+                named_params! {":version": m.version, ":name": m.name},
+            )?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+    /// Read a `u_message` row in the same shape mysql returns it.
+    fn sql_user_message(row: &Row) -> rusqlite::Result<SqlUserMessage> {
+        Ok((
+            row.get::<_, i64>(0)? as u64,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+        ))
+    }
+    /// Read a `g_message` row in the same shape mysql returns it.
+    fn sql_group_message(row: &Row) -> rusqlite::Result<SqlGroupMessage> {
+        Ok((
+            row.get::<_, i64>(0)? as u64,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
+    }
+    /// Read a `u_friend_request` row in the same shape mysql returns it.
+    fn sql_friend_request(row: &Row) -> rusqlite::Result<SqlFriendRequest> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    }
+    /// Read a `u_session` row in the same shape mysql returns it.
+    fn sql_session(row: &Row) -> rusqlite::Result<SqlSession> {
+        Ok((
+            row.get::<_, i64>(0)? as u64,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+            row.get(8)?,
+            row.get(9)?,
+        ))
+    }
+    /// Read a `u` row in the same shape mysql returns it.
+    fn sql_user_record(row: &Row) -> rusqlite::Result<SqlUserRecord> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+            row.get(6)?,
+            row.get(7)?,
+            row.get(8)?,
+            row.get(9)?,
+            row.get(10)?,
+        ))
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn try_register(&self, req: RegisterRequest) -> Result<UserId, RegisterError> {
+        // hashing is slow on purpose, so do it before holding a connection
+        let hashed_pass = hash_password(&req.password_hash).ok_or(RegisterError::Unknown)?;
+        let mut c = self.c.get().map_err(RegisterError::from)?;
+        let tx = c.transaction().map_err(RegisterError::from)?;
+        // try to get associated userid from email
+        let existing = tx
+            .row(
+                sql!("SELECT uid FROM u WHERE email = :email;"),
+                named_params! {":email": &req.email},
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()
+            .map_err(RegisterError::from)?;
+        if existing.is_some() {
+            // user already exists
+            Err(RegisterError::UserAlreadyExists)
+        } else {
+            // new user
+            tx.exec(
+                sql!("INSERT INTO u
+            (email, pubkey, hashed_pass, friends, groups, status, visibility) VALUES
+            (:email, :pubkey, :hashed_pass, :friends, :groups, :status, :visibility);"),
+                named_params! {
+                    ":email": req.email,
+                    ":pubkey": req.pubkey,
+                    ":hashed_pass": hashed_pass.as_str(),
+                    ":friends": serde_json::to_string::<[UserId]>(&[]).unwrap(),
+                    ":groups": serde_json::to_string::<[UserId]>(&[]).unwrap(),
+                    ":status": serde_json::to_string(&UserStatus::default()).unwrap(),
+                    ":visibility": serde_json::to_string(&UserVisibility::default()).unwrap()
+                },
+            )
+            .map_err(RegisterError::from)?;
+            let uid = tx.last_insert_rowid() as u32;
+            tx.commit().map_err(RegisterError::from)?;
+            Ok(uid.into())
+        }
+    }
+    fn try_login(&self, req: LoginRequest) -> Result<UserId, LoginError> {
+        debug!("storage: login request for {}", &req.email);
+        // the connection goes back to the pool before a rehash needs one
+        let row = self
+            .c
+            .get()
+            .map_err(LoginError::from)?
+            .row(
+                sql!("SELECT uid, hashed_pass FROM u WHERE email = :email;"),
+                named_params! {":email": &req.email},
+                |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .map_err(LoginError::from)?;
+        match row {
+            Some((ref_uid, ref_pass)) => {
+                debug!("storage: login uid pass found");
+                let uid = UserId::from(ref_uid);
+                verify_login(uid, HashedPassword::from(ref_pass), &req.password_hash, |hashed| {
+                    self.set_password(uid, hashed)
+                })
+            }
+            None => {
+                debug!("storage: login unknown email");
+                Err(reject_unknown_login(&req.password_hash))
+            }
+        }
+    }
+    fn new_message_u(
+        &self,
+        sender: UserId,
+        receiver: UserId,
+        msg: ClientMessage,
+    ) -> Option<PublicUserMessage> {
+        if self.has_blocked(receiver, sender)? {
+            return None;
+        }
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("INSERT INTO u_message (sender_id, receiver_id, msg_content, time_posted, r)
+        VALUES (:sender_id, :receiver_id, :msg_content, :time_posted, :r);"),
+            named_params! {
+                ":sender_id": u32::from(sender),
+                ":receiver_id": u32::from(receiver),
+                ":msg_content": msg.to_string(),
+                ":time_posted": DateTime::<Utc>::from(SystemTime::now()).naive_utc(),
+                ":r": false
+            },
+        )
+        .ok()?;
+        let umid = tx.last_insert_rowid();
+        let echo_msg = tx
+            .row(
+                sql!("SELECT * FROM u_message WHERE umid = :umid;"),
+                named_params! {":umid": umid},
+                SqliteStorage::sql_user_message,
+            )
+            .ok()
+            .and_then(PublicUserMessage::from_sql_tup)?;
+        tx.commit().ok()?;
+        Some(echo_msg)
+    }
+    fn new_message_g(
+        &self,
+        sender: UserId,
+        group: GroupId,
+        msg: ClientMessage,
+    ) -> Option<PublicGroupMessage> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("INSERT INTO g_message (sender_id, gid, msg_content, time_posted)
+        VALUES (:sender_id, :gid, :msg_content, :time_posted);"),
+            named_params! {
+                ":sender_id": u32::from(sender),
+                ":gid": u32::from(group),
+                ":msg_content": msg.to_string(),
+                ":time_posted": DateTime::<Utc>::from(SystemTime::now()).naive_utc()
+            },
+        )
+        .ok()?;
+        let gmid = tx.last_insert_rowid();
+        let echo_msg = tx
+            .row(
+                sql!("SELECT * FROM g_message WHERE gmid = :gmid;"),
+                named_params! {":gmid": gmid},
+                SqliteStorage::sql_group_message,
+            )
+            .ok()
+            .and_then(PublicGroupMessage::from_sql_tup)?;
+        tx.commit().ok()?;
+        Some(echo_msg)
+    }
+    fn get_user_data(
+        &self,
+        u: UserId,
+        requester: Option<UserId>,
+    ) -> Option<Option<PublicUserRecord>> {
+        let are_friends = self.are_friends(u, requester)?;
+        let blocked = match requester {
+            Some(r) => self.has_blocked(u, r)?,
+            None => false,
+        };
+        let row = match self
+            .c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT * FROM u WHERE uid = :uid;"),
+                named_params! {":uid": u32::from(u)},
+                SqliteStorage::sql_user_record,
+            )
+            .optional()
+            .ok()?
+        {
+            Some(row) => row,
+            None => return Some(None),
+        };
+        let mut ur = UserRecord::from_sql_tup(row)?;
+        // memberships live in g_member and u_friend, not the legacy columns
+        ur.groups = self.get_user_groups(u)?;
+        ur.friends = self.get_friends(u)?;
+        let mask_lvl = mask_level(u, requester, are_friends, blocked, &ur.visibility);
+        Some(Some(ur.mask(mask_lvl)))
+    }
+    fn search_users(
+        &self,
+        alias: Option<&str>,
+        email: Option<&str>,
+        requester: Option<UserId>,
+        after: Option<UserId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserRecord>> {
+        let found = self
+            .c
+            .get()
+            .ok()?
+            .rows(
+                Q_SQLITE_SEARCH_USERS,
+                named_params! {
+                    ":alias": alias.map(like_prefix),
+                    ":email": email,
+                    ":after": after.map(u32::from),
+                    ":public": serde_json::to_string(&UserVisibility::Public).ok()?,
+                    ":friends_only": serde_json::to_string(&UserVisibility::FriendsOnly).ok()?,
+                    ":requester": requester.map(u32::from),
+                    ":amt": amt
+                },
+                SqliteStorage::sql_user_record,
+            )
+            .ok()?;
+        found
+            .into_iter()
+            .map(|tup| mask_search_hit(tup, requester))
+            .collect()
+    }
+    fn get_group_data(&self, g: GroupId) -> Option<GroupRecord> {
+        let c = self.c.get().ok()?;
+        let (name, motd) = c
+            .row(
+                sql!("SELECT name, motd FROM g WHERE gid = :gid;"),
+                named_params! {":gid": u32::from(g)},
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .ok()?;
+        let members = c
+            .rows(
+                sql!("SELECT uid, role FROM g_member WHERE gid = :gid;"),
+                named_params! {":gid": u32::from(g)},
+                |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u8>(1)?)),
+            )
+            .ok()?
+            .into_iter()
+            .map(|(uid, role)| {
+                Some(GroupMember {
+                    uid: UserId::from(uid),
+                    role: GroupRole::try_from(role).ok()?,
+                })
+            })
+            .collect::<Option<Vec<GroupMember>>>()?;
+        Some(GroupRecord {
+            gid: g,
+            name,
+            motd,
+            members,
+        })
+    }
+    fn get_user_user_unread(&self, s: UserId, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                sql!("SELECT * FROM u_message WHERE
+        sender_id = :sender_id AND
+        receiver_id = :receiver_id AND
+        r = 0;"),
+                named_params! {
+                    ":sender_id": u32::from(s),
+                    ":receiver_id": u32::from(r)
+                },
+                SqliteStorage::sql_user_message,
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_unread(&self, r: UserId) -> Option<Vec<PublicUserMessage>> {
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                sql!("SELECT * FROM u_message WHERE receiver_id = :receiver_id AND r = 0 ORDER BY umid;"),
+                named_params! {":receiver_id": u32::from(r)},
+                SqliteStorage::sql_user_message,
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_group_unread(
+        &self,
+        s: UserId,
+        g: GroupId,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                sql!("SELECT * FROM g_message m WHERE
+        m.gid = :gid AND
+        m.sender_id <> :uid AND
+        NOT EXISTS (SELECT 1 FROM g_message_read r WHERE r.gmid = m.gmid AND r.reader_id = :uid)
+        ORDER BY m.gmid;"),
+                named_params! {
+                    ":gid": u32::from(g),
+                    ":uid": u32::from(s)
+                },
+                SqliteStorage::sql_group_message,
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_user_history(
+        &self,
+        s: UserId,
+        r: UserId,
+        q: &HistoryQuery,
+        before: Option<UserMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicUserMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                Q_GET_USER_HISTORY,
+                named_params! {
+                    ":s": u32::from(s),
+                    ":r": u32::from(r),
+                    ":before": before.map(|umid| u64::from(umid) as i64),
+                    ":from": from,
+                    ":to": to,
+                    ":unseen": unseen,
+                    ":amt": amt
+                },
+                SqliteStorage::sql_user_message,
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicUserMessage::from_sql_tup)
+            .collect()
+    }
+    fn get_group_history(
+        &self,
+        s: UserId,
+        g: GroupId,
+        q: &HistoryQuery,
+        before: Option<GroupMessageId>,
+        amt: u16,
+    ) -> Option<Vec<PublicGroupMessage>> {
+        let (unseen, from, to) = q.bounds();
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                Q_GET_GROUP_HISTORY,
+                named_params! {
+                    ":gid": u32::from(g),
+                    ":uid": u32::from(s),
+                    ":before": before.map(|gmid| u64::from(gmid) as i64),
+                    ":from": from,
+                    ":to": to,
+                    ":unseen": unseen,
+                    ":amt": amt
+                },
+                SqliteStorage::sql_group_message,
+            )
+            .ok()?
+            .into_iter()
+            .map(PublicGroupMessage::from_sql_tup)
+            .collect()
+    }
+    fn flag_u_delivered(
+        &self,
+        umid: UserMessageId,
+        r: UserId,
+        device: SessionId,
+        at: DateTime<Utc>,
+    ) -> Option<(UserId, u32)> {
+        let umid = u64::from(umid) as i64;
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let sender = tx
+            .row(
+                sql!("SELECT sender_id FROM u_message WHERE umid = :umid AND receiver_id = :r;"),
+                named_params! {":umid": umid, ":r": u32::from(r)},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?;
+        let confirmed = tx
+            .exec(
+                sql!("INSERT OR IGNORE INTO u_message_delivery (umid, sid, at) VALUES (:umid, :sid, :at);"),
+                named_params! {
+                    ":umid": umid,
+                    ":sid": u64::from(device) as i64,
+                    ":at": at.naive_utc()
+                },
+            )
+            .ok()?;
+        if confirmed == 0 {
+            // this device already confirmed it
+            return None;
+        }
+        tx.exec(
+            sql!("UPDATE u_message SET delivered = :at WHERE umid = :umid AND delivered IS NULL;"),
+            named_params! {":at": at.naive_utc(), ":umid": umid},
+        )
+        .ok()?;
+        let devices = tx
+            .row(
+                sql!("SELECT COUNT(*) FROM u_message_delivery WHERE umid = :umid;"),
+                named_params! {":umid": umid},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?;
+        tx.commit().ok()?;
+        Some((UserId::from(sender), devices))
+    }
+    fn flag_u_read(&self, umid: UserMessageId, r: UserId, at: DateTime<Utc>) -> Option<UserId> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let changed = tx
+            .exec(
+                sql!("UPDATE u_message SET r = 1, read_at = :at, delivered = COALESCE(delivered, :at)
+        WHERE umid = :umid AND receiver_id = :r AND r = 0;"),
+                named_params! {
+                    ":at": at.naive_utc(),
+                    ":umid": u64::from(umid.clone()) as i64,
+                    ":r": u32::from(r)
+                },
+            )
+            .ok()?;
+        if changed == 0 {
+            return None;
+        }
+        let sender = tx
+            .row(
+                sql!("SELECT sender_id FROM u_message WHERE umid = :umid;"),
+                named_params! {":umid": u64::from(umid) as i64},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?;
+        tx.commit().ok()?;
+        Some(UserId::from(sender))
+    }
+    fn flag_g_read(&self, g: GroupId, gmid: GroupMessageId, reader: UserId) -> Option<()> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let posted: bool = tx
+            .row(
+                sql!("SELECT EXISTS (SELECT 1 FROM g_message WHERE gmid = :gmid AND gid = :gid);"),
+                named_params! {":gmid": u64::from(gmid) as i64, ":gid": u32::from(g)},
+                |row| row.get(0),
+            )
+            .ok()?;
+        if !posted {
+            return None;
+        }
+        tx.exec(
+            sql!("INSERT OR IGNORE INTO g_message_read (gmid, reader_id) VALUES (:gmid, :reader_id);"),
+            named_params! {
+                ":gmid": u64::from(gmid) as i64,
+                ":reader_id": u32::from(reader)
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    fn set_password(&self, u: UserId, hashed: HashedPassword) -> Option<()> {
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE u SET hashed_pass = :hashed_pass WHERE uid = :uid;"),
+                named_params! {":hashed_pass": hashed.as_str(), ":uid": u32::from(u)},
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_status(&self, u: UserId) -> Option<(UserStatus, Option<String>)> {
+        let (status, text) = self
+            .c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT status, status_text FROM u WHERE uid = :uid;"),
+                named_params! {":uid": u32::from(u)},
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .ok()?;
+        Some((serde_json::from_str(&status).ok()?, text))
+    }
+    fn set_status(&self, u: UserId, status: UserStatus, text: Option<String>) -> Option<()> {
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE u SET status = :status, status_text = :status_text WHERE uid = :uid;"),
+                named_params! {
+                    ":status": serde_json::to_string(&status).ok()?,
+                    ":status_text": text,
+                    ":uid": u32::from(u)
+                },
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn set_profile(&self, u: UserId, update: &ProfileUpdate) -> Option<()> {
+        let visibility = match &update.visibility {
+            Some(v) => Some(serde_json::to_string(v).ok()?),
+            None => None,
+        };
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE u SET
+            alias = CASE WHEN :set_alias THEN :alias ELSE alias END,
+            motd = CASE WHEN :set_motd THEN :motd ELSE motd END,
+            visibility = COALESCE(:visibility, visibility),
+            pubkey = COALESCE(:pubkey, pubkey)
+            WHERE uid = :uid;"),
+                named_params! {
+                    ":set_alias": update.alias.is_some(),
+                    ":alias": update.alias.clone().flatten(),
+                    ":set_motd": update.motd.is_some(),
+                    ":motd": update.motd.clone().flatten(),
+                    ":visibility": visibility,
+                    ":pubkey": update.pubkey,
+                    ":uid": u32::from(u)
+                },
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_friends(&self, u: UserId) -> Option<Vec<UserId>> {
+        let c = self.c.get().ok()?;
+        let res = c
+            .rows(
+                sql!("SELECT r FROM u_friend WHERE l = :l;"),
+                named_params! {":l": u32::from(u)},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?
+            .into_iter()
+            .map(UserId::from)
+            .collect();
+        Some(res)
+    }
+    fn are_friends(&self, l: UserId, r: Option<UserId>) -> Option<bool> {
+        match r {
+            Some(r) => self
+                .c
+                .get()
+                .ok()?
+                .row(
+                    sql!("SELECT EXISTS (SELECT 1 FROM u_friend WHERE l = :l AND r = :r);"),
+                    named_params! {":l": u32::from(l), ":r": u32::from(r)},
+                    |row| row.get(0),
+                )
+                .ok(),
+            None => Some(false),
+        }
+    }
+    fn add_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("INSERT INTO u_friend (l, r) VALUES (:l, :r), (:r, :l);"),
+            named_params! {":l": u32::from(l), ":r": u32::from(r)},
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    fn remove_friend(&self, l: UserId, r: UserId) -> Option<()> {
+        let removed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM u_friend WHERE (l = :l AND r = :r) OR (l = :r AND r = :l);"),
+                named_params! {":l": u32::from(l), ":r": u32::from(r)},
+            )
+            .ok()?;
+        if removed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_friend_request(&self, s: UserId, r: UserId) -> Option<Option<FriendRequest>> {
+        let row = self
+            .c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT sender_id, receiver_id, state, at FROM u_friend_request
+            WHERE sender_id = :s AND receiver_id = :r;"),
+                named_params! {":s": u32::from(s), ":r": u32::from(r)},
+                SqliteStorage::sql_friend_request,
+            )
+            .optional()
+            .ok()?;
+        match row {
+            Some(row) => FriendRequest::from_sql_tup(row).map(Some),
+            None => Some(None),
+        }
+    }
+    fn set_friend_request(
+        &self,
+        s: UserId,
+        r: UserId,
+        state: FriendRequestState,
+        at: DateTime<Utc>,
+    ) -> Option<FriendRequest> {
+        self.c
+            .get()
+            .ok()?
+            .exec(
+                sql!("INSERT OR REPLACE INTO u_friend_request (sender_id, receiver_id, state, at)
+            VALUES (:s, :r, :state, :at);"),
+                named_params! {
+                    ":s": u32::from(s),
+                    ":r": u32::from(r),
+                    ":state": u8::from(state),
+                    ":at": at.naive_utc()
+                },
+            )
+            .ok()?;
+        Some(FriendRequest {
+            from: s,
+            to: r,
+            state,
+            at,
+        })
+    }
+    fn accept_friend_request(&self, s: UserId, r: UserId, at: DateTime<Utc>) -> Option<FriendRequest> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let changed = tx
+            .exec(
+                sql!("UPDATE u_friend_request SET state = :accepted, at = :at
+            WHERE sender_id = :s AND receiver_id = :r AND state = :pending;"),
+                named_params! {
+                    ":accepted": u8::from(FriendRequestState::Accepted),
+                    ":pending": u8::from(FriendRequestState::Pending),
+                    ":at": at.naive_utc(),
+                    ":s": u32::from(s),
+                    ":r": u32::from(r)
+                },
+            )
+            .ok()?;
+        if changed == 0 {
+            return None;
+        }
+        tx.exec(
+            sql!("INSERT INTO u_friend (l, r) VALUES (:l, :r), (:r, :l);"),
+            named_params! {":l": u32::from(s), ":r": u32::from(r)},
+        )
+        .ok()?;
+        tx.commit().ok()?;
+        Some(FriendRequest {
+            from: s,
+            to: r,
+            state: FriendRequestState::Accepted,
+            at,
+        })
+    }
+    fn get_friend_requests(&self, u: UserId) -> Option<Vec<FriendRequest>> {
+        let c = self.c.get().ok()?;
+        c
+            .rows(
+                sql!("SELECT sender_id, receiver_id, state, at FROM u_friend_request
+            WHERE (sender_id = :u OR receiver_id = :u) AND state = :pending ORDER BY at;"),
+                named_params! {
+                    ":u": u32::from(u),
+                    ":pending": u8::from(FriendRequestState::Pending)
+                },
+                SqliteStorage::sql_friend_request,
+            )
+            .ok()?
+            .into_iter()
+            .map(FriendRequest::from_sql_tup)
+            .collect()
+    }
+    fn block_user(&self, u: UserId, other: UserId) -> Option<()> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("INSERT OR IGNORE INTO u_block (uid, blocked) VALUES (:u, :other);"),
+            named_params! {":u": u32::from(u), ":other": u32::from(other)},
+        )
+        .ok()?;
+        tx.exec(
+            sql!("DELETE FROM u_friend WHERE (l = :u AND r = :other) OR (l = :other AND r = :u);"),
+            named_params! {":u": u32::from(u), ":other": u32::from(other)},
+        )
+        .ok()?;
+        tx.exec(
+            sql!("DELETE FROM u_friend_request WHERE (sender_id = :u AND receiver_id = :other)
+        OR (sender_id = :other AND receiver_id = :u);"),
+            named_params! {":u": u32::from(u), ":other": u32::from(other)},
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    fn unblock_user(&self, u: UserId, other: UserId) -> Option<()> {
+        let removed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM u_block WHERE uid = :u AND blocked = :other;"),
+                named_params! {":u": u32::from(u), ":other": u32::from(other)},
+            )
+            .ok()?;
+        if removed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn has_blocked(&self, u: UserId, other: UserId) -> Option<bool> {
+        self.c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT EXISTS (SELECT 1 FROM u_block WHERE uid = :u AND blocked = :other);"),
+                named_params! {":u": u32::from(u), ":other": u32::from(other)},
+                |row| row.get(0),
+            )
+            .ok()
+    }
+    fn get_blocked(&self, u: UserId) -> Option<Vec<UserId>> {
+        let c = self.c.get().ok()?;
+        let res = c
+            .rows(
+                sql!("SELECT blocked FROM u_block WHERE uid = :u;"),
+                named_params! {":u": u32::from(u)},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?
+            .into_iter()
+            .map(UserId::from)
+            .collect();
+        Some(res)
+    }
+    fn create_group(
+        &self,
+        owner: UserId,
+        name: Option<String>,
+        motd: Option<String>,
+    ) -> Option<GroupRecord> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("INSERT INTO g (name, motd) VALUES (:name, :motd);"),
+            named_params! {":name": &name, ":motd": &motd},
+        )
+        .ok()?;
+        let gid = GroupId::from(tx.last_insert_rowid() as u32);
+        tx.exec(
+            sql!("INSERT INTO g_member (uid, gid, role) VALUES (:uid, :gid, :role);"),
+            named_params! {
+                ":uid": u32::from(owner),
+                ":gid": u32::from(gid),
+                ":role": u8::from(GroupRole::Owner)
+            },
+        )
+        .ok()?;
+        tx.commit().ok()?;
+        Some(GroupRecord {
+            gid,
+            name,
+            motd,
+            members: vec![GroupMember {
+                uid: owner,
+                role: GroupRole::Owner,
+            }],
+        })
+    }
+    fn set_group_name(&self, g: GroupId, name: Option<String>) -> Option<()> {
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE g SET name = :name WHERE gid = :gid;"),
+                named_params! {":name": name, ":gid": u32::from(g)},
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn set_group_motd(&self, g: GroupId, motd: Option<String>) -> Option<()> {
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE g SET motd = :motd WHERE gid = :gid;"),
+                named_params! {":motd": motd, ":gid": u32::from(g)},
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_group_members(&self, g: GroupId) -> Option<Vec<UserId>> {
+        let c = self.c.get().ok()?;
+        let res = c
+            .rows(
+                sql!("SELECT uid FROM g_member WHERE gid = :gid;"),
+                named_params! {":gid": u32::from(g)},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?
+            .into_iter()
+            .map(UserId::from)
+            .collect();
+        Some(res)
+    }
+    fn get_user_groups(&self, u: UserId) -> Option<Vec<GroupId>> {
+        let c = self.c.get().ok()?;
+        let res = c
+            .rows(
+                sql!("SELECT gid FROM g_member WHERE uid = :uid;"),
+                named_params! {":uid": u32::from(u)},
+                |row| row.get::<_, u32>(0),
+            )
+            .ok()?
+            .into_iter()
+            .map(GroupId::from)
+            .collect();
+        Some(res)
+    }
+    fn is_group_member(&self, u: UserId, g: GroupId) -> Option<bool> {
+        self.c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT EXISTS (SELECT 1 FROM g_member WHERE uid = :uid AND gid = :gid);"),
+                named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+                |row| row.get(0),
+            )
+            .ok()
+    }
+    fn invite_to_group(&self, inviter: UserId, invitee: UserId, g: GroupId) -> Option<()> {
+        self.c
+            .get()
+            .ok()?
+            .exec(
+                sql!("INSERT OR IGNORE INTO g_invite (gid, uid, inviter) VALUES (:gid, :uid, :inviter);"),
+                named_params! {
+                    ":gid": u32::from(g),
+                    ":uid": u32::from(invitee),
+                    ":inviter": u32::from(inviter)
+                },
+            )
+            .ok()
+            .map(|_| ())
+    }
+    fn join_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        if self.is_banned(u, g)? {
+            return None;
+        }
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let invites = tx
+            .exec(
+                sql!("DELETE FROM g_invite WHERE gid = :gid AND uid = :uid;"),
+                named_params! {":gid": u32::from(g), ":uid": u32::from(u)},
+            )
+            .ok()?;
+        if invites == 0 {
+            // not invited
+            return None;
+        }
+        tx.exec(
+            sql!("INSERT OR IGNORE INTO g_member (uid, gid) VALUES (:uid, :gid);"),
+            named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    fn leave_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        let removed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM g_member WHERE uid = :uid AND gid = :gid;"),
+                named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+            )
+            .ok()?;
+        if removed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn get_member_role(&self, u: UserId, g: GroupId) -> Option<Option<GroupRole>> {
+        let role = self
+            .c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT role FROM g_member WHERE uid = :uid AND gid = :gid;"),
+                named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+                |row| row.get::<_, u8>(0),
+            )
+            .optional()
+            .ok()?;
+        match role {
+            Some(role) => GroupRole::try_from(role).ok().map(Some),
+            None => Some(None),
+        }
+    }
+    fn set_member_role(&self, u: UserId, g: GroupId, role: GroupRole) -> Option<()> {
+        let changed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid;"),
+                named_params! {
+                    ":role": u8::from(role),
+                    ":uid": u32::from(u),
+                    ":gid": u32::from(g)
+                },
+            )
+            .ok()?;
+        if changed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn transfer_ownership(&self, from: UserId, to: UserId, g: GroupId) -> Option<()> {
+        if from == to {
+            return None;
+        }
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        let demoted = tx
+            .exec(
+                sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid AND role = :was;"),
+                named_params! {
+                    ":role": u8::from(GroupRole::Admin),
+                    ":uid": u32::from(from),
+                    ":gid": u32::from(g),
+                    ":was": u8::from(GroupRole::Owner)
+                },
+            )
+            .ok()?;
+        let promoted = tx
+            .exec(
+                sql!("UPDATE g_member SET role = :role WHERE uid = :uid AND gid = :gid;"),
+                named_params! {
+                    ":role": u8::from(GroupRole::Owner),
+                    ":uid": u32::from(to),
+                    ":gid": u32::from(g)
+                },
+            )
+            .ok()?;
+        // dropping `tx` rolls back, unless `from` owned the group and `to` is in it
+        if demoted == 0 || promoted == 0 {
+            return None;
+        }
+        tx.commit().ok()
+    }
+    fn ban_from_group(&self, banner: UserId, u: UserId, g: GroupId) -> Option<()> {
+        let mut c = self.c.get().ok()?;
+        let tx = c.transaction().ok()?;
+        tx.exec(
+            sql!("DELETE FROM g_member WHERE uid = :uid AND gid = :gid;"),
+            named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+        )
+        .ok()?;
+        tx.exec(
+            sql!("DELETE FROM g_invite WHERE uid = :uid AND gid = :gid;"),
+            named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+        )
+        .ok()?;
+        tx.exec(
+            sql!("INSERT OR IGNORE INTO g_ban (gid, uid, banned_by) VALUES (:gid, :uid, :banned_by);"),
+            named_params! {
+                ":gid": u32::from(g),
+                ":uid": u32::from(u),
+                ":banned_by": u32::from(banner)
+            },
+        )
+        .ok()?;
+        tx.commit().ok()
+    }
+    fn unban_from_group(&self, u: UserId, g: GroupId) -> Option<()> {
+        let removed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM g_ban WHERE uid = :uid AND gid = :gid;"),
+                named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+            )
+            .ok()?;
+        if removed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn is_banned(&self, u: UserId, g: GroupId) -> Option<bool> {
+        self.c
+            .get()
+            .ok()?
+            .row(
+                sql!("SELECT EXISTS (SELECT 1 FROM g_ban WHERE uid = :uid AND gid = :gid);"),
+                named_params! {":uid": u32::from(u), ":gid": u32::from(g)},
+                |row| row.get(0),
+            )
+            .ok()
+    }
+    fn create_session(&self, rec: &SessionRecord) -> Option<()> {
+        let s = &rec.session;
+        self.c
+            .get()
+            .ok()?
+            .exec(
+                sql!("INSERT INTO u_session
+            (sid, uid, token_hash, refresh_hash, created, last_seen, expires, refresh_expires, device, ip)
+            VALUES
+            (:sid, :uid, :token_hash, :refresh_hash, :created, :last_seen, :expires, :refresh_expires, :device, :ip);"),
+                named_params! {
+                    ":sid": u64::from(s.sid) as i64,
+                    ":uid": u32::from(s.uid),
+                    ":token_hash": &rec.token_hash,
+                    ":refresh_hash": &rec.refresh_hash,
+                    ":created": s.created.naive_utc(),
+                    ":last_seen": s.last_seen.naive_utc(),
+                    ":expires": s.expires.naive_utc(),
+                    ":refresh_expires": s.refresh_expires.naive_utc(),
+                    ":device": &s.device,
+                    ":ip": &s.ip
+                },
+            )
+            .ok()?;
+        Some(())
+    }
+    fn rotate_session(&self, rec: &SessionRecord) -> Option<()> {
+        let s = &rec.session;
+        let updated = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE u_session SET
+            token_hash = :token_hash, refresh_hash = :refresh_hash, last_seen = :last_seen,
+            expires = :expires, refresh_expires = :refresh_expires
+            WHERE sid = :sid;"),
+                named_params! {
+                    ":sid": u64::from(s.sid) as i64,
+                    ":token_hash": &rec.token_hash,
+                    ":refresh_hash": &rec.refresh_hash,
+                    ":last_seen": s.last_seen.naive_utc(),
+                    ":expires": s.expires.naive_utc(),
+                    ":refresh_expires": s.refresh_expires.naive_utc()
+                },
+            )
+            .ok()?;
+        if updated == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn touch_session(&self, sid: SessionId, last_seen: DateTime<Utc>, expires: DateTime<Utc>) -> Option<()> {
+        let updated = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("UPDATE u_session SET last_seen = :last_seen, expires = :expires WHERE sid = :sid;"),
+                named_params! {
+                    ":sid": u64::from(sid) as i64,
+                    ":last_seen": last_seen.naive_utc(),
+                    ":expires": expires.naive_utc()
+                },
+            )
+            .ok()?;
+        if updated == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn delete_session(&self, sid: SessionId) -> Option<()> {
+        let removed = self
+            .c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM u_session WHERE sid = :sid;"),
+                named_params! {":sid": u64::from(sid) as i64},
+            )
+            .ok()?;
+        if removed == 0 {
+            None
+        } else {
+            Some(())
+        }
+    }
+    fn delete_user_sessions(&self, u: UserId) -> Option<()> {
+        self.c
+            .get()
+            .ok()?
+            .exec(
+                sql!("DELETE FROM u_session WHERE uid = :uid;"),
+                named_params! {":uid": u32::from(u)},
+            )
+            .ok()?;
+        Some(())
+    }
+    fn load_sessions(&self, now: DateTime<Utc>) -> Option<Vec<SessionRecord>> {
+        let c = self.c.get().ok()?;
+        c.exec(
+            sql!("DELETE FROM u_session WHERE refresh_expires <= :now;"),
+            named_params! {":now": now.naive_utc()},
+        )
+        .ok()?;
+        c.rows(
+            sql!("SELECT sid, uid, token_hash, refresh_hash, created, last_seen, expires, refresh_expires, device, ip
+            FROM u_session;"),
+            &[],
+            SqliteStorage::sql_session,
+        )
+        .ok()?
+        .into_iter()
+        .map(SessionRecord::from_sql_tup)
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh() -> Connection {
+        let mut conn = SqliteStorage::open(":memory:").unwrap();
+        SqliteStorage::init(&mut conn).unwrap();
+        conn
+    }
+
+    /// Every version recorded in `schema_version`, oldest first.
+    fn versions(conn: &Connection) -> Vec<u32> {
+        conn.rows(
+            sql!("SELECT version FROM schema_version ORDER BY version;"),
+            &[],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    /// Names of the tables and indices, to compare schemas by.
+    fn schema(conn: &Connection) -> Vec<String> {
+        conn.rows(
+            sql!("SELECT name FROM sqlite_master ORDER BY name;"),
+            &[],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    fn latest() -> u32 {
+        SQLITE_MIGRATIONS.last().unwrap().version
+    }
+
+    #[test]
+    fn fresh_database_gets_every_migration() {
+        let conn = fresh();
+        let all: Vec<u32> = SQLITE_MIGRATIONS.iter().map(|m| m.version).collect();
+        assert_eq!(versions(&conn), all);
+        assert_eq!(all, (1..=latest()).collect::<Vec<_>>());
+        let current = conn.row(Q_GET_SCHEMA_VERSION, &[], |row| row.get::<_, u32>(0));
+        assert_eq!(current.unwrap(), latest());
+    }
+
+    #[test]
+    fn init_again_changes_nothing() {
+        let mut conn = fresh();
+        let before = (versions(&conn), schema(&conn));
+        SqliteStorage::init(&mut conn).unwrap();
+        assert_eq!((versions(&conn), schema(&conn)), before);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let mut conn = fresh();
+        conn.exec(
+            Q_SET_SCHEMA_VERSION,
+            named_params! {":version": latest() + 1, ":name": "from the future"},
+        )
+        .unwrap();
+        match SqliteStorage::init(&mut conn) {
+            Err(StorageError::SchemaTooNew { current, supported }) => {
+                assert_eq!((current, supported), (latest() + 1, latest()));
+            }
+            r => panic!("expected SchemaTooNew, got {:?}", r),
+        }
+    }
+}
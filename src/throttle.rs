@@ -0,0 +1,356 @@
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+use crate::imports::*;
+
+/// Login throttling. Every field is optional in the launch config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// Failed logins from one address before it has to wait between attempts.
+    pub ip_free_attempts: u32,
+    /// Failed logins for one email before it has to wait between attempts.
+    pub account_free_attempts: u32,
+    /// The first wait in seconds. It doubles with every failure after that.
+    pub base_delay_s: i64,
+    /// The longest wait between attempts, in seconds.
+    pub max_delay_s: i64,
+    /// Failed logins for one email before it is locked.
+    pub lockout_attempts: u32,
+    /// How long a lockout lasts, in seconds.
+    pub lockout_s: i64,
+    /// Failures are forgotten after this many seconds without another one.
+    pub forget_after_s: i64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            ip_free_attempts: 20,
+            account_free_attempts: 5,
+            base_delay_s: 1,
+            max_delay_s: 60 * 5,
+            lockout_attempts: 10,
+            lockout_s: 60 * 15,
+            forget_after_s: 60 * 60,
+        }
+    }
+}
+
+/// Tables are swept for forgotten entries once they grow past this.
+const SWEEP_AT: usize = 10_000;
+
+/**
+Failed login tracking, per address and per email. Cheap to clone; clones share the same counts.
+
+Emails are counted whether or not anyone registered them, so a lockout says nothing about
+which accounts exist. Logging in clears the email's failures but not the address's, so an
+attacker can't reset their own count with an account of their own.
+Every attempt counts as failed until it is known not to be.
+*/
+#[derive(Clone)]
+pub struct LoginThrottle {
+    conf: ThrottleConfig,
+    t: Arc<ShardedLock<ThrottleTable>>,
+}
+
+#[derive(Default)]
+struct ThrottleTable {
+    by_ip: HashMap<IpAddr, Failures>,
+    /// Keyed by lowercased email.
+    by_account: HashMap<String, Failures>,
+}
+
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+    blocked_until: DateTime<Utc>,
+}
+
+impl Failures {
+    /// Count one more failure, waiting once `free` are used up and locking at `lockout`.
+    fn add(&mut self, conf: &ThrottleConfig, now: DateTime<Utc>, free: u32, lockout: Option<u32>) {
+        if now - self.last > chrono::Duration::seconds(conf.forget_after_s) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = now;
+        if let Some(wait) = self.wait(conf, free, lockout) {
+            self.blocked_until = now + chrono::Duration::seconds(wait);
+        }
+    }
+    /**
+    Take back the last failure, since the attempt it was counted for didn't fail.
+    The wait is worked out again from when that attempt started, so it never grows.
+    */
+    fn refund(&mut self, conf: &ThrottleConfig, free: u32, lockout: Option<u32>) {
+        self.count = self.count.saturating_sub(1);
+        let wait = self.wait(conf, free, lockout).unwrap_or(0);
+        self.blocked_until = self
+            .blocked_until
+            .min(self.last + chrono::Duration::seconds(wait));
+    }
+    /// Seconds to wait after `count` failures, if any.
+    fn wait(&self, conf: &ThrottleConfig, free: u32, lockout: Option<u32>) -> Option<i64> {
+        match lockout {
+            Some(l) if self.count >= l => Some(conf.lockout_s),
+            _ if self.count > free => {
+                let doublings = (self.count - free - 1).min(32);
+                Some(
+                    conf.base_delay_s
+                        .saturating_mul(1 << doublings)
+                        .min(conf.max_delay_s),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whole seconds left until `until`, rounded up. `None` once it has passed.
+fn retry_after(now: DateTime<Utc>, until: DateTime<Utc>) -> Option<i64> {
+    let ms = (until - now).num_milliseconds();
+    if ms > 0 {
+        Some((ms + 999) / 1000)
+    } else {
+        None
+    }
+}
+
+fn account_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// What logs call an email, so they can tell accounts apart without holding the address.
+fn account_tag(email: &str) -> String {
+    let mut tag = format!("{:x}", Sha256::digest(account_key(email).as_bytes()));
+    tag.truncate(12);
+    tag
+}
+
+fn sweep<K: Eq + Hash>(m: &mut HashMap<K, Failures>, now: DateTime<Utc>, keep: chrono::Duration) {
+    if m.len() > SWEEP_AT {
+        m.retain(|_, f| f.blocked_until > now || now - f.last <= keep);
+    }
+}
+
+impl LoginThrottle {
+    pub fn new(conf: ThrottleConfig) -> LoginThrottle {
+        LoginThrottle {
+            conf,
+            t: Arc::new(ShardedLock::new(ThrottleTable::default())),
+        }
+    }
+    /**
+    Start a login attempt. Fails with how many seconds to wait if it has to wait.
+    `None` if the counts can't be read.
+
+    The attempt is counted as failed right away, before the password is checked, so attempts
+    sent all at once can't slip past the count. `succeeded` or `aborted` take it back.
+    */
+    pub fn begin(&self, ip: Option<IpAddr>, email: &str) -> Option<Result<(), i64>> {
+        self.begin_at(ip, email, Utc::now())
+    }
+    fn begin_at(
+        &self,
+        ip: Option<IpAddr>,
+        email: &str,
+        now: DateTime<Utc>,
+    ) -> Option<Result<(), i64>> {
+        let conf = &self.conf;
+        let keep = chrono::Duration::seconds(conf.forget_after_s);
+        let fresh = || Failures {
+            count: 0,
+            last: now,
+            blocked_until: now,
+        };
+        let mut t = self.t.write().ok()?;
+        let by_ip = ip
+            .and_then(|ip| t.by_ip.get(&ip))
+            .and_then(|f| retry_after(now, f.blocked_until));
+        let by_account = t
+            .by_account
+            .get(&account_key(email))
+            .and_then(|f| retry_after(now, f.blocked_until));
+        // a throttled attempt isn't checked at all, so it can't count either
+        if let Some(wait) = by_ip.max(by_account) {
+            return Some(Err(wait));
+        }
+        if let Some(ip) = ip {
+            sweep(&mut t.by_ip, now, keep);
+            t.by_ip
+                .entry(ip)
+                .or_insert_with(fresh)
+                .add(conf, now, conf.ip_free_attempts, None);
+        }
+        sweep(&mut t.by_account, now, keep);
+        let account = t.by_account.entry(account_key(email)).or_insert_with(fresh);
+        account.add(conf, now, conf.account_free_attempts, Some(conf.lockout_attempts));
+        if account.count == conf.lockout_attempts {
+            let tag = account_tag(email);
+            warn!(
+                "throttle: locked logins for account {} after {} failures",
+                tag, account.count
+            );
+        }
+        Some(Ok(()))
+    }
+    /// Someone got the email right. Forgets its failures, and takes back the attempt from the address.
+    pub fn succeeded(&self, ip: Option<IpAddr>, email: &str) -> Option<()> {
+        let conf = &self.conf;
+        let mut t = self.t.write().ok()?;
+        if let Some(f) = ip.and_then(|ip| t.by_ip.get_mut(&ip)) {
+            f.refund(conf, conf.ip_free_attempts, None);
+        }
+        t.by_account.remove(&account_key(email));
+        Some(())
+    }
+    /// The password couldn't be checked at all, which isn't the user's fault. Takes back the attempt.
+    pub fn aborted(&self, ip: Option<IpAddr>, email: &str) -> Option<()> {
+        let conf = &self.conf;
+        let mut t = self.t.write().ok()?;
+        if let Some(f) = ip.and_then(|ip| t.by_ip.get_mut(&ip)) {
+            f.refund(conf, conf.ip_free_attempts, None);
+        }
+        if let Some(f) = t.by_account.get_mut(&account_key(email)) {
+            f.refund(conf, conf.account_free_attempts, Some(conf.lockout_attempts));
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(conf: ThrottleConfig) -> LoginThrottle {
+        LoginThrottle::new(ThrottleConfig {
+            ip_free_attempts: 1000,
+            lockout_attempts: 1000,
+            ..conf
+        })
+    }
+
+    fn at(s: i64) -> DateTime<Utc> {
+        DateTime::from_utc(NaiveDateTime::from_timestamp(1_600_000_000 + s, 0), Utc)
+    }
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let t = throttle(ThrottleConfig {
+            account_free_attempts: 2,
+            base_delay_s: 1,
+            max_delay_s: 8,
+            ..ThrottleConfig::default()
+        });
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        // the third failure is the first one past the free ones
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Err(1)));
+        let mut now = 1;
+        for wait in [2, 4, 8, 8] {
+            assert_eq!(t.begin_at(IP, "a", at(now)), Some(Ok(())));
+            assert_eq!(t.begin_at(IP, "a", at(now + wait - 1)), Some(Err(1)));
+            now += wait;
+        }
+        // other emails aren't affected, and case doesn't matter
+        assert_eq!(t.begin_at(IP, "b", at(now - 1)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, " A ", at(now - 1)), Some(Err(1)));
+    }
+
+    #[test]
+    fn attempts_count_before_they_finish() {
+        let t = throttle(ThrottleConfig {
+            account_free_attempts: 2,
+            ..ThrottleConfig::default()
+        });
+        // none of these has an answer yet, but they still count
+        for _ in 0..3 {
+            assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        }
+        assert!(t.begin_at(IP, "a", at(0)).unwrap().is_err());
+        assert!(t.begin_at(None, "a", at(0)).unwrap().is_err());
+    }
+
+    #[test]
+    fn lockout_lasts_its_window() {
+        let t = LoginThrottle::new(ThrottleConfig {
+            account_free_attempts: 100,
+            lockout_attempts: 3,
+            lockout_s: 60,
+            ..ThrottleConfig::default()
+        });
+        for _ in 0..3 {
+            assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        }
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Err(60)));
+        assert_eq!(t.begin_at(None, "a", at(59)), Some(Err(1)));
+        assert_eq!(t.begin_at(None, "a", at(60)), Some(Ok(())));
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let t = throttle(ThrottleConfig {
+            account_free_attempts: 1,
+            base_delay_s: 10,
+            forget_after_s: 100,
+            ..ThrottleConfig::default()
+        });
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(10)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(10)), Some(Err(20)));
+        // starts over as the first failure
+        assert_eq!(t.begin_at(IP, "a", at(200)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(200)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(200)), Some(Err(10)));
+    }
+
+    #[test]
+    fn success_resets_the_email_only() {
+        let t = LoginThrottle::new(ThrottleConfig {
+            ip_free_attempts: 2,
+            account_free_attempts: 1,
+            lockout_attempts: 1000,
+            ..ThrottleConfig::default()
+        });
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert!(t.begin_at(IP, "a", at(0)).unwrap().is_err());
+        // the address is still one failure away from waiting, so this gets through
+        t.succeeded(IP, "a").unwrap();
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        t.succeeded(IP, "a").unwrap();
+        // the two failures from before still count for the address
+        assert_eq!(t.begin_at(IP, "b", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "c", at(0)), Some(Ok(())));
+        assert!(t.begin_at(IP, "d", at(0)).unwrap().is_err());
+    }
+
+    #[test]
+    fn logs_tag_accounts_without_the_email() {
+        let tag = account_tag("Someone@Example.com ");
+        assert_eq!(tag, account_tag("someone@example.com"));
+        assert_ne!(tag, account_tag("someone.else@example.com"));
+        assert_eq!(tag.len(), 12);
+        assert!(!tag.contains("someone"));
+    }
+
+    #[test]
+    fn aborted_attempts_dont_count() {
+        let t = throttle(ThrottleConfig {
+            account_free_attempts: 1,
+            ..ThrottleConfig::default()
+        });
+        for _ in 0..10 {
+            assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+            t.aborted(IP, "a").unwrap();
+        }
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert_eq!(t.begin_at(IP, "a", at(0)), Some(Ok(())));
+        assert!(t.begin_at(IP, "a", at(0)).unwrap().is_err());
+    }
+}
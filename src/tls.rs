@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::imports::*;
+use crate::symbols::*;
+
+#[cfg(test)]
+mod tests;
+
+/// Where the certificate chain and private key are, both PEM. Reread on SIGHUP.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// A client has this long to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT_S: u64 = 10;
+
+/**
+TLS termination for both listeners. Cheap to clone; clones share the same certificate.
+
+Connections keep the certificate they were accepted with, so reloading only affects new ones.
+*/
+#[derive(Clone)]
+pub struct Tls {
+    conf: TlsConfig,
+    acceptor: Arc<ShardedLock<TlsAcceptor>>,
+}
+
+/// An accepted connection, TLS or not, and who is on the other end.
+pub struct Conn {
+    io: Box<dyn ConnIo>,
+    pub peer: SocketAddr,
+}
+
+trait ConnIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> ConnIo for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// The address a connection came from, for requests served outside `warp::serve`.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+fn read_pem(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Io(path.to_owned(), e))
+}
+
+impl Tls {
+    /// Read the certificate and key. Fails if either is missing or they don't match.
+    pub fn load(conf: TlsConfig) -> Result<Tls, TlsError> {
+        let acceptor = Tls::read(&conf)?;
+        Ok(Tls {
+            conf,
+            acceptor: Arc::new(ShardedLock::new(acceptor)),
+        })
+    }
+    fn read(conf: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+        let certs = pemfile::certs(&mut read_pem(&conf.cert_path)?)
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| TlsError::NoCertificate(conf.cert_path.clone()))?;
+        // PKCS#8 first, then the older RSA-only format
+        let key = pemfile::pkcs8_private_keys(&mut read_pem(&conf.key_path)?)
+            .ok()
+            .and_then(|mut keys| keys.pop())
+            .or_else(|| {
+                pemfile::rsa_private_keys(&mut read_pem(&conf.key_path).ok()?)
+                    .ok()?
+                    .pop()
+            })
+            .ok_or_else(|| TlsError::NoKey(conf.key_path.clone()))?;
+        let mut sc = ServerConfig::new(NoClientAuth::new());
+        sc.set_single_cert(certs, key)?;
+        Ok(TlsAcceptor::from(Arc::new(sc)))
+    }
+    /// Reread the certificate and key. On failure the old ones stay in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let acceptor = Tls::read(&self.conf)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+    /// Reload whenever the process gets SIGHUP. Runs until the runtime shuts down.
+    pub async fn reload_on_hangup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("tls: can't listen for SIGHUP, certificates won't reload: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("tls: reloaded {:?}", &self.conf.cert_path),
+                Err(e) => error!("tls: reload failed, keeping the old certificate: {}", e),
+            }
+        }
+    }
+    async fn accept(&self, t: TcpStream) -> Option<tokio_rustls::server::TlsStream<TcpStream>> {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        let handshake = acceptor.accept(t);
+        match tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_S), handshake).await {
+            Ok(Ok(s)) => Some(s),
+            Ok(Err(e)) => {
+                debug!("tls: handshake failed: {}", e);
+                None
+            }
+            Err(_) => {
+                debug!("tls: handshake timed out");
+                None
+            }
+        }
+    }
+}
+
+/**
+Accept connections on `listener`, doing the TLS handshake first if `tls` is set.
+Handshakes run on their own tasks so a slow client doesn't hold up the rest.
+*/
+pub fn accept_conns(listener: tokio::net::TcpListener, tls: Option<Tls>) -> Receiver<Conn> {
+    let (s_conn, r_conn) = mpsc::channel(100);
+    tokio::spawn(async move {
+        let mut listener = listener;
+        loop {
+            let (t, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    continue;
+                }
+            };
+            let mut s_conn = s_conn.clone();
+            match tls.clone() {
+                Some(tls) => {
+                    tokio::spawn(async move {
+                        if let Some(s) = tls.accept(t).await {
+                            s_conn.send(Conn { io: Box::new(s), peer }).await.ok();
+                        }
+                    });
+                }
+                None => {
+                    // nobody is listening anymore
+                    if s_conn.send(Conn { io: Box::new(t), peer }).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    r_conn
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
@@ -3,6 +3,9 @@ use std::net::SocketAddr;
 use crate::imports::*;
 use crate::symbols::*;
 
+#[cfg(test)]
+mod tests;
+
 pub struct Web {}
 
 pub type CoreAsker =
@@ -17,80 +20,409 @@ impl Web {
     pub async fn internal(
         nc: NetConfig,
         web_chans: WebChannels,
+        sessions: Sessions,
+        tls: Option<Tls>,
         r_stop: tokio::sync::watch::Receiver<DateTime<Utc>>,
     ) {
-        let mut lt_uid: Arc<RwLock<HashMap<LoginToken, UserId>>> =
-            Arc::new(RwLock::new(HashMap::new()));
         let mut r_stop = r_stop;
         let mut web_chans = web_chans;
-        let s_ws = web_chans.s_ws.clone();
         let ac = web_chans.ask_core.clone();
-        let cls_lt_uid = lt_uid.clone();
+        let cls_sessions = sessions.clone();
+        let throttle = LoginThrottle::new(nc.login_throttle.clone());
         //let (s_webworker, r_from_webworker) = tokio::sync::mpsc::channel(1000);
         let login =
-            warp::post()
-                .and(warp::any().map(move || ac.clone()))
-                .and(warp::any().map(move || s_ws.clone()))
+            warp::any()
+                .map(move || ac.clone())
                 .and(warp::path("login"))
+                .and(warp::post())
                 .and(warp::body::json())
-                .and(warp::any().map(move || cls_lt_uid.clone()))
-                .and_then(async move |ask_core, notify_ws, login_req, lt_uid| {
-                    match Web::try_auth_user(ask_core, login_req).await {
-                        Some(uid) => Web::handle_login(lt_uid, notify_ws, uid).await,
-                        None => Err(warp::reject::custom(WebInvalidUser)),
-                    }
-                });
+                .and(warp::any().map(move || cls_sessions.clone()))
+                .and(warp::any().map(move || throttle.clone()))
+                .and(Web::remote())
+                .and_then(Web::handle_login);
+
+        let cls_sessions = sessions.clone();
+        let refresh = warp::any()
+            .map(move || cls_sessions.clone())
+            .and(warp::path("refresh"))
+            .and(warp::post())
+            .and(warp::body::json())
+            .and_then(Web::handle_refresh);
+
+        let cls_sessions = sessions.clone();
+        let s_ws = web_chans.s_ws.clone();
+        let logout = warp::any()
+            .map(move || cls_sessions.clone())
+            .and(warp::any().map(move || s_ws.clone()))
+            .and(warp::path!("logout"))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_logout);
+
+        let cls_sessions = sessions.clone();
+        let s_ws = web_chans.s_ws.clone();
+        let logout_all = warp::any()
+            .map(move || cls_sessions.clone())
+            .and(warp::any().map(move || s_ws.clone()))
+            .and(warp::path!("logout" / "all"))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_logout_all);
+
+        let cls_sessions = sessions.clone();
+        let list_sessions = warp::any()
+            .map(move || cls_sessions.clone())
+            .and(warp::path!("sessions"))
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_sessions);
+
+        let cls_sessions = sessions.clone();
+        let s_ws = web_chans.s_ws.clone();
+        let end_session = warp::any()
+            .map(move || cls_sessions.clone())
+            .and(warp::any().map(move || s_ws.clone()))
+            .and(warp::path!("sessions" / SessionId))
+            .and(warp::delete())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_end_session);
+
+        let cls_sessions = sessions.clone();
+        let s_ws = web_chans.s_ws.clone();
+        let ws_route = nc.ws_route;
+        let ws = warp::any()
+            .and(warp::path!("ws"))
+            .and(warp::get())
+            .and(warp::any().and_then(move || async move {
+                if ws_route {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }))
+            .untuple_one()
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::any().map(move || s_ws.clone()))
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::ws())
+            .and_then(Web::handle_ws);
 
         let ac = web_chans.ask_core.clone();
-        let register = warp::post()
-            .and(warp::any().map(move || ac.clone()))
+        let register = warp::any()
+            .map(move || ac.clone())
             .and(warp::path("register"))
+            .and(warp::post())
             .and(warp::body::json())
             .and_then(Web::handle_register);
 
         let ac = web_chans.ask_core.clone();
-        let cls_lt_uid = lt_uid.clone();
-        let userinfo = warp::get()
-            .and(warp::any().map(move || ac.clone()))
-            .and(warp::any().map(move || cls_lt_uid.clone()))
+        let cls_sessions = sessions.clone();
+        let userinfo = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
             .and(warp::path("users"))
             .and(warp::path::param::<UserId>())
+            .and(warp::get())
             .and(warp::header::optional::<LoginToken>(
                 http::header::AUTHORIZATION.as_str(),
             ))
             .and_then(Web::handle_userinfo);
 
-        let (addr, server) = warp::serve(userinfo.or(login).or(register))
-            .bind_with_graceful_shutdown(nc.api_addr.parse::<SocketAddr>().unwrap(), async move {
-                r_stop.recv().await;
-                r_stop.recv().await;
-                info!("Web: stopped");
-            });
-        info!("Web: started");
-        server.await;
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let search_users = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("users" / "search"))
+            .and(warp::get())
+            .and(warp::header::optional::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::query::<UserSearchParams>())
+            .and_then(Web::handle_search_users);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let update_profile = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("users" / "me"))
+            .and(warp::patch())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::body::json())
+            .and_then(Web::handle_update_profile);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let groupinfo = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path("groups"))
+            .and(warp::path::param::<GroupId>())
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_groupinfo);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let user_history = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("history" / "users" / UserId))
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::query::<HistoryParams>())
+            .and_then(Web::handle_user_history);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let group_history = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("history" / "groups" / GroupId))
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::query::<HistoryParams>())
+            .and_then(Web::handle_group_history);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let mark_group_read = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("groups" / GroupId / "read" / GroupMessageId))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_mark_group_read);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let friends = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("friends"))
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_friends);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let friend_request = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("friends" / "requests" / UserId))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_friend_request);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let accept_friend = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("friends" / "requests" / UserId / "accept"))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::any().map(|| true))
+            .and_then(Web::handle_answer_friend_request);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let decline_friend = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("friends" / "requests" / UserId / "decline"))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::any().map(|| false))
+            .and_then(Web::handle_answer_friend_request);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let remove_friend = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("friends" / UserId))
+            .and(warp::delete())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_remove_friend);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let blocks = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("blocks"))
+            .and(warp::get())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and_then(Web::handle_blocks);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let block = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("blocks" / UserId))
+            .and(warp::post())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::any().map(|| true))
+            .and_then(Web::handle_set_blocked);
+
+        let ac = web_chans.ask_core.clone();
+        let cls_sessions = sessions.clone();
+        let unblock = warp::any()
+            .map(move || ac.clone())
+            .and(warp::any().map(move || cls_sessions.clone()))
+            .and(warp::path!("blocks" / UserId))
+            .and(warp::delete())
+            .and(warp::header::<LoginToken>(
+                http::header::AUTHORIZATION.as_str(),
+            ))
+            .and(warp::any().map(|| false))
+            .and_then(Web::handle_set_blocked);
+
+        let routes = search_users
+            .or(userinfo)
+            .or(update_profile)
+            .or(groupinfo)
+            .or(user_history)
+            .or(group_history)
+            .or(mark_group_read)
+            .or(friends)
+            .or(friend_request)
+            .or(accept_friend)
+            .or(decline_friend)
+            .or(remove_friend)
+            .or(blocks)
+            .or(block)
+            .or(unblock)
+            .or(login)
+            .or(refresh)
+            .or(logout)
+            .or(logout_all)
+            .or(list_sessions)
+            .or(end_session)
+            .or(ws)
+            .or(register)
+            .recover(Web::recover);
+        let addr = nc.api_addr.parse::<SocketAddr>().unwrap();
+        let stopped = async move {
+            r_stop.recv().await;
+            r_stop.recv().await;
+            info!("Web: stopped");
+        };
+        match tls {
+            Some(tls) => Web::serve_tls(routes.boxed(), addr, tls, stopped).await,
+            None => {
+                let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, stopped);
+                info!("Web: started");
+                server.await;
+            }
+        }
+    }
+
+    /**
+    Serve over TLS. warp can't swap certificates while running, so this goes through hyper.
+
+    `warp::addr::remote` sees nothing here; `Web::remote` reads the `PeerAddr` set on every request.
+    */
+    async fn serve_tls<T: Reply + 'static>(
+        routes: warp::filters::BoxedFilter<(T,)>,
+        addr: SocketAddr,
+        tls: Tls,
+        stopped: impl std::future::Future<Output = ()>,
+    ) {
+        use warp::hyper::service::{make_service_fn, service_fn, Service};
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Web: can't listen on {}: {}", &addr, e);
+                return;
+            }
+        };
+        let conns = accept_conns(listener, Some(tls));
+        let make_svc = make_service_fn(move |conn: &Conn| {
+            let peer = PeerAddr(conn.peer);
+            let mut svc = warp::service(routes.clone());
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |mut req| {
+                    req.extensions_mut().insert(peer);
+                    svc.call(req)
+                }))
+            }
+        });
+        let server = warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(
+            conns.map(Ok::<_, std::io::Error>),
+        ))
+        .serve(make_svc)
+        .with_graceful_shutdown(stopped);
+        info!("Web: started with tls");
+        if let Err(e) = server.await {
+            error!("Web: server error: {}", e);
+        }
+    }
+
+    /// Where a request came from, over TLS or not.
+    fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = std::convert::Infallible> + Clone {
+        warp::ext::optional::<PeerAddr>()
+            .and(warp::addr::remote())
+            .map(|peer: Option<PeerAddr>, remote: Option<SocketAddr>| peer.map(|p| p.0).or(remote))
     }
 
     async fn handle_userinfo(
         ca: CoreAsker,
-        lt_uid: Arc<RwLock<HashMap<LoginToken, UserId>>>,
+        sessions: Sessions,
         access_uid: UserId,
         auth_opt: Option<LoginToken>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
         match auth_opt {
-            Some(lt) => match lt_uid.read().await.get(&lt) {
+            Some(lt) => match sessions.authenticate(&lt) {
                 Some(associated_uid) => {
-                    if let Some(CoreReply::GetUserData(pur)) = Core::ask(
+                    match Core::ask(
                         ca,
                         CoreRequest::GetUserData {
                             lookup: access_uid,
-                            asker: Some(associated_uid.to_owned()),
+                            asker: Some(associated_uid),
                         },
                     )
                     .await
                     {
-                        Ok(warp::reply::json(&pur))
-                    } else {
-                        Err(warp::reject::custom(WebCoreLookupFailed))
+                        Some(CoreReply::GetUserData(pur)) => Ok(warp::reply::json(&pur)),
+                        reply => Err(Web::core_failed(reply)),
                     }
                 }
                 None => {
@@ -101,7 +433,7 @@ impl Web {
             None => {
                 // unprivileged access
                 // visibility public
-                if let Some(CoreReply::GetUserData(pur)) = Core::ask(
+                match Core::ask(
                     ca,
                     CoreRequest::GetUserData {
                         lookup: access_uid,
@@ -110,49 +442,564 @@ impl Web {
                 )
                 .await
                 {
-                    Ok(warp::reply::json(&pur))
-                } else {
-                    Err(warp::reject::custom(WebCoreLookupFailed {}))
+                    Some(CoreReply::GetUserData(pur)) => Ok(warp::reply::json(&pur)),
+                    reply => Err(Web::core_failed(reply)),
                 }
             }
         }
     }
 
+    // without a token, only public users are found
+    async fn handle_search_users(
+        ca: CoreAsker,
+        sessions: Sessions,
+        auth_opt: Option<LoginToken>,
+        sp: UserSearchParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let asker = match auth_opt {
+            Some(lt) => Some(Web::uid_from_lt(&sessions, &lt)?),
+            None => None,
+        };
+        let (alias, email) = sp
+            .terms()
+            .ok_or_else(|| warp::reject::custom(WebEmptySearch))?;
+        match Core::ask(
+            ca,
+            CoreRequest::SearchUsers {
+                alias,
+                email,
+                asker,
+                after: sp.after.map(UserId::from),
+                amt: sp.amt(),
+            },
+        )
+        .await
+        {
+            Some(CoreReply::Users(found)) => Ok(warp::reply::json(&found)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // replies with the profile as the user sees it, like `GET /users/{uid}` on oneself
+    async fn handle_update_profile(
+        ca: CoreAsker,
+        sessions: Sessions,
+        lt: LoginToken,
+        update: ProfileUpdate,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        update
+            .check()
+            .map_err(|e| warp::reject::custom(WebInvalidProfile(e)))?;
+        match Core::ask(
+            ca,
+            CoreRequest::UpdateProfile {
+                u: associated_uid,
+                update,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::GetUserData(pur)) => Ok(warp::reply::json(&pur)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // groups are only visible to their members
+    async fn handle_groupinfo(
+        ca: CoreAsker,
+        sessions: Sessions,
+        access_gid: GroupId,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::GetGroupData {
+                lookup: access_gid,
+                asker: associated_uid,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::GetGroupData(gr)) => Ok(warp::reply::json(&gr)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // pages are newest first, pass the last umid as `before` for the next one
+    async fn handle_user_history(
+        ca: CoreAsker,
+        sessions: Sessions,
+        with: UserId,
+        lt: LoginToken,
+        hp: HistoryParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::GetUserLast {
+                s: associated_uid,
+                r: with,
+                query: hp.query(),
+                before: hp.before.map(UserMessageId::from),
+                amt: hp.amt(),
+            },
+        )
+        .await
+        {
+            Some(CoreReply::UserHistory(page)) => Ok(warp::reply::json(&WsClientboundPayload::from(page))),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    async fn handle_group_history(
+        ca: CoreAsker,
+        sessions: Sessions,
+        group: GroupId,
+        lt: LoginToken,
+        hp: HistoryParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::GetGroupLast {
+                s: associated_uid,
+                g: group,
+                query: hp.query(),
+                before: hp.before.map(GroupMessageId::from),
+                amt: hp.amt(),
+            },
+        )
+        .await
+        {
+            Some(CoreReply::GroupHistory(page)) => Ok(warp::reply::json(&WsClientboundPayload::from(page))),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // replies with the gmid that was flagged
+    async fn handle_mark_group_read(
+        ca: CoreAsker,
+        sessions: Sessions,
+        group: GroupId,
+        gmid: GroupMessageId,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::MarkGroupRead {
+                u: associated_uid,
+                g: group,
+                gmid,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::GroupRead(gmid)) => Ok(warp::reply::json(&gmid)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    async fn handle_friends(
+        ca: CoreAsker,
+        sessions: Sessions,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(ca, CoreRequest::GetFriends { u: associated_uid }).await {
+            Some(CoreReply::Friends(fl)) => Ok(warp::reply::json(&fl)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    async fn handle_friend_request(
+        ca: CoreAsker,
+        sessions: Sessions,
+        to: UserId,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::RequestFriend {
+                u: associated_uid,
+                to,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::FriendRequest(req)) => Ok(warp::reply::json(&req)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    async fn handle_answer_friend_request(
+        ca: CoreAsker,
+        sessions: Sessions,
+        from: UserId,
+        lt: LoginToken,
+        accept: bool,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::AnswerFriendRequest {
+                u: associated_uid,
+                from,
+                accept,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::FriendRequest(req)) => Ok(warp::reply::json(&req)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // replies with the friend list that is left
+    async fn handle_remove_friend(
+        ca: CoreAsker,
+        sessions: Sessions,
+        friend: UserId,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::RemoveFriend {
+                u: associated_uid,
+                friend,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::Friends(fl)) => Ok(warp::reply::json(&fl)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    async fn handle_blocks(
+        ca: CoreAsker,
+        sessions: Sessions,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(ca, CoreRequest::GetBlocked { u: associated_uid }).await {
+            Some(CoreReply::Blocked(list)) => Ok(warp::reply::json(&list)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    // replies with the updated block list
+    async fn handle_set_blocked(
+        ca: CoreAsker,
+        sessions: Sessions,
+        other: UserId,
+        lt: LoginToken,
+        blocked: bool,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match Core::ask(
+            ca,
+            CoreRequest::SetBlocked {
+                u: associated_uid,
+                other,
+                blocked,
+            },
+        )
+        .await
+        {
+            Some(CoreReply::Blocked(list)) => Ok(warp::reply::json(&list)),
+            reply => Err(Web::core_failed(reply)),
+        }
+    }
+
+    /// Reject a request core didn't answer as expected: 404 if it found nothing, 500 if it failed.
+    fn core_failed(reply: Option<CoreReply>) -> warp::Rejection {
+        match reply {
+            Some(CoreReply::NotFound) => warp::reject::custom(WebCoreLookupFailed),
+            _ => warp::reject::custom(WebCoreFailed),
+        }
+    }
+
+    /// Find who a login token belongs to. Using it keeps the session alive.
+    fn uid_from_lt(sessions: &Sessions, lt: &LoginToken) -> Result<UserId, warp::Rejection> {
+        match sessions.authenticate(lt) {
+            Some(uid) => Ok(uid),
+            None => Err(warp::reject::custom(WebInvalidLoginToken {})),
+        }
+    }
+
     async fn handle_register(
         ask_core: CoreAsker,
         register_req: RegisterRequest,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        debug!("web: register request {:?} received", &register_req);
-        if let Some(CoreReply::Register(uid)) = Core::ask(ask_core, CoreRequest::Register(register_req)).await {
-            debug!("web: register ok {}", &uid);
-            Ok(warp::reply::json(&uid))
-        } else {
-            Err(warp::reject::custom(WebRegisterError {}))
+        debug!("web: register request for {} received", &register_req.email);
+        match Core::ask(ask_core, CoreRequest::Register(register_req)).await {
+            Some(CoreReply::Register(uid)) => {
+                debug!("web: register ok {}", &uid);
+                Ok(warp::reply::json(&uid))
+            }
+            Some(CoreReply::UserAlreadyExists) => {
+                Err(warp::reject::custom(WebRegisterError::UserAlreadyExists))
+            }
+            _ => Err(warp::reject::custom(WebRegisterError::Failed)),
         }
     }
 
-    async fn try_auth_user(ask_core: CoreAsker, login_req: LoginRequest) -> Option<UserId> {
-        if let Some(CoreReply::Login(uid)) = Core::ask(ask_core, CoreRequest::Login(login_req)).await {
-            Some(uid)
-        } else {
-            None
+    /// `Some(None)` if the email or password is wrong, `None` if core couldn't check.
+    async fn try_auth_user(ask_core: CoreAsker, login_req: LoginRequest) -> Option<Option<UserId>> {
+        match Core::ask(ask_core, CoreRequest::Login(login_req)).await {
+            Some(CoreReply::Login(uid)) => Some(Some(uid)),
+            Some(CoreReply::LoginRejected) => Some(None),
+            _ => None,
         }
     }
 
-    // let ws know to expect this lt
+    // ws checks the same sessions, so the token works there right away
     async fn handle_login(
-        lt_uid: Arc<RwLock<HashMap<LoginToken, UserId>>>,
-        notify_ws: tokio::sync::mpsc::Sender<WebToWs>,
-        uid: UserId,
+        ask_core: CoreAsker,
+        login_req: LoginRequest,
+        sessions: Sessions,
+        throttle: LoginThrottle,
+        addr: Option<SocketAddr>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
+        let ip = addr.map(|a| a.ip());
+        // counted before the slow password check, so parallel attempts see each other
+        match throttle.begin(ip, &login_req.email) {
+            Some(Ok(())) => {}
+            Some(Err(retry_after_s)) => {
+                debug!("web: login for {} throttled for {}s", &login_req.email, retry_after_s);
+                return Err(warp::reject::custom(WebLoginThrottled { retry_after_s }));
+            }
+            None => return Err(warp::reject::custom(WebCoreFailed)),
+        }
+        let email = login_req.email.clone();
+        let device = login_req.device.clone();
+        let uid = match Web::try_auth_user(ask_core, login_req).await {
+            Some(Some(uid)) => uid,
+            Some(None) => return Err(warp::reject::custom(WebInvalidCredentials)),
+            None => {
+                throttle.aborted(ip, &email);
+                return Err(warp::reject::custom(WebCoreFailed));
+            }
+        };
+        throttle
+            .succeeded(ip, &email)
+            .ok_or_else(|| warp::reject::custom(WebCoreFailed))?;
+        match sessions.create(uid, device, ip.map(|ip| ip.to_string())).await {
+            Some(tokens) => {
+                info!("web: uid {} logged in, session {}", &uid, &tokens.sid);
+                Ok(warp::reply::json(&tokens))
+            }
+            None => Err(warp::reject::custom(WebSessionError)),
+        }
+    }
+
+    /// Reply to every rejection with an `ApiError`.
+    async fn recover(r: warp::Rejection) -> Result<warp::reply::Response, std::convert::Infallible> {
+        let (code, message) = Web::explain(&r);
+        if code == ApiErrorCode::Internal {
+            error!("web: request failed: {:?}", &r);
+        }
+        let retry_after_s = r.find::<WebLoginThrottled>().map(|t| t.retry_after_s);
+        let body = ApiError {
+            code,
+            message,
+            retry_after_s,
+        };
+        let mut res = warp::reply::with_status(warp::reply::json(&body), Web::status(code))
+            .into_response();
+        if let Some(s) = retry_after_s {
+            res.headers_mut()
+                .insert(http::header::RETRY_AFTER, http::HeaderValue::from(s));
+        }
+        Ok(res)
+    }
+
+    // warp's own rejections come last, since they can be combined with ours
+    fn explain(r: &warp::Rejection) -> (ApiErrorCode, String) {
+        use warp::reject::*;
+        let auth = |name: &str| name.eq_ignore_ascii_case(http::header::AUTHORIZATION.as_str());
+        if r.find::<WebInvalidCredentials>().is_some() {
+            (ApiErrorCode::InvalidCredentials, "wrong email or password".to_owned())
+        } else if let Some(t) = r.find::<WebLoginThrottled>() {
+            let m = format!("too many failed logins, try again in {}s", t.retry_after_s);
+            (ApiErrorCode::TooManyAttempts, m)
+        } else if r.find::<WebInvalidLoginToken>().is_some() {
+            (ApiErrorCode::InvalidToken, "token is invalid or expired".to_owned())
+        } else if let Some(e) = r.find::<WebRegisterError>() {
+            match e {
+                WebRegisterError::UserAlreadyExists => {
+                    (ApiErrorCode::UserAlreadyExists, "email is already registered".to_owned())
+                }
+                WebRegisterError::Failed => (ApiErrorCode::Internal, "registration failed".to_owned()),
+            }
+        } else if r.find::<WebEmptySearch>().is_some() {
+            (ApiErrorCode::BadRequest, "search needs an alias or an email".to_owned())
+        } else if let Some(WebInvalidProfile(e)) = r.find::<WebInvalidProfile>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if r.find::<WebCoreLookupFailed>().is_some() {
+            (ApiErrorCode::NotFound, "not found".to_owned())
+        } else if r.find::<WebCoreFailed>().is_some()
+            || r.find::<WebSessionError>().is_some()
+            || r.find::<WebChannelsError>().is_some()
+        {
+            (ApiErrorCode::Internal, "internal error".to_owned())
+        } else if let Some(e) = r.find::<MissingHeader>().filter(|e| auth(e.name())) {
+            (ApiErrorCode::InvalidToken, e.to_string())
+        } else if let Some(e) = r.find::<InvalidHeader>().filter(|e| auth(e.name())) {
+            (ApiErrorCode::InvalidToken, e.to_string())
+        } else if let Some(e) = r.find::<MissingHeader>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if let Some(e) = r.find::<InvalidHeader>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if let Some(e) = r.find::<warp::body::BodyDeserializeError>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if let Some(e) = r.find::<InvalidQuery>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if let Some(e) = r.find::<warp::ws::MissingConnectionUpgrade>() {
+            (ApiErrorCode::BadRequest, e.to_string())
+        } else if let Some(e) = r.find::<LengthRequired>() {
+            (ApiErrorCode::LengthRequired, e.to_string())
+        } else if let Some(e) = r.find::<PayloadTooLarge>() {
+            (ApiErrorCode::PayloadTooLarge, e.to_string())
+        } else if let Some(e) = r.find::<UnsupportedMediaType>() {
+            (ApiErrorCode::UnsupportedMediaType, e.to_string())
+        } else if let Some(e) = r.find::<MethodNotAllowed>() {
+            (ApiErrorCode::MethodNotAllowed, e.to_string())
+        } else if r.is_not_found() {
+            (ApiErrorCode::NotFound, "not found".to_owned())
+        } else {
+            (ApiErrorCode::Internal, "internal error".to_owned())
+        }
+    }
+
+    fn status(code: ApiErrorCode) -> StatusCode {
+        match code {
+            ApiErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ApiErrorCode::InvalidCredentials | ApiErrorCode::InvalidToken => StatusCode::UNAUTHORIZED,
+            ApiErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ApiErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiErrorCode::UserAlreadyExists | ApiErrorCode::Conflict => StatusCode::CONFLICT,
+            ApiErrorCode::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ApiErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiErrorCode::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            ApiErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // replies with the session that ended
+    async fn handle_logout(
+        sessions: Sessions,
+        notify_ws: Sender<WebToWs>,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let current = match sessions.check(&lt) {
+            Some(s) => s,
+            None => return Err(warp::reject::custom(WebInvalidLoginToken)),
+        };
+        Web::end_session(sessions, notify_ws, current.uid, current.sid)
+            .await
+            .map(|ended| warp::reply::json(&ended))
+    }
+
+    // replies with the sessions that ended
+    async fn handle_logout_all(
+        sessions: Sessions,
+        notify_ws: Sender<WebToWs>,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut notify_ws = notify_ws;
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        let ended = sessions
+            .revoke_all(associated_uid)
+            .await
+            .ok_or_else(|| warp::reject::custom(WebSessionError))?;
+        info!("web: uid {} logged out everywhere", &associated_uid);
+        match notify_ws.send(WebToWs::ClearTokens(associated_uid)).await {
+            Ok(_) => Ok(warp::reply::json(&ended)),
+            Err(_) => Err(warp::reject::custom(WebChannelsError)),
+        }
+    }
+
+    async fn handle_sessions(
+        sessions: Sessions,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        match sessions.list(associated_uid) {
+            Some(list) => Ok(warp::reply::json(&list)),
+            None => Err(warp::reject::custom(WebSessionError)),
+        }
+    }
+
+    // same check as the dedicated listener, then ws takes the connection over
+    async fn handle_ws(
+        sessions: Sessions,
+        notify_ws: Sender<WebToWs>,
+        lt: LoginToken,
+        upgrade: warp::ws::Ws,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let session = match sessions.check(&lt) {
+            Some(s) => s,
+            None => return Err(warp::reject::custom(WebInvalidLoginToken)),
+        };
+        Ok(upgrade.on_upgrade(move |socket| async move {
+            let mut notify_ws = notify_ws;
+            let conn = WsConn::from(socket);
+            let (uid, sid) = (session.uid, session.sid);
+            if notify_ws.send(WebToWs::Connect { uid, sid, conn }).await.is_err() {
+                warn!("web: ws is gone, dropping upgraded connection of {}", &uid);
+            }
+        }))
+    }
+
+    // replies with the sessions that are left
+    async fn handle_end_session(
+        sessions: Sessions,
+        notify_ws: Sender<WebToWs>,
+        sid: SessionId,
+        lt: LoginToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let associated_uid = Web::uid_from_lt(&sessions, &lt)?;
+        Web::end_session(sessions.clone(), notify_ws, associated_uid, sid).await?;
+        match sessions.list(associated_uid) {
+            Some(list) => Ok(warp::reply::json(&list)),
+            None => Err(warp::reject::custom(WebSessionError)),
+        }
+    }
+
+    /// Revoke a session of `uid` and drop the ws connections made with it.
+    async fn end_session(
+        sessions: Sessions,
+        notify_ws: Sender<WebToWs>,
+        uid: UserId,
+        sid: SessionId,
+    ) -> Result<Session, warp::Rejection> {
         let mut notify_ws = notify_ws;
-        let mut hmap = lt_uid.write().await;
-        let lt = LoginToken::new();
-        hmap.insert(lt.clone(), uid.clone());
-        info!("web: uid {} logged in", &uid);
-        match notify_ws.send(WebToWs::AddToken(uid, lt.clone())).await {
-            Ok(_) => Ok(warp::reply::json(&lt)),
+        let ended = match sessions.revoke(uid, sid).await {
+            Some(Some(ended)) => ended,
+            Some(None) => return Err(warp::reject::custom(WebCoreLookupFailed)),
+            None => return Err(warp::reject::custom(WebSessionError)),
+        };
+        info!("web: uid {} ended session {}", &uid, &sid);
+        match notify_ws.send(WebToWs::EndSession(uid, sid)).await {
+            Ok(_) => Ok(ended),
             Err(_) => Err(warp::reject::custom(WebChannelsError)),
         }
     }
+
+    async fn handle_refresh(
+        sessions: Sessions,
+        refresh: RefreshToken,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        match sessions.refresh(&refresh).await {
+            Some(tokens) => Ok(warp::reply::json(&tokens)),
+            None => Err(warp::reject::custom(WebInvalidLoginToken)),
+        }
+    }
 }
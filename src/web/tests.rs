@@ -0,0 +1,109 @@
+/*!
+What each rejection is answered with.
+*/
+use crate::imports::*;
+use crate::symbols::*;
+
+fn rejections() -> Vec<(warp::Rejection, ApiErrorCode, StatusCode)> {
+    use warp::reject::custom;
+    vec![
+        (
+            custom(WebInvalidCredentials),
+            ApiErrorCode::InvalidCredentials,
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            custom(WebInvalidLoginToken),
+            ApiErrorCode::InvalidToken,
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            custom(WebLoginThrottled { retry_after_s: 30 }),
+            ApiErrorCode::TooManyAttempts,
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+        (
+            custom(WebRegisterError::UserAlreadyExists),
+            ApiErrorCode::UserAlreadyExists,
+            StatusCode::CONFLICT,
+        ),
+        (
+            custom(WebRegisterError::Failed),
+            ApiErrorCode::Internal,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            custom(WebEmptySearch),
+            ApiErrorCode::BadRequest,
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            custom(WebCoreLookupFailed),
+            ApiErrorCode::NotFound,
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            custom(WebCoreFailed),
+            ApiErrorCode::Internal,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            custom(WebSessionError),
+            ApiErrorCode::Internal,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            custom(WebChannelsError),
+            ApiErrorCode::Internal,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            warp::reject::not_found(),
+            ApiErrorCode::NotFound,
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            warp::reject(),
+            ApiErrorCode::NotFound,
+            StatusCode::NOT_FOUND,
+        ),
+    ]
+}
+
+#[test]
+fn explain_picks_code_and_status() {
+    for (r, code, status) in rejections() {
+        assert_eq!(Web::explain(&r).0, code, "{:?}", r);
+        assert_eq!(Web::status(code), status, "{:?}", r);
+    }
+}
+
+#[tokio::test]
+async fn recover_replies_with_api_error() {
+    for (r, code, status) in rejections() {
+        let name = format!("{:?}", r);
+        let res = Web::recover(r).await.unwrap();
+        assert_eq!(res.status(), status, "{}", name);
+        let retry_after = res.headers().get(http::header::RETRY_AFTER).cloned();
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: ApiError = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, code, "{}", name);
+        if code == ApiErrorCode::TooManyAttempts {
+            assert_eq!(body.retry_after_s, Some(30), "{}", name);
+            assert_eq!(retry_after.unwrap(), "30", "{}", name);
+        } else {
+            assert!(retry_after.is_none(), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn core_failures_are_not_lookups() {
+    let status = |reply| Web::status(Web::explain(&Web::core_failed(reply)).0);
+    assert_eq!(status(Some(CoreReply::NotFound)), StatusCode::NOT_FOUND);
+    assert_eq!(status(None), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(
+        status(Some(CoreReply::LoginRejected)),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
@@ -14,6 +14,82 @@ impl Display for ConnectionId {
 
 pub struct Ws {}
 
+/// A client of the dedicated listener has this long to finish the websocket handshake.
+const UPGRADE_TIMEOUT_S: u64 = 10;
+
+type WsConnError = Box<dyn Error + Send + Sync>;
+
+/// An authenticated websocket, from the dedicated listener or upgraded on the API server.
+pub struct WsConn {
+    tx: Pin<Box<dyn futures::Sink<tungstenite::Message, Error = WsConnError> + Send>>,
+    rx: Pin<Box<dyn Stream<Item = Result<tungstenite::Message, WsConnError>> + Send>>,
+}
+
+impl std::fmt::Debug for WsConn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WsConn")
+    }
+}
+
+impl From<WebSocketStream<Conn>> for WsConn {
+    fn from(ws: WebSocketStream<Conn>) -> Self {
+        let (tx, rx) = ws.split();
+        WsConn {
+            tx: Box::pin(tx.sink_map_err(WsConnError::from)),
+            rx: Box::pin(rx.map(|m| m.map_err(WsConnError::from))),
+        }
+    }
+}
+
+impl From<warp::ws::WebSocket> for WsConn {
+    fn from(ws: warp::ws::WebSocket) -> Self {
+        let (tx, rx) = ws.split();
+        // warp answers pings itself and can't send a pong, so those are dropped
+        let tx = tx.sink_map_err(WsConnError::from).with_flat_map(|m| {
+            let m = match m {
+                tungstenite::Message::Text(s) => Some(warp::ws::Message::text(s)),
+                tungstenite::Message::Binary(b) => Some(warp::ws::Message::binary(b)),
+                tungstenite::Message::Ping(p) => Some(warp::ws::Message::ping(p)),
+                tungstenite::Message::Pong(_) => None,
+                tungstenite::Message::Close(Some(frame)) => {
+                    Some(warp::ws::Message::close_with(frame.code, frame.reason))
+                }
+                tungstenite::Message::Close(None) => Some(warp::ws::Message::close()),
+            };
+            futures::stream::iter(m.map(Ok))
+        });
+        let rx = rx.map(|m| {
+            m.map(|m| {
+                if m.is_text() {
+                    tungstenite::Message::Text(m.to_str().unwrap_or_default().to_owned())
+                } else if m.is_ping() {
+                    tungstenite::Message::Ping(m.into_bytes())
+                } else if m.is_pong() {
+                    tungstenite::Message::Pong(m.into_bytes())
+                } else if m.is_close() {
+                    tungstenite::Message::Close(None)
+                } else {
+                    tungstenite::Message::Binary(m.into_bytes())
+                }
+            })
+            .map_err(WsConnError::from)
+        });
+        WsConn {
+            tx: Box::pin(tx),
+            rx: Box::pin(rx),
+        }
+    }
+}
+
+/// Which user and session each connection belongs to, and how to reach its worker.
+#[derive(Default)]
+struct ConnLookups {
+    s_workers: HashMap<ConnectionId, Sender<WsToWorker>>,
+    cid_uid_lookup: HashMap<ConnectionId, UserId>,
+    uid_cids_lookup: HashMap<UserId, Vec<ConnectionId>>,
+    cid_sid_lookup: HashMap<ConnectionId, SessionId>,
+}
+
 pub struct ConnectionIdAllocator {
     inner: u64,
 }
@@ -30,97 +106,136 @@ impl ConnectionIdAllocator {
 }
 
 impl Ws {
-    pub async fn internal(nc: NetConfig, chans: WsChannels) -> Result<(), NetInternalError> {
-        let mut tcp_listener = tokio::net::TcpListener::bind(nc.ws_addr.to_owned())
-            .await
-            .map_err(|e| NetInternalError::ListenerBind(Box::new(e)))?;
+    pub async fn internal(
+        nc: NetConfig,
+        chans: WsChannels,
+        sessions: Sessions,
+        tls: Option<Tls>,
+    ) -> Result<(), NetInternalError> {
+        let mut conns = match &nc.ws_addr {
+            Some(addr) => {
+                let tcp_listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| NetInternalError::ListenerBind(Box::new(e)))?;
+                accept_conns(tcp_listener, tls)
+            }
+            // only web hands over connections
+            None => mpsc::channel(1).1,
+        };
         let mut run = true;
         let mut chans = chans;
-        let mut cid_uid_lookup = HashMap::new();
-        let mut uid_cids_lookup = HashMap::new();
-        let mut lt_uid_lookup = HashMap::new();
-        let mut s_workers = HashMap::new();
+        let mut lookups = ConnLookups::default();
         let mut cia = ConnectionIdAllocator::new();
         let (mut s_to_worker, mut r_from_worker) = tokio::sync::mpsc::channel(100000);
+        // upgrades run on their own tasks so a slow client doesn't hold up routing
+        let (s_upgraded, mut r_upgraded) = mpsc::channel(100);
         let mut first_stopped = false;
         info!("Ws: started");
         info!("{:?}", &nc);
         while run {
             tokio::select! {
-                Some(ntc_maybe) = tcp_listener.next() => {
-                    if let Ok(ntc) = ntc_maybe {
-                        debug!("ws internal: new tcp connection {:?}", &ntc);
-                        Ws::handle_new_tcp(
-                            &mut cia,
-                            ntc,
-                            &chans.s_web,
-                            &mut s_workers,
-                            &mut cid_uid_lookup,
-                            &mut uid_cids_lookup,
-                            &mut lt_uid_lookup,
-                            s_to_worker.clone()
-                        ).await;
-                        debug!("ws internal: new tcp success");
+                Some(ntc) = conns.recv() => {
+                    debug!("ws internal: new tcp connection from {}", &ntc.peer);
+                    let sessions = sessions.clone();
+                    let mut s_upgraded = s_upgraded.clone();
+                    tokio::task::spawn(async move {
+                        if let Some((uid, sid, conn)) = Ws::upgrade(ntc, &sessions).await {
+                            debug!("ws internal: new tcp success");
+                            // handed over the same way web hands over the ones it upgraded
+                            if s_upgraded.send(WebToWs::Connect { uid, sid, conn }).await.is_err() {
+                                warn!("ws internal: stopped before uid {} connected", &uid);
+                            }
+                        }
+                    });
+                }
+                Some(m_up) = r_upgraded.recv() => {
+                    if let Err(e) = Ws::handle_web(
+                        &mut cia,
+                        m_up,
+                        &chans.s_web,
+                        &mut lookups,
+                        &chans.s_core,
+                        s_to_worker.clone(),
+                    ).await {
+                        warn!("ws internal: new connection not handed to core: {}", e);
                     }
                 }
                 Some(m_web) = chans.r_web.recv() => {
                     debug!("ws internal: new web msg {:?}", &m_web);
-                    Ws::handle_web(
+                    if let Err(e) = Ws::handle_web(
                         &mut cia,
                         m_web,
                         &chans.s_web,
-                        &mut s_workers,
-                        &mut cid_uid_lookup,
-                        &mut uid_cids_lookup,
-                        &mut lt_uid_lookup,
-                    ).await;
+                        &mut lookups,
+                        &chans.s_core,
+                        s_to_worker.clone(),
+                    ).await {
+                        warn!("ws internal: web msg not handled: {}", e);
+                    }
                 }
                 Some(m_core) = chans.r_core.recv() => {
                     debug!("ws internal: new core msg {:?}", &m_core);
                     match m_core {
                         CoreToWs::SendDirect {dest, tx} => {
-                            // get dest's connected devices
-                            match uid_cids_lookup.get(&dest) {
-                                Some(cids) => {
-                                    let m = WsToWorker::from(tx);
-                                    for cid in cids {
-                                        debug!("ws internal: sending payload to cid {}", &cid);
-                                        s_workers.get_mut(&cid).unwrap().send(m.clone()).await;
+                            Ws::send_to_uid(&dest, WsToWorker::from(tx), &mut lookups.s_workers, &lookups.uid_cids_lookup).await;
+                        },
+                        CoreToWs::SendMultiple {dest, tx} => {
+                            let m = WsToWorker::from(tx);
+                            for uid in dest.iter() {
+                                Ws::send_to_uid(uid, m.clone(), &mut lookups.s_workers, &lookups.uid_cids_lookup).await;
+                            }
+                        },
+                        CoreToWs::SendConnection {cid, tx} => {
+                            match lookups.s_workers.get_mut(&cid) {
+                                Some(s_worker) => {
+                                    if s_worker.send(WsToWorker::from(tx)).await.is_err() {
+                                        warn!("ws internal: send to {} failed, worker gone", &cid);
                                     }
                                 },
                                 None => {
-                                    warn!("ws internal: send to uid {} failed, no devices", &dest);
+                                    warn!("ws internal: send to {} failed, already gone", &cid);
                                 }
                             }
                         },
-                        _ => unimplemented!()
                     }
                 }
                 Some(m_worker) = r_from_worker.recv() => {
                     debug!("ws internal: new worker msg {:?}", &m_worker);
                     match m_worker {
                         WorkerToWs::ForwardToCore(cid, tung_msg) => {
-                            if let Some(uid) = cid_uid_lookup.get(&cid) {
-                                match WsServerboundTx::new(uid.to_owned(), tung_msg).map(WsToCore::from) {
-                                    Some(core_msg) => {
-                                        chans.s_core.send(core_msg).await;
-                                    },
-                                    None => {}
+                            if let Some(uid) = lookups.cid_uid_lookup.get(&cid) {
+                                if let Some(tx) = WsServerboundTx::new(uid.to_owned(), tung_msg) {
+                                    // only ws knows which device a delivery confirmation came from
+                                    let core_msg = match (tx.delivered(), lookups.cid_sid_lookup.get(&cid)) {
+                                        (Some(umid), Some(sid)) => WsToCore::Delivered { uid: uid.to_owned(), sid: *sid, umid },
+                                        _ => WsToCore::from(tx),
+                                    };
+                                    if chans.s_core.send(core_msg).await.is_err() {
+                                        warn!("ws -> core: core is gone, dropped msg from {}", &cid);
+                                    }
                                 }
                             } else {
                                 warn!("ws -> core: no associated uid with cid {} for sending string", &cid);
                             }
                         },WorkerToWs::Disconnected(cid) => {
-                            if let Some(uid) = cid_uid_lookup.get(&cid) {
-                                let uid = uid.clone();
-                                s_workers.retain(|k, v| *k != cid);
-                                cid_uid_lookup.retain(|k, v| *k != cid);
-                                uid_cids_lookup.get_mut(&uid).unwrap().retain(|e| *e != cid);
+                            if let Some(uid) = lookups.cid_uid_lookup.get(&cid) {
+                                let uid = *uid;
+                                lookups.s_workers.retain(|k, _| *k != cid);
+                                lookups.cid_uid_lookup.retain(|k, _| *k != cid);
+                                lookups.cid_sid_lookup.remove(&cid);
+                                if let Some(cids) = lookups.uid_cids_lookup.get_mut(&uid) {
+                                    cids.retain(|e| *e != cid);
+                                    if cids.is_empty() {
+                                        lookups.uid_cids_lookup.remove(&uid);
+                                    }
+                                }
+                                if chans.s_core.send(WsToCore::Disconnected { uid, cid }).await.is_err() {
+                                    warn!("ws -> core: core is gone, dropped a disconnect of uid {}", &uid);
+                                }
                             } else {
                                 warn!("ws internal: received disconnect from unknown uid worker");
                             }
                         },
-                        _ => unimplemented!()
                     }
                 }
                 Some(_) = chans.r_stop.recv() => {
@@ -137,64 +252,97 @@ impl Ws {
         Ok(())
     }
 
+    /// Send a message to every connected device of a user.
+    async fn send_to_uid(
+        dest: &UserId,
+        m: WsToWorker,
+        s_workers: &mut HashMap<ConnectionId, Sender<WsToWorker>>,
+        uid_cids_lookup: &HashMap<UserId, Vec<ConnectionId>>,
+    ) {
+        // get dest's connected devices
+        match uid_cids_lookup.get(dest) {
+            Some(cids) => {
+                for cid in cids {
+                    debug!("ws internal: sending payload to cid {}", &cid);
+                    if let Some(s_worker) = s_workers.get_mut(cid) {
+                        if s_worker.send(m.clone()).await.is_err() {
+                            warn!("ws internal: send to {} failed, worker gone", &cid);
+                        }
+                    }
+                }
+            }
+            None => {
+                warn!("ws internal: send to uid {} failed, no devices", &dest);
+            }
+        }
+    }
+
     async fn handle_web(
         cia: &mut ConnectionIdAllocator,
         m_web: WebToWs,
         s_web: &Sender<WsToWeb>,
-        s_workers: &mut HashMap<ConnectionId, Sender<WsToWorker>>,
-        cid_uid_lookup: &mut HashMap<ConnectionId, UserId>,
-        uid_cids_lookup: &mut HashMap<UserId, Vec<ConnectionId>>,
-        lt_uid_lookup: &mut HashMap<LoginToken, UserId>,
+        lookups: &mut ConnLookups,
+        s_core: &Sender<WsToCore>,
+        s_worker: Sender<WorkerToWs>,
     ) -> Result<(), Box<dyn Error>> {
-        match m_web {
-            WebToWs::AddToken(cid, lt) => {
-                lt_uid_lookup.insert(lt, cid);
-                Ok(())
+        let (uid, conn_ids) = match m_web {
+            WebToWs::Connect { uid, sid, conn } => {
+                let cid = Ws::add_conn(
+                    cia,
+                    conn,
+                    uid,
+                    sid,
+                    lookups,
+                    s_worker,
+                )
+                .await;
+                // let core catch the new device up on what it missed
+                s_core.clone().send(WsToCore::Connected { uid, cid }).await?;
+                return Ok(());
             }
             // disconnect all connections
-            WebToWs::ClearTokens(cid) => {
-                info!("clearing and disconnecting {}", &cid);
-                if let Some(conn_ids) = uid_cids_lookup.get(&cid) {
-                    let disconnects_ks = s_workers
-                        .iter()
-                        .filter_map(|(k, _)| {
-                            if conn_ids.contains(k) {
-                                Some(k.to_owned())
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<ConnectionId>>();
-                    for k in disconnects_ks {
-                        s_workers
-                            .get_mut(&k)
-                            .unwrap()
-                            .send(WsToWorker::Disconnect)
-                            .await;
-                    }
-                    s_workers.retain(|k, v| !conn_ids.contains(k));
-                    cid_uid_lookup.retain(|k, v| !conn_ids.contains(k));
-                    uid_cids_lookup.retain(|k, v| *k != cid);
-                    lt_uid_lookup.retain(|k, v| *v != cid);
-                    Ok(())
-                } else {
-                    // clear tokens failed, invalid c_uuid
-                    Ok(())
-                }
+            WebToWs::ClearTokens(uid) => {
+                info!("clearing and disconnecting {}", &uid);
+                (uid, lookups.uid_cids_lookup.get(&uid).cloned().unwrap_or_default())
+            }
+            WebToWs::EndSession(uid, sid) => {
+                info!("ending session {} of {}", &sid, &uid);
+                let conn_ids = lookups.cid_sid_lookup
+                    .iter()
+                    .filter(|(_, v)| **v == sid)
+                    .map(|(k, _)| k.to_owned())
+                    .collect::<Vec<ConnectionId>>();
+                (uid, conn_ids)
+            }
+        };
+        for k in conn_ids.iter() {
+            if let Some(s_worker) = lookups.s_workers.get_mut(k) {
+                // a worker that is already gone needs no telling
+                s_worker.send(WsToWorker::Disconnect).await.ok();
             }
         }
+        // the mappings are gone before the workers report back, so tell core here
+        let mut s_core = s_core.clone();
+        for conn_id in conn_ids.iter() {
+            let cid = conn_id.to_owned();
+            if s_core.send(WsToCore::Disconnected { uid, cid }).await.is_err() {
+                warn!("ws -> core: core is gone, dropped a disconnect of uid {}", &uid);
+            }
+        }
+        lookups.s_workers.retain(|k, _| !conn_ids.contains(k));
+        lookups.cid_uid_lookup.retain(|k, _| !conn_ids.contains(k));
+        lookups.cid_sid_lookup.retain(|k, _| !conn_ids.contains(k));
+        if let Some(cids) = lookups.uid_cids_lookup.get_mut(&uid) {
+            cids.retain(|k| !conn_ids.contains(k));
+            if cids.is_empty() {
+                lookups.uid_cids_lookup.remove(&uid);
+            }
+        }
+        Ok(())
     }
 
-    async fn handle_new_tcp(
-        cia: &mut ConnectionIdAllocator,
-        t: TcpStream,
-        s_web: &Sender<WsToWeb>,
-        s_workers: &mut HashMap<ConnectionId, Sender<WsToWorker>>,
-        cid_uid_lookup: &mut HashMap<ConnectionId, UserId>,
-        uid_cids_lookup: &mut HashMap<UserId, Vec<ConnectionId>>,
-        lt_uid_lookup: &HashMap<LoginToken, UserId>,
-        s_worker: Sender<WorkerToWs>,
-    ) -> Option<(UserId, ConnectionId)> {
+    /// Upgrade a connection from the dedicated listener, authenticated by its `Authorization` header.
+    async fn upgrade(t: Conn, sessions: &Sessions) -> Option<(UserId, SessionId, WsConn)> {
         let (send_c_uuid, recv_c_uuid) = tokio::sync::oneshot::channel();
         let cb = |req: &tungstenite::handshake::server::Request,
                   resp: Response<()>|
@@ -202,9 +350,9 @@ impl Ws {
             let hdr = req.headers();
             if let Some(hv) = hdr.get(http::header::AUTHORIZATION) {
                 if let Ok(tk) = hv.to_str() {
-                    if let Some(c_uuid) = lt_uid_lookup.get(&LoginToken { tk: tk.to_owned() }) {
+                    if let Some(session) = sessions.check(&LoginToken { tk: tk.to_owned() }) {
                         // valid user
-                        send_c_uuid.send(c_uuid.to_owned()).map_err(|e| {
+                        send_c_uuid.send((session.uid, session.sid)).map_err(|_| {
                             Response::builder()
                                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                                 .body(Some("Channel send error".to_owned()))
@@ -231,37 +379,58 @@ impl Ws {
                     .unwrap())
             }
         };
-        match tokio_tungstenite::accept_hdr_async(t, cb).await {
-            Ok(ws) => {
-                let c_uuid = recv_c_uuid.await.unwrap();
-                let cid = cia.get();
+        let handshake = tokio_tungstenite::accept_hdr_async(t, cb);
+        match tokio::time::timeout(Duration::from_secs(UPGRADE_TIMEOUT_S), handshake).await {
+            Ok(Ok(ws)) => {
+                let (uid, sid) = recv_c_uuid.await.ok()?;
+                info!("ws: new tcp handle done");
+                Some((uid, sid, WsConn::from(ws)))
+            }
+            Ok(Err(e)) => {
+                debug!("ws: upgrade failed: {}", e);
+                None
+            }
+            Err(_) => {
+                debug!("ws: upgrade timed out");
+                None
+            }
+        }
+    }
 
-                info!("accepting new connection {}", &cid);
+    /// Start a worker for an authenticated connection and remember whose it is.
+    async fn add_conn(
+        cia: &mut ConnectionIdAllocator,
+        conn: WsConn,
+        uid: UserId,
+        sid: SessionId,
+        lookups: &mut ConnLookups,
+        s_worker: Sender<WorkerToWs>,
+    ) -> ConnectionId {
+        let cid = cia.get();
 
-                let (w_s, r_w) = tokio::sync::mpsc::channel(1000);
+        info!("accepting new connection {}", &cid);
 
-                Ws::new_worker_conn(s_worker.clone(), r_w, ws, cid.clone()).await;
+        let (w_s, r_w) = tokio::sync::mpsc::channel(1000);
 
-                s_workers.insert(cid.clone(), w_s);
+        Ws::new_worker_conn(s_worker, r_w, conn, cid.clone()).await;
 
-                cid_uid_lookup.insert(cid.clone(), c_uuid.clone());
+        lookups.s_workers.insert(cid.clone(), w_s);
 
-                if let Some(cids) = uid_cids_lookup.get_mut(&c_uuid) {
-                    cids.push(cid.clone());
-                } else {
-                    uid_cids_lookup.insert(c_uuid.clone(), vec![cid.clone()]);
-                }
-                info!("ws: new tcp handle done");
-                Some((c_uuid, cid))
-            }
-            Err(e) => None,
+        lookups.cid_uid_lookup.insert(cid.clone(), uid);
+        lookups.cid_sid_lookup.insert(cid.clone(), sid);
+
+        if let Some(cids) = lookups.uid_cids_lookup.get_mut(&uid) {
+            cids.push(cid.clone());
+        } else {
+            lookups.uid_cids_lookup.insert(uid, vec![cid.clone()]);
         }
+        cid
     }
 
     async fn new_worker_conn(
         s: Sender<WorkerToWs>,
         r: Receiver<WsToWorker>,
-        c: WebSocketStream<TcpStream>,
+        c: WsConn,
         conn_id: ConnectionId,
     ) {
         tokio::spawn(async move {
@@ -280,26 +449,42 @@ impl Ws {
                             WsToWorker::Disconnect => {
                                 info!("{} disconnect received", &conn_id);
                                 worker_active = false;
-                                c.close(None).await;
-                                s.send(WorkerToWs::Disconnected(conn_id.clone())).await;
+                                c.tx.close().await.ok();
+                                if s.send(WorkerToWs::Disconnected(conn_id.clone())).await.is_err() {
+                                    warn!("{} can't tell ws it disconnected", &conn_id);
+                                }
                             },
                             WsToWorker::Tx(tx) => {
                                 debug!("worker cid {}: received payload", &conn_id);
                                 if let Some(tung_msg) = tx.extract() {
-                                    c.send(tung_msg).await; // possible send failure
+                                    if c.tx.send(tung_msg).await.is_err() {
+                                        warn!("{} msg write failed", &conn_id);
+                                    }
                                 }
                             }
                         }
                     }
-                    Some(raw_c_msg) = c.next() => {
+                    raw_c_msg = c.rx.next() => {
                         debug!("ws worker: new from client");
-                        if let Ok(c_msg) = raw_c_msg {
-                            s.send(WorkerToWs::ForwardToCore(conn_id.to_owned(), c_msg)).await;
-                        } else {
-                            warn!("{} msg read failed", &conn_id);
-                            worker_active = false;
-                            c.close(None).await;
-                            s.send(WorkerToWs::Disconnected(conn_id.clone())).await;
+                        match raw_c_msg {
+                            Some(Ok(c_msg)) => {
+                                if s.send(WorkerToWs::ForwardToCore(conn_id.to_owned(), c_msg)).await.is_err() {
+                                    warn!("{} can't forward to ws", &conn_id);
+                                }
+                            }
+                            // the stream ends once the client closed cleanly
+                            other => {
+                                if other.is_some() {
+                                    warn!("{} msg read failed", &conn_id);
+                                } else {
+                                    info!("{} closed by client", &conn_id);
+                                }
+                                worker_active = false;
+                                c.tx.close().await.ok();
+                                if s.send(WorkerToWs::Disconnected(conn_id.clone())).await.is_err() {
+                                    warn!("{} can't tell ws it disconnected", &conn_id);
+                                }
+                            }
                         }
                     }
                 }